@@ -10,6 +10,9 @@ pub struct Dimension {
 }
 
 impl Dimension {
+    pub const FIRST: ReferInd = RefIndex::named("First");
+    pub const SECOND: ReferInd = RefIndex::named("Second");
+
     pub fn new(first: Point3f, second: Point3f, offset: WorldCoord) -> Dimension {
         let id = RefID::new_v4();
         Dimension {
@@ -129,12 +132,20 @@ impl UpdateFromRefs for Dimension {
     fn get_refs(&self) -> Vec<Option<Reference>> {
         let mut results = Vec::new();
         if let Some(id) = &self.first.refer {
-            results.push(Some(Reference::new(self.id.clone(), 0, id.clone())));
+            results.push(Some(Reference::new(
+                self.id.clone(),
+                Dimension::FIRST,
+                id.clone(),
+            )));
         } else {
             results.push(None);
         }
         if let Some(id) = &self.second.refer {
-            results.push(Some(Reference::new(self.id.clone(), 1, id.clone())));
+            results.push(Some(Reference::new(
+                self.id.clone(),
+                Dimension::SECOND,
+                id.clone(),
+            )));
         } else {
             results.push(None);
         }
@@ -144,10 +155,10 @@ impl UpdateFromRefs for Dimension {
     fn get_available_refs(&self) -> Vec<ReferInd> {
         let mut results = Vec::new();
         if let None = self.first.refer {
-            results.push(0);
+            results.push(Dimension::FIRST);
         }
         if let None = self.second.refer {
-            results.push(1);
+            results.push(Dimension::SECOND);
         }
         results
     }
@@ -158,15 +169,15 @@ impl UpdateFromRefs for Dimension {
 
     fn set_ref(
         &mut self,
-        index: ReferInd,
+        index: &ReferInd,
         result: &RefGeometry,
         other_ref: GeometryId,
         snap_pt: &Option<Point3f>,
     ) {
-        match index {
-            0 => self.first.set_reference(result, other_ref, snap_pt),
-            1 => self.second.set_reference(result, other_ref, snap_pt),
-            _ => (),
+        if *index == Dimension::FIRST {
+            self.first.set_reference(result, other_ref, snap_pt);
+        } else if *index == Dimension::SECOND {
+            self.second.set_reference(result, other_ref, snap_pt);
         }
     }
 
@@ -174,27 +185,29 @@ impl UpdateFromRefs for Dimension {
         return false;
     }
 
-    fn delete_ref(&mut self, index: ReferInd) {
-        match index {
-            0 => self.first.refer = None,
-            1 => self.second.refer = None,
-            _ => (),
+    fn delete_ref(&mut self, index: &ReferInd) {
+        if *index == Dimension::FIRST {
+            self.first.refer = None;
+        } else if *index == Dimension::SECOND {
+            self.second.refer = None;
         }
     }
 
-    fn get_associated_geom(&self, index: ReferInd) -> Option<RefGeometry> {
-        match index {
-            0 => Some(self.first.geom.get_geom()),
-            1 => Some(self.second.geom.get_geom()),
-            _ => None,
+    fn get_associated_geom(&self, index: &ReferInd) -> Option<RefGeometry> {
+        if *index == Dimension::FIRST {
+            Some(self.first.geom.get_geom())
+        } else if *index == Dimension::SECOND {
+            Some(self.second.geom.get_geom())
+        } else {
+            None
         }
     }
 
-    fn set_associated_geom(&mut self, index: ReferInd, geom: &Option<RefGeometry>) {
-        match index {
-            0 => self.first.update(geom),
-            1 => self.second.update(geom),
-            _ => (),
+    fn set_associated_geom(&mut self, index: &ReferInd, geom: &Option<RefGeometry>) {
+        if *index == Dimension::FIRST {
+            self.first.update(geom);
+        } else if *index == Dimension::SECOND {
+            self.second.update(geom);
         }
     }
 }
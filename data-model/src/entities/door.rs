@@ -10,6 +10,13 @@ pub struct Door {
 }
 
 impl Door {
+    pub const FIRST: ResultInd = RefIndex::named("First");
+    pub const SECOND: ResultInd = RefIndex::named("Second");
+    pub const OPENING: ResultInd = RefIndex::named("Opening");
+    ///The line the door sits on, which the first and second points and the opening are all driven by.
+    ///It's a reference and also a result, so the other results can subscribe to it.
+    pub const DIR: ReferInd = RefIndex::named("Dir");
+
     pub fn new(first: Point3f, second: Point3f, width: WorldCoord, height: WorldCoord) -> Door {
         let id = RefID::new_v4();
         Door {
@@ -126,54 +133,61 @@ impl Data for Door {
     }
 }
 
-impl ReferTo for Door {
-    fn get_result(&self, res: ResultInd) -> Option<RefGeometry> {
-        match res {
-            0 => Some(RefGeometry::Point {
-                pt: self.dir.geom.pt_1,
-            }),
-            1 => Some(RefGeometry::Point {
-                pt: self.dir.geom.pt_2,
-            }),
-            2 => {
-                let third = Point3f::new(
-                    self.dir.geom.pt_2.x,
-                    self.dir.geom.pt_2.y,
-                    self.dir.geom.pt_2.z + self.height,
-                );
-                Some(RefGeometry::Rect {
-                    pt_1: self.dir.geom.pt_1,
-                    pt_2: self.dir.geom.pt_2,
-                    pt_3: third,
-                })
-            }
-            _ => None,
-        }
-    }
-
-    fn get_all_results(&self) -> Vec<RefGeometry> {
-        let mut results = Vec::new();
-        results.push(RefGeometry::Point {
-            pt: self.dir.geom.pt_1,
-        });
-        results.push(RefGeometry::Point {
-            pt: self.dir.geom.pt_2,
-        });
+impl Door {
+    fn get_opening(&self) -> RefGeometry {
         let third = Point3f::new(
             self.dir.geom.pt_2.x,
             self.dir.geom.pt_2.y,
             self.dir.geom.pt_2.z + self.height,
         );
-        results.push(RefGeometry::Rect {
+        RefGeometry::Rect {
             pt_1: self.dir.geom.pt_1,
             pt_2: self.dir.geom.pt_2,
             pt_3: third,
-        });
+        }
+    }
+}
+
+impl ReferTo for Door {
+    fn get_result(&self, index: &ResultInd) -> Option<RefGeometry> {
+        if *index == Door::FIRST {
+            Some(RefGeometry::Point {
+                pt: self.dir.geom.pt_1,
+            })
+        } else if *index == Door::SECOND {
+            Some(RefGeometry::Point {
+                pt: self.dir.geom.pt_2,
+            })
+        } else if *index == Door::OPENING {
+            Some(self.get_opening())
+        } else if *index == Door::DIR {
+            Some(self.dir.geom.get_geom())
+        } else {
+            None
+        }
+    }
+
+    fn get_all_results(&self) -> Vec<(ResultInd, RefGeometry)> {
+        let mut results = Vec::new();
+        results.push((
+            Door::FIRST,
+            RefGeometry::Point {
+                pt: self.dir.geom.pt_1,
+            },
+        ));
+        results.push((
+            Door::SECOND,
+            RefGeometry::Point {
+                pt: self.dir.geom.pt_2,
+            },
+        ));
+        results.push((Door::OPENING, self.get_opening()));
+        results.push((Door::DIR, self.dir.geom.get_geom()));
         results
     }
 
-    fn get_num_results(&self) -> usize {
-        3
+    fn is_derived_result(&self, index: &ResultInd) -> bool {
+        *index == Door::DIR
     }
 }
 
@@ -185,28 +199,24 @@ impl UpdateFromRefs for Door {
     fn get_refs(&self) -> Vec<Option<Reference>> {
         let mut results = Vec::new();
         if let Some(id) = &self.dir.refer {
-            results.push(Some(Reference::new(self.id.clone(), 0, id.clone())));
+            results.push(Some(Reference::new(self.id.clone(), Door::DIR, id.clone())));
         } else {
             results.push(None);
         }
-        let self_id_0 = GeometryId::new(self.id.clone(), 0);
-        let self_id_1 = GeometryId::new(self.id.clone(), 1);
-        let self_id_2 = GeometryId::new(self.id.clone(), 2);
-        results.push(Some(Reference {
-            owner: self_id_2.clone(),
-            other: self_id_0,
-        }));
-        results.push(Some(Reference {
-            owner: self_id_2,
-            other: self_id_1,
-        }));
+        let self_dir = GeometryId::new(self.id.clone(), Door::DIR);
+        for index in vec![Door::FIRST, Door::SECOND, Door::OPENING] {
+            results.push(Some(Reference {
+                owner: GeometryId::new(self.id.clone(), index),
+                other: self_dir.clone(),
+            }));
+        }
         results
     }
 
     fn get_available_refs(&self) -> Vec<ReferInd> {
         let mut results = Vec::new();
         if let None = self.dir.refer {
-            results.push(0);
+            results.push(Door::DIR);
         }
         results
     }
@@ -217,14 +227,13 @@ impl UpdateFromRefs for Door {
 
     fn set_ref(
         &mut self,
-        index: ReferInd,
+        index: &ReferInd,
         result: &RefGeometry,
         other_ref: GeometryId,
         snap_pt: &Option<Point3f>,
     ) {
-        match index {
-            0 => self.dir.set_reference(result, other_ref, snap_pt),
-            _ => (),
+        if *index == Door::DIR {
+            self.dir.set_reference(result, other_ref, snap_pt);
         }
     }
 
@@ -232,24 +241,23 @@ impl UpdateFromRefs for Door {
         return false;
     }
 
-    fn delete_ref(&mut self, index: ReferInd) {
-        match index {
-            0 => self.dir.refer = None,
-            _ => (),
+    fn delete_ref(&mut self, index: &ReferInd) {
+        if *index == Door::DIR {
+            self.dir.refer = None;
         }
     }
 
-    fn get_associated_geom(&self, index: ReferInd) -> Option<RefGeometry> {
-        match index {
-            0 => Some(self.dir.geom.get_geom()),
-            _ => None,
+    fn get_associated_geom(&self, index: &ReferInd) -> Option<RefGeometry> {
+        if *index == Door::DIR {
+            Some(self.dir.geom.get_geom())
+        } else {
+            None
         }
     }
 
-    fn set_associated_geom(&mut self, index: ReferInd, geom: &Option<RefGeometry>) {
-        match index {
-            0 => self.dir.update(geom),
-            _ => (),
+    fn set_associated_geom(&mut self, index: &ReferInd, geom: &Option<RefGeometry>) {
+        if *index == Door::DIR {
+            self.dir.update(geom);
         }
     }
 }
@@ -74,18 +74,14 @@ impl Data for Slab {
 }
 
 impl ReferTo for Slab {
-    fn get_result(&self, result: ResultInd) -> Option<RefGeometry> {
+    fn get_result(&self, result: &ResultInd) -> Option<RefGeometry> {
         None
     }
 
-    fn get_all_results(&self) -> Vec<RefGeometry> {
+    fn get_all_results(&self) -> Vec<(ResultInd, RefGeometry)> {
         let mut results = Vec::new();
         results
     }
-
-    fn get_num_results(&self) -> usize {
-        0
-    }
 }
 
 impl UpdateFromRefs for Slab {
@@ -107,7 +103,7 @@ impl UpdateFromRefs for Slab {
 
     fn set_ref(
         &mut self,
-        index: ReferInd,
+        index: &ReferInd,
         result: &RefGeometry,
         other_ref: GeometryId,
         snap_pt: &Option<Point3f>,
@@ -123,13 +119,13 @@ impl UpdateFromRefs for Slab {
         false
     }
 
-    fn delete_ref(&mut self, index: ReferInd) {}
+    fn delete_ref(&mut self, index: &ReferInd) {}
 
-    fn get_associated_geom(&self, index: ReferInd) -> Option<RefGeometry> {
+    fn get_associated_geom(&self, index: &ReferInd) -> Option<RefGeometry> {
         None
     }
 
-    fn set_associated_geom(&mut self, index: ReferInd, geom: &Option<RefGeometry>) {}
+    fn set_associated_geom(&mut self, index: &ReferInd, geom: &Option<RefGeometry>) {}
 }
 
 impl Position for Slab {
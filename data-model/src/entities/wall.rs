@@ -1,6 +1,7 @@
 use crate::*;
 use primitives::PrismOpening;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Wall {
@@ -8,8 +9,10 @@ pub struct Wall {
     pub second_pt: UpdatableGeometry<RefPoint>,
     pub width: WorldCoord,
     pub height: WorldCoord,
-    openings: Vec<UpdatableGeometry<RefRect>>,
-    #[serde(skip_serializing, default = "String::new")]
+    openings: BTreeMap<u64, UpdatableGeometry<RefRect>>,
+    ///Openings are referenced by sub id, and ids are never reused so a reference can't end up on the wrong opening.
+    next_opening: u64,
+    #[serde(skip)]
     data: String,
     id: RefID,
 }
@@ -24,6 +27,17 @@ interfaces!(
 );
 
 impl Wall {
+    pub const FIRST: ResultInd = RefIndex::named("First");
+    pub const SECOND: ResultInd = RefIndex::named("Second");
+    pub const CENTER: ResultInd = RefIndex::named("Center");
+    pub const MIDPOINT: ResultInd = RefIndex::named("Midpoint");
+    pub const RIGHT_FACE: ResultInd = RefIndex::named("RightFace");
+    pub const LEFT_FACE: ResultInd = RefIndex::named("LeftFace");
+    pub const FIRST_RIGHT: ResultInd = RefIndex::named("FirstRight");
+    pub const SECOND_RIGHT: ResultInd = RefIndex::named("SecondRight");
+    pub const SECOND_LEFT: ResultInd = RefIndex::named("SecondLeft");
+    pub const FIRST_LEFT: ResultInd = RefIndex::named("FirstLeft");
+
     pub fn new(first: Point3f, second: Point3f, width: WorldCoord, height: WorldCoord) -> Wall {
         let id = RefID::new_v4();
         Wall {
@@ -31,7 +45,8 @@ impl Wall {
             second_pt: UpdatableGeometry::new(RefPoint { pt: second }),
             width: width,
             height: height,
-            openings: Vec::new(),
+            openings: BTreeMap::new(),
+            next_opening: 0,
             data: String::new(),
             id: id,
         }
@@ -70,10 +85,18 @@ impl Data for Wall {
             })),
         };
         let self_length = (self.second_pt.geom.pt - self.first_pt.geom.pt).magnitude();
-        self.openings.retain(|open| open.refer != None);
-        let mut sorted: Vec<PrismOpening> = self
+        let orphans: Vec<u64> = self
             .openings
             .iter()
+            .filter(|(_, open)| open.refer == None)
+            .map(|(sub, _)| *sub)
+            .collect();
+        for sub in orphans {
+            self.openings.remove(&sub);
+        }
+        let mut sorted: Vec<PrismOpening> = self
+            .openings
+            .values()
             .map(|val| {
                 let position = (val.geom.pt_1 - self.first_pt.geom.pt).magnitude();
                 let interp = Interp::new(position / self_length);
@@ -109,7 +132,7 @@ impl Data for Wall {
         let self_length = (self.second_pt.geom.pt - self.first_pt.geom.pt).magnitude();
         let mut sorted: Vec<PrismOpening> = self
             .openings
-            .iter()
+            .values()
             .map(|val| {
                 let position = (val.geom.pt_1 - self.first_pt.geom.pt).magnitude();
                 let interp = Interp::new(position / self_length);
@@ -175,57 +198,105 @@ impl Data for Wall {
     }
 }
 
+impl Wall {
+    ///Results that are always present.  Everything but the end points is derived from them.
+    fn get_fixed_results(&self) -> Vec<(ResultInd, RefGeometry)> {
+        let first = self.first_pt.geom.pt;
+        let second = self.second_pt.geom.pt;
+        let offset = if first != second {
+            get_perp_2d(&first, &second) * self.width
+        } else {
+            Vector3f::zero()
+        };
+        vec![
+            (Wall::FIRST, RefGeometry::Point { pt: first }),
+            (Wall::SECOND, RefGeometry::Point { pt: second }),
+            (
+                Wall::CENTER,
+                RefGeometry::Line {
+                    pt_1: first,
+                    pt_2: second,
+                },
+            ),
+            (
+                Wall::MIDPOINT,
+                RefGeometry::Point {
+                    pt: first.midpoint(second),
+                },
+            ),
+            (
+                Wall::RIGHT_FACE,
+                RefGeometry::Line {
+                    pt_1: first + offset,
+                    pt_2: second + offset,
+                },
+            ),
+            (
+                Wall::LEFT_FACE,
+                RefGeometry::Line {
+                    pt_1: first - offset,
+                    pt_2: second - offset,
+                },
+            ),
+            (Wall::FIRST_RIGHT, RefGeometry::Point { pt: first + offset }),
+            (
+                Wall::SECOND_RIGHT,
+                RefGeometry::Point {
+                    pt: second + offset,
+                },
+            ),
+            (
+                Wall::SECOND_LEFT,
+                RefGeometry::Point {
+                    pt: second - offset,
+                },
+            ),
+            (Wall::FIRST_LEFT, RefGeometry::Point { pt: first - offset }),
+        ]
+    }
+}
+
 impl ReferTo for Wall {
-    fn get_result(&self, result: ResultInd) -> Option<RefGeometry> {
-        match result {
-            0 => Some(RefGeometry::Point {
-                pt: self.first_pt.geom.pt,
-            }),
-            1 => Some(RefGeometry::Point {
-                pt: self.second_pt.geom.pt,
-            }),
-            2 => Some(RefGeometry::Line {
-                pt_1: self.first_pt.geom.pt,
-                pt_2: self.second_pt.geom.pt,
-            }),
-            _ => {
-                if let Some(open) = self.openings.get(result - 2) {
-                    Some(RefGeometry::Rect {
-                        pt_1: open.geom.pt_1,
-                        pt_2: open.geom.pt_2,
-                        pt_3: open.geom.pt_3,
-                    })
-                } else {
-                    None
-                }
-            }
+    fn get_result(&self, index: &ResultInd) -> Option<RefGeometry> {
+        match index {
+            RefIndex::Named(_) => self
+                .get_fixed_results()
+                .into_iter()
+                .find(|(name, _)| name == index)
+                .map(|(_, geom)| geom),
+            RefIndex::Sub(sub) => self.openings.get(sub).map(|open| open.geom.get_geom()),
         }
     }
 
-    fn get_all_results(&self) -> Vec<RefGeometry> {
-        let mut results = Vec::new();
-        results.push(RefGeometry::Point {
-            pt: self.first_pt.geom.pt,
-        });
-        results.push(RefGeometry::Point {
-            pt: self.second_pt.geom.pt,
-        });
-        results.push(RefGeometry::Line {
-            pt_1: self.first_pt.geom.pt,
-            pt_2: self.second_pt.geom.pt,
-        });
-        for open in &self.openings {
-            results.push(RefGeometry::Rect {
-                pt_1: open.geom.pt_1,
-                pt_2: open.geom.pt_2,
-                pt_3: open.geom.pt_3,
-            });
+    fn get_all_results(&self) -> Vec<(ResultInd, RefGeometry)> {
+        let mut results = self.get_fixed_results();
+        for (sub, open) in &self.openings {
+            results.push((RefIndex::Sub(*sub), open.geom.get_geom()));
         }
         results
     }
 
-    fn get_num_results(&self) -> usize {
-        3 + self.openings.len()
+    fn is_derived_result(&self, index: &ResultInd) -> bool {
+        match index {
+            RefIndex::Named(_) => {
+                *index != Wall::FIRST && *index != Wall::SECOND && *index != Wall::CENTER
+            }
+            RefIndex::Sub(_) => false,
+        }
+    }
+
+    fn snap_type(&self, index: &ResultInd, geom: &RefGeometry) -> SnapType {
+        if *index == Wall::MIDPOINT {
+            SnapType::Midpoint
+        } else if *index == Wall::FIRST_RIGHT
+            || *index == Wall::SECOND_RIGHT
+            || *index == Wall::SECOND_LEFT
+            || *index == Wall::FIRST_LEFT
+        {
+            SnapType::Corner
+        } else {
+            SnapType::for_geometry(geom)
+        }
     }
 }
 
@@ -233,7 +304,7 @@ impl UpdateFromRefs for Wall {
     fn clear_refs(&mut self) {
         self.first_pt.refer = None;
         self.second_pt.refer = None;
-        for open in &mut self.openings {
+        for open in self.openings.values_mut() {
             open.refer = None;
         }
     }
@@ -241,31 +312,43 @@ impl UpdateFromRefs for Wall {
     fn get_refs(&self) -> Vec<Option<Reference>> {
         let mut results = Vec::new();
         if let Some(id) = &self.first_pt.refer {
-            results.push(Some(Reference::new(self.id.clone(), 0, id.clone())));
+            results.push(Some(Reference::new(
+                self.id.clone(),
+                Wall::FIRST,
+                id.clone(),
+            )));
         } else {
             results.push(None);
         }
         if let Some(id) = &self.second_pt.refer {
-            results.push(Some(Reference::new(self.id.clone(), 1, id.clone())));
+            results.push(Some(Reference::new(
+                self.id.clone(),
+                Wall::SECOND,
+                id.clone(),
+            )));
         } else {
             results.push(None);
         }
-        let self_id_0 = GeometryId::new(self.id.clone(), 0);
-        let self_id_1 = GeometryId::new(self.id.clone(), 1);
-        let self_id_2 = GeometryId::new(self.id.clone(), 2);
-        results.push(Some(Reference {
-            owner: self_id_2.clone(),
-            other: self_id_0,
-        }));
-        results.push(Some(Reference {
-            owner: self_id_2,
-            other: self_id_1,
-        }));
-        let mut index = 3;
-        for open in &self.openings {
+        let self_first = GeometryId::new(self.id.clone(), Wall::FIRST);
+        let self_second = GeometryId::new(self.id.clone(), Wall::SECOND);
+        for (index, _) in self.get_fixed_results().into_iter().skip(2) {
+            let derived = GeometryId::new(self.id.clone(), index);
+            results.push(Some(Reference {
+                owner: derived.clone(),
+                other: self_first.clone(),
+            }));
+            results.push(Some(Reference {
+                owner: derived,
+                other: self_second.clone(),
+            }));
+        }
+        for (sub, open) in &self.openings {
             if let Some(id) = &open.refer {
-                results.push(Some(Reference::new(self.id.clone(), index, id.clone())));
-                index += 1;
+                results.push(Some(Reference::new(
+                    self.id.clone(),
+                    RefIndex::Sub(*sub),
+                    id.clone(),
+                )));
             }
         }
         results
@@ -278,28 +361,28 @@ impl UpdateFromRefs for Wall {
     fn get_available_refs(&self) -> Vec<ReferInd> {
         let mut results = Vec::new();
         if let None = self.first_pt.refer {
-            results.push(0);
+            results.push(Wall::FIRST);
         }
         if let None = self.second_pt.refer {
-            results.push(1);
+            results.push(Wall::SECOND);
         }
         results
     }
 
     fn set_ref(
         &mut self,
-        index: ReferInd,
+        index: &ReferInd,
         result: &RefGeometry,
         other_ref: GeometryId,
         snap_pt: &Option<Point3f>,
     ) {
-        match index {
-            0 => self.first_pt.set_reference(result, other_ref, snap_pt),
-            1 => self.second_pt.set_reference(result, other_ref, snap_pt),
-            _ => {
-                if let Some(open) = self.openings.get_mut(index - 3) {
-                    open.set_reference(result, other_ref, snap_pt);
-                }
+        if *index == Wall::FIRST {
+            self.first_pt.set_reference(result, other_ref, snap_pt);
+        } else if *index == Wall::SECOND {
+            self.second_pt.set_reference(result, other_ref, snap_pt);
+        } else if let RefIndex::Sub(sub) = index {
+            if let Some(open) = self.openings.get_mut(sub) {
+                open.set_reference(result, other_ref, snap_pt);
             }
         }
     }
@@ -313,47 +396,44 @@ impl UpdateFromRefs for Wall {
         if let RefGeometry::Rect { pt_1, pt_2, pt_3 } = result {
             let mut new_open = UpdatableGeometry::new(RefRect::new(*pt_1, *pt_2, *pt_3));
             new_open.set_reference(result, other_ref, snap_pt);
-            self.openings.push(new_open);
+            self.openings.insert(self.next_opening, new_open);
+            self.next_opening += 1;
             true
         } else {
             false
         }
     }
 
-    fn delete_ref(&mut self, index: ReferInd) {
-        match index {
-            0 => self.first_pt.refer = None,
-            1 => self.second_pt.refer = None,
-            _ => {
-                if self.openings.len() > (index - 3) {
-                    self.openings.remove(index - 3);
-                }
-            }
+    fn delete_ref(&mut self, index: &ReferInd) {
+        if *index == Wall::FIRST {
+            self.first_pt.refer = None;
+        } else if *index == Wall::SECOND {
+            self.second_pt.refer = None;
+        } else if let RefIndex::Sub(sub) = index {
+            self.openings.remove(sub);
         }
     }
 
-    fn get_associated_geom(&self, index: ReferInd) -> Option<RefGeometry> {
-        match index {
-            0 => Some(self.first_pt.geom.get_geom()),
-            1 => Some(self.second_pt.geom.get_geom()),
-            _ => {
-                if let Some(open) = self.openings.get(index - 3) {
-                    Some(open.geom.get_geom())
-                } else {
-                    None
-                }
-            }
+    fn get_associated_geom(&self, index: &ReferInd) -> Option<RefGeometry> {
+        if *index == Wall::FIRST {
+            Some(self.first_pt.geom.get_geom())
+        } else if *index == Wall::SECOND {
+            Some(self.second_pt.geom.get_geom())
+        } else if let RefIndex::Sub(sub) = index {
+            self.openings.get(sub).map(|open| open.geom.get_geom())
+        } else {
+            None
         }
     }
 
-    fn set_associated_geom(&mut self, index: ReferInd, geom: &Option<RefGeometry>) {
-        match index {
-            0 => self.first_pt.update(geom),
-            1 => self.second_pt.update(geom),
-            _ => {
-                if let Some(open) = self.openings.get_mut(index - 3) {
-                    open.update(geom);
-                }
+    fn set_associated_geom(&mut self, index: &ReferInd, geom: &Option<RefGeometry>) {
+        if *index == Wall::FIRST {
+            self.first_pt.update(geom);
+        } else if *index == Wall::SECOND {
+            self.second_pt.update(geom);
+        } else if let RefIndex::Sub(sub) = index {
+            if let Some(open) = self.openings.get_mut(sub) {
+                open.update(geom);
             }
         }
     }
@@ -363,7 +443,7 @@ impl Position for Wall {
     fn move_obj(&mut self, delta: &Vector3f) {
         self.first_pt.geom.pt += *delta;
         self.second_pt.geom.pt += *delta;
-        for open in &mut self.openings {
+        for open in self.openings.values_mut() {
             open.geom.pt_1 += *delta;
             open.geom.pt_2 += *delta;
             open.geom.pt_3 += *delta;
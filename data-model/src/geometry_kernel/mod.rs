@@ -1,6 +1,7 @@
 use crate::RefID;
 use cgmath::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 
 pub mod primitives;
 
@@ -9,8 +10,32 @@ pub const ORIGIN: Point3f = Point3f::new(0.0, 0.0, 0.0);
 pub type Point3f = cgmath::Point3<f64>;
 pub type WorldCoord = f64;
 pub type Vector3f = cgmath::Vector3<f64>;
-pub type ResultInd = usize;
-pub type ReferInd = usize;
+pub type ResultInd = RefIndex;
+pub type ReferInd = RefIndex;
+
+///Identifies a result or a reference slot within one object.  Geometry that is always there gets a name.
+///Geometry that comes and goes, like wall openings, gets a sub id that the object never reuses,
+///so removing one doesn't change what the others are called.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RefIndex {
+    Named(Cow<'static, str>),
+    Sub(u64),
+}
+
+impl RefIndex {
+    pub const fn named(name: &'static str) -> RefIndex {
+        RefIndex::Named(Cow::Borrowed(name))
+    }
+}
+
+impl std::fmt::Display for RefIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RefIndex::Named(name) => write!(f, "{}", name),
+            RefIndex::Sub(sub) => write!(f, "#{}", sub),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Line {
@@ -73,9 +98,15 @@ impl Updatable for RefPoint {
         RefGeometry::Point { pt: self.pt }
     }
 
-    fn update_geom(&mut self, geom: &RefGeometry, _: &Option<Point3f>) {
-        if let RefGeometry::Point { pt } = geom {
-            self.pt = *pt;
+    fn update_geom(&mut self, geom: &RefGeometry, snap_pt: &Option<Point3f>) {
+        match geom {
+            RefGeometry::Point { pt } => self.pt = *pt,
+            //A point referencing a line stays where it is along the line, so it follows the line sideways
+            RefGeometry::Line { pt_1, pt_2 } => {
+                let along = snap_pt.unwrap_or(self.pt);
+                self.pt = project_on_line(pt_1, pt_2, &along);
+            }
+            _ => (),
         }
     }
 }
@@ -160,14 +191,14 @@ impl Updatable for RefRect {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Hash)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, PartialOrd, Eq, Ord, Hash)]
 pub struct GeometryId {
     pub id: RefID,
     pub index: ResultInd,
 }
 
 impl GeometryId {
-    pub fn new(id: RefID, index: usize) -> GeometryId {
+    pub fn new(id: RefID, index: ResultInd) -> GeometryId {
         GeometryId { id, index }
     }
 }
@@ -180,7 +211,7 @@ pub struct Reference {
 }
 
 impl Reference {
-    pub fn new(owner_id: RefID, owner_index: usize, other: GeometryId) -> Reference {
+    pub fn new(owner_id: RefID, owner_index: ReferInd, other: GeometryId) -> Reference {
         Reference {
             owner: GeometryId::new(owner_id, owner_index),
             other,
@@ -266,20 +297,70 @@ impl RefGeometry {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct MeshData {
     pub id: RefID,
-    pub positions: Vec<WorldCoord>,
-    pub indices: Vec<u64>,
+    ///Single precision is plenty for display, and halves what has to be sent.
+    pub positions: Vec<f32>,
+    pub indices: Vec<u32>,
     pub metadata: Option<serde_json::Value>,
 }
 
 impl MeshData {
     pub fn push_pt(&mut self, pt: Point3f) {
         //Bake in coordinate transformations to graphical space
-        self.positions.push(pt.x);
-        self.positions.push(pt.z);
-        self.positions.push(-pt.y);
+        self.positions.push(pt.x as f32);
+        self.positions.push(pt.z as f32);
+        self.positions.push(-pt.y as f32);
+    }
+
+    ///Returns just the vertices that moved between this and `newer`, if sending those is smaller.
+    ///Only meshes with the same faces can be diffed.
+    pub fn diff(&self, newer: &MeshData) -> Option<MeshDelta> {
+        if self.id != newer.id
+            || self.indices != newer.indices
+            || self.metadata != newer.metadata
+            || self.positions.len() != newer.positions.len()
+        {
+            return None;
+        }
+        let mut delta = MeshDelta {
+            id: newer.id,
+            vertices: Vec::new(),
+            positions: Vec::new(),
+        };
+        for (i, (old, new)) in self
+            .positions
+            .chunks(3)
+            .zip(newer.positions.chunks(3))
+            .enumerate()
+        {
+            if old != new {
+                delta.vertices.push(i as u32);
+                delta.positions.extend_from_slice(new);
+            }
+        }
+        //Each moved vertex costs its index on top of its position
+        if delta.vertices.len() * 4 < newer.positions.len() {
+            Some(delta)
+        } else {
+            None
+        }
+    }
+
+    pub fn apply(&mut self, delta: &MeshDelta) {
+        for (vertex, pos) in delta.vertices.iter().zip(delta.positions.chunks(3)) {
+            let start = *vertex as usize * 3;
+            self.positions[start..start + 3].copy_from_slice(pos);
+        }
     }
 }
 
+///The vertices of a mesh that moved since it was last sent, with three positions for each.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MeshDelta {
+    pub id: RefID,
+    pub vertices: Vec<u32>,
+    pub positions: Vec<f32>,
+}
+
 pub trait Position {
     fn move_obj(&mut self, delta: &Vector3f);
 }
@@ -290,6 +371,40 @@ pub fn project_on_line(first: &Point3f, second: &Point3f, project: &Point3f) ->
     first + proj_vec
 }
 
+///Like project_on_line, but the result is clamped to lie between first and second.
+pub fn project_on_segment(first: &Point3f, second: &Point3f, project: &Point3f) -> Point3f {
+    let dir = second - first;
+    let len2 = dir.magnitude2();
+    if len2 == 0.0 {
+        return *first;
+    }
+    let interp = Interp::new((project - first).dot(dir) / len2);
+    first + dir * interp.val()
+}
+
+///Intersects two segments in the XY plane.  The Z coordinate of the result is taken from the first segment.
+///Parallel segments don't intersect.
+pub fn intersect_segments_2d(
+    first_1: &Point3f,
+    first_2: &Point3f,
+    second_1: &Point3f,
+    second_2: &Point3f,
+) -> Option<Point3f> {
+    let dir_1 = first_2 - first_1;
+    let dir_2 = second_2 - second_1;
+    let denom = dir_1.x * dir_2.y - dir_1.y * dir_2.x;
+    if denom.abs() < std::f64::EPSILON {
+        return None;
+    }
+    let between = second_1 - first_1;
+    let t = (between.x * dir_2.y - between.y * dir_2.x) / denom;
+    let u = (between.x * dir_1.y - between.y * dir_1.x) / denom;
+    if t < 0.0 || t > 1.0 || u < 0.0 || u > 1.0 {
+        return None;
+    }
+    Some(first_1 + dir_1 * t)
+}
+
 pub fn get_interp_along_line(first: &Point3f, second: &Point3f, project: &Point3f) -> Interp {
     let dir = second - first;
     let proj_vec = (project - first).project_on(dir);
@@ -341,6 +456,39 @@ impl Interp {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_mesh_diff() {
+        let mut old = MeshData {
+            id: RefID::new_v4(),
+            positions: Vec::new(),
+            indices: Vec::new(),
+            metadata: None,
+        };
+        primitives::rectangular_prism(
+            &Point3f::new(0.0, 0.0, 0.0),
+            &Point3f::new(1.0, 0.0, 0.0),
+            1.0,
+            1.0,
+            &mut old,
+        );
+        let mut new = old.clone();
+        new.positions[3..6].copy_from_slice(&[5.0, 6.0, 7.0]);
+        let delta = old.diff(&new).unwrap();
+        assert_eq!(delta.vertices, vec![1]);
+        assert_eq!(delta.positions, vec![5.0, 6.0, 7.0]);
+        let mut applied = old.clone();
+        applied.apply(&delta);
+        assert_eq!(applied, new);
+
+        //Moving most of the vertices is cheaper to send whole
+        let mut moved = old.clone();
+        moved.positions.iter_mut().for_each(|pos| *pos += 1.0);
+        assert_eq!(old.diff(&moved), None);
+        let mut reshaped = new.clone();
+        reshaped.indices.pop();
+        assert_eq!(old.diff(&reshaped), None);
+    }
+
     #[test]
     fn test_project_on_line() {
         let first = Point3f::new(0.0, 0.0, 0.0);
@@ -375,4 +523,49 @@ mod tests {
             Point3f::new(-45.0, 20.0, 0.0)
         );
     }
+
+    #[test]
+    fn test_project_on_segment() {
+        let first = Point3f::new(0.0, 0.0, 0.0);
+        let second = Point3f::new(1.0, 0.0, 0.0);
+        let project = Point3f::new(0.5, 1.0, 0.0);
+        assert_eq!(
+            project_on_segment(&first, &second, &project),
+            Point3f::new(0.5, 0.0, 0.0)
+        );
+
+        let project = Point3f::new(-1.0, -1.0, 1.0);
+        assert_eq!(project_on_segment(&first, &second, &project), first);
+
+        let project = Point3f::new(3.0, 1.0, 0.0);
+        assert_eq!(project_on_segment(&first, &second, &project), second);
+
+        assert_eq!(project_on_segment(&first, &first, &project), first);
+    }
+
+    #[test]
+    fn test_intersect_segments_2d() {
+        let first_1 = Point3f::new(0.0, 0.0, 0.0);
+        let first_2 = Point3f::new(2.0, 0.0, 0.0);
+        let second_1 = Point3f::new(1.0, -1.0, 0.0);
+        let second_2 = Point3f::new(1.0, 1.0, 0.0);
+        assert_eq!(
+            intersect_segments_2d(&first_1, &first_2, &second_1, &second_2),
+            Some(Point3f::new(1.0, 0.0, 0.0))
+        );
+
+        let second_1 = Point3f::new(3.0, -1.0, 0.0);
+        let second_2 = Point3f::new(3.0, 1.0, 0.0);
+        assert_eq!(
+            intersect_segments_2d(&first_1, &first_2, &second_1, &second_2),
+            None
+        );
+
+        let second_1 = Point3f::new(0.0, 1.0, 0.0);
+        let second_2 = Point3f::new(2.0, 1.0, 0.0);
+        assert_eq!(
+            intersect_segments_2d(&first_1, &first_2, &second_1, &second_2),
+            None
+        );
+    }
 }
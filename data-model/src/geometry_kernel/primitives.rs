@@ -15,9 +15,9 @@ fn prism_and_next_opening(
     offset: &Vector3f,
     vert_offset: &Vector3f,
     hole_offset: &Vector3f,
-    index: u64,
+    index: u32,
     results: &mut MeshData,
-) -> u64 {
+) -> u32 {
     let zero = first_pt + offset;
     let first = first_pt - offset;
     let second = second_pt + offset;
@@ -74,9 +74,9 @@ fn prism(
     second_pt: &Point3f,
     offset: &Vector3f,
     vert_offset: &Vector3f,
-    index: u64,
+    index: u32,
     results: &mut MeshData,
-) -> u64 {
+) -> u32 {
     let first = first_pt + offset;
     let second = first_pt - offset;
     let third = second_pt + offset;
@@ -20,7 +20,9 @@ pub use entities::slab::Slab;
 pub use entities::wall::Wall;
 pub use geometry_kernel::*;
 
-#[derive(Debug, PartialEq)]
+///Sent to clients as `{"code": "Locked", "detail": "..."}`, where `detail` is left out for errors without one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "code", content = "detail")]
 pub enum DBError {
     NotFound(String),
     Locked(String),
@@ -32,6 +34,14 @@ pub enum DBError {
     UserNotFound,
     ObjLacksTrait,
     TimedOut,
+    ///These objects have been changed by later events.
+    ModifiedSince(Vec<RefID>),
+    ///A client sent something that couldn't be read as a command.
+    BadRequest(String),
+    ///A client fell too far behind on updates.
+    QueueFull,
+    ///The user doesn't have access to do this.
+    Forbidden(String),
     Other(String),
 }
 
@@ -48,6 +58,9 @@ pub enum UpdateMsg {
     Mesh {
         data: MeshData,
     },
+    MeshDelta {
+        data: MeshDelta,
+    },
     Read {
         query_id: QueryID,
         user: UserID,
@@ -56,13 +69,95 @@ pub enum UpdateMsg {
     Other {
         data: serde_json::Value,
     },
+    Saved {
+        autosave: bool,
+    },
+    Modified {
+        modified: bool,
+    },
+    ///Another user started or finished editing an object, or just one of its properties if `prop` is set.
+    ///No owner means it's unlocked.
+    Lock {
+        key: RefID,
+        prop: Option<String>,
+        owner: Option<UserID>,
+    },
+    ///Someone joined the file, or changed how they're shown to others.
+    Presence {
+        user: UserID,
+        name: String,
+        color: String,
+    },
+    UserLeft {
+        user: UserID,
+    },
+    Cursor {
+        user: UserID,
+        pos: Point3f,
+    },
+    Selection {
+        user: UserID,
+        ids: Vec<RefID>,
+    },
+    ///A command finished successfully.  It's queued behind the updates the command made, so they've all arrived
+    ///by the time it does.  Work the command left to the background, like updating dependents outside of an
+    ///atomic event or opening a file, can still be going on.
+    Ack {
+        request_id: RequestID,
+    },
+    ///A command failed.  Errors without a request id can't be tied to a command: a failed autosave, background work
+    ///that failed after its command was acked, like opening a file or updating dependents, or losing access to the file.
     Error {
-        msg: String,
+        request_id: Option<RequestID>,
+        error: DBError,
     },
+    ///A broadcast change, numbered so a reconnecting client can ask for what it missed.
+    Sequenced {
+        seq: u64,
+        msg: Box<UpdateMsg>,
+    },
+    ///A new reference closed a dependency cycle.  That's allowed, since joined objects refer to each
+    ///other, but everything in the cycle is updated as a group.
+    DepCycle {
+        publisher: GeometryId,
+        sub: GeometryId,
+    },
+    ///The client missed updates and should reconnect to catch up.
+    ResyncRequired,
+    ///The client is caught up to `seq` in this session.  If `full`, it should clear everything and
+    ///expect the whole file to be resent.
+    Synced {
+        session: SessionID,
+        seq: u64,
+        full: bool,
+    },
+}
+
+impl UpdateMsg {
+    ///Strips the sequence number from a broadcast change.
+    pub fn unsequenced(self) -> UpdateMsg {
+        match self {
+            UpdateMsg::Sequenced { msg, .. } => *msg,
+            other => other,
+        }
+    }
+
+    ///The object this replaces the last update for, if any.
+    pub fn get_key(&self) -> Option<RefID> {
+        match self {
+            UpdateMsg::Mesh { data } => Some(data.id),
+            UpdateMsg::MeshDelta { data } => Some(data.id),
+            UpdateMsg::Delete { key } => Some(*key),
+            UpdateMsg::Other { data } => data.get("id").and_then(|id| RefID::deserialize(id).ok()),
+            UpdateMsg::Sequenced { msg, .. } => msg.get_key(),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct CmdMsg {
+    pub request_id: RequestID,
     pub func_name: String,
     pub params: Vec<serde_json::Value>,
 }
@@ -85,6 +180,21 @@ pub fn from_json(type_str: &str, obj: serde_json::Value) -> Result<DataObject, D
     }
 }
 
+///Files written before results had stable indices referred to them by position.
+///This maps an old position on an object of the given type to its current index.
+pub fn legacy_result_index(type_str: &str, index: u64) -> Option<ResultInd> {
+    match (type_str, index) {
+        ("Wall", 0) => Some(Wall::FIRST),
+        ("Wall", 1) => Some(Wall::SECOND),
+        ("Wall", 2) => Some(Wall::CENTER),
+        ("Wall", opening) => Some(RefIndex::Sub(opening - 3)),
+        ("Door", 0) => Some(Door::FIRST),
+        ("Door", 1) => Some(Door::SECOND),
+        ("Door", 2) => Some(Door::OPENING),
+        _ => None,
+    }
+}
+
 #[typetag::serde]
 pub trait Data: Object + Send + Sync {
     fn get_id(&self) -> &RefID;
@@ -94,6 +204,10 @@ pub trait Data: Object + Send + Sync {
     fn set_data(&mut self, data: serde_json::Value) -> Result<(), DBError>;
     //Only use this if you know exactly what you're doing.
     fn set_id(&mut self, id: RefID);
+    ///The name the type is tagged with when it's serialized, like "Wall".
+    fn get_type_name(&self) -> &'static str {
+        self.typetag_name()
+    }
 }
 mopo!(dyn Data);
 
@@ -102,11 +216,42 @@ pub type RefID = Uuid;
 pub type UserID = Uuid;
 pub type UndoEventID = Uuid;
 pub type QueryID = Uuid;
+pub type SessionID = Uuid;
+pub type RequestID = Uuid;
+
+///The kind of geometry a snap landed on.  When more than one candidate is within the tolerance, earlier variants win.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SnapType {
+    Endpoint,
+    Corner,
+    Intersection,
+    Midpoint,
+    Perpendicular,
+    Nearest,
+    Grid,
+}
+
+impl SnapType {
+    ///Points snap as endpoints, and anything else as the nearest spot on it.
+    pub fn for_geometry(geom: &RefGeometry) -> SnapType {
+        match geom {
+            RefGeometry::Point { .. } => SnapType::Endpoint,
+            _ => SnapType::Nearest,
+        }
+    }
+}
 
 pub trait ReferTo {
-    fn get_result(&self, index: ResultInd) -> Option<RefGeometry>;
-    fn get_all_results(&self) -> Vec<RefGeometry>;
-    fn get_num_results(&self) -> usize;
+    fn get_result(&self, index: &ResultInd) -> Option<RefGeometry>;
+    fn get_all_results(&self) -> Vec<(ResultInd, RefGeometry)>;
+    ///Derived results, like midpoints and faces, can be referenced but aren't used when joining objects together.
+    fn is_derived_result(&self, _index: &ResultInd) -> bool {
+        false
+    }
+    ///What snapping to the result counts as.  Override it for results like corners and midpoints.
+    fn snap_type(&self, _index: &ResultInd, geom: &RefGeometry) -> SnapType {
+        SnapType::for_geometry(geom)
+    }
 }
 
 pub trait UpdateFromRefs {
@@ -116,7 +261,7 @@ pub trait UpdateFromRefs {
     fn get_num_refs(&self) -> usize;
     fn set_ref(
         &mut self,
-        index: ReferInd,
+        index: &ReferInd,
         result: &RefGeometry,
         other_ref: GeometryId,
         snap_pt: &Option<Point3f>,
@@ -127,7 +272,7 @@ pub trait UpdateFromRefs {
         other_ref: GeometryId,
         snap_pt: &Option<Point3f>,
     ) -> bool;
-    fn delete_ref(&mut self, index: ReferInd);
-    fn get_associated_geom(&self, index: ReferInd) -> Option<RefGeometry>;
-    fn set_associated_geom(&mut self, index: ReferInd, geom: &Option<RefGeometry>);
+    fn delete_ref(&mut self, index: &ReferInd);
+    fn get_associated_geom(&self, index: &ReferInd) -> Option<RefGeometry>;
+    fn set_associated_geom(&mut self, index: &ReferInd, geom: &Option<RefGeometry>);
 }
@@ -1,13 +1,19 @@
-use crate::operation_manager::OperationManager;
+use crate::operation_manager::{
+    FileHeader, GraphFormat, HistoryEntry, LockInfo, OperationManager, OverflowPolicy, ProjectInfo, QueueStats, UndoLimits, Units, UserPresence,
+};
 use crate::prelude::*;
 use ccl::dhashmap::DHashMap;
 use crossbeam_channel::Sender;
 use std::path::PathBuf;
+use std::sync::Once;
+use std::time::{Duration, Instant};
 
 lazy_static! {
     static ref APP_STATE: AppState = AppState::new();
 }
 
+static START_AUTOSAVE: Once = Once::new();
+
 pub struct AppState {
     files: DHashMap<PathBuf, OperationManager>,
 }
@@ -22,7 +28,9 @@ impl AppState {
 pub fn init_file(file: PathBuf, user: UserID, updates: Sender<UpdateMsg>) {
     rayon::ThreadPoolBuilder::new().num_threads(6).build_global();
     if let Some(ops) = APP_STATE.files.get(&file) {
-        ops.updates.insert(user, updates);
+        if let Err(e) = ops.add_user(user, updates) {
+            error!("Error adding user {:?}: {:?}", user, e);
+        }
         info!("Sending file updates");
         if let Some(ops) = APP_STATE.files.get(&file) {
             if let Err(e) = ops.update_all(Some(&user)) {
@@ -31,29 +39,71 @@ pub fn init_file(file: PathBuf, user: UserID, updates: Sender<UpdateMsg>) {
         }
     } else if file.exists() {
         info!("Going to open file {:?}", file);
+        //The command has been answered by the time this finishes, so a failure can't be tied to it
+        let errors = updates.clone();
         rayon::spawn(move || match OperationManager::open(&file, user, updates) {
             Ok(ops) => {
                 info!("Opened file {:?}", file);
+                if let Err(e) = ops.open_journal(&file) {
+                    error!("Couldn't open journal for {:?}: {:?}", file, e);
+                }
                 APP_STATE.files.insert(file.clone(), ops);
             }
-            Err(e) => error!("Couldn't open file: {:?}", e),
+            Err(e) => {
+                error!("Couldn't open file: {:?}", e);
+                if let Err(e) = errors.try_send(UpdateMsg::Error { request_id: None, error: e }) {
+                    debug!("Couldn't send open error for {:?}: {:?}", file, e);
+                }
+            }
         })
     } else {
-        APP_STATE.files.insert(file.clone(), OperationManager::new(user, updates));
+        let ops = OperationManager::new(user, updates);
+        if let Err(e) = ops.open_journal(&file) {
+            error!("Couldn't open journal for {:?}: {:?}", file, e);
+        }
+        APP_STATE.files.insert(file.clone(), ops);
+    }
+}
+
+///Reattaches a user after their connection dropped, resending only what they missed if possible.
+pub fn reconnect_file(file: PathBuf, user: UserID, updates: Sender<UpdateMsg>, session: SessionID, last_seq: u64) {
+    match APP_STATE.files.get(&file) {
+        Some(ops) => {
+            if let Err(e) = ops.reconnect(user, updates, session, last_seq) {
+                error!("Error reconnecting user {:?}: {:?}", user, e);
+            }
+        }
+        None => init_file(file, user, updates),
     }
 }
 
 pub fn close_file(file: PathBuf, user: UserID) {
     let mut remove = false;
     if let Some(ops) = APP_STATE.files.get(&file) {
+        //Anything the user left half done is dropped, and lets go of the objects it had locked
+        if let Err(e) = ops.cancel_user_events(&user) {
+            error!("Error cancelling events for {:?}, {:?}", user, e);
+        }
         info!("Saving file: {:?}", file);
-        if let Err(e) = ops.save(&file) {
+        let saved = ops.save(&file);
+        if let Err(e) = &saved {
             error!("Error saving file {:?}, {:?}", file, e);
         }
         info!("Removing user {:?} from file {:?}", user, file);
-        ops.updates.remove(&user);
+        if let Err(e) = ops.remove_user(&user) {
+            error!("Error removing user {:?}, {:?}", user, e);
+        }
+        if let Err(e) = ops.release_locks(&user) {
+            error!("Error releasing locks for {:?}, {:?}", user, e);
+        }
         if ops.updates.len() == 0 {
             remove = true;
+            //Keep the journal around if the save failed, so the changes can still be recovered
+            if saved.is_ok() {
+                if let Err(e) = ops.close_journal() {
+                    error!("Error removing journal for {:?}, {:?}", file, e);
+                }
+            }
         }
     }
     if remove {
@@ -64,7 +114,77 @@ pub fn close_file(file: PathBuf, user: UserID) {
 
 pub fn save_file(file: &PathBuf) -> Result<(), DBError> {
     match APP_STATE.files.get(file) {
-        Some(ops) => ops.save(file),
+        Some(ops) => {
+            ops.save(file)?;
+            ops.send(UpdateMsg::Saved { autosave: false }, None)
+        }
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+///How often the autosave thread looks for files that are due.
+const AUTOSAVE_TICK: Duration = Duration::from_secs(1);
+
+///Saves the file in the background every interval, whenever it's been modified since the last save.  None turns autosave off.
+///One thread checks every file each second, so an autosave can run up to a second late.
+pub fn set_autosave(file: &PathBuf, interval: Option<Duration>) -> Result<(), DBError> {
+    if interval == Some(Duration::from_secs(0)) {
+        return Err(DBError::Other(String::from("The autosave interval can't be zero")));
+    }
+    match APP_STATE.files.get(file) {
+        Some(ops) => ops.set_autosave(interval),
+        None => return Err(DBError::FileNotFound),
+    }
+    if interval.is_some() {
+        START_AUTOSAVE.call_once(|| {
+            std::thread::spawn(|| loop {
+                std::thread::sleep(AUTOSAVE_TICK);
+                autosave_tick(Instant::now());
+            });
+        });
+    }
+    Ok(())
+}
+
+///Saves every file whose autosave is due at `now`, if it's been modified since the last save.
+pub fn autosave_tick(now: Instant) {
+    let mut due = Vec::new();
+    for chunk in APP_STATE.files.chunks() {
+        for (file, ops) in chunk.iter() {
+            if ops.is_autosave_due(now) {
+                due.push(file.clone());
+            }
+        }
+    }
+    for file in due {
+        if let Some(ops) = APP_STATE.files.get(&file) {
+            if ops.is_modified() {
+                info!("Autosaving file {:?}", file);
+                let msg = match ops.save(&file) {
+                    Ok(()) => UpdateMsg::Saved { autosave: true },
+                    Err(e) => {
+                        error!("Error autosaving file {:?}, {:?}", file, e);
+                        UpdateMsg::Error { request_id: None, error: e }
+                    }
+                };
+                if let Err(e) = ops.send(msg, None) {
+                    error!("Error sending autosave result {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+pub fn is_modified(file: &PathBuf) -> Result<bool, DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => Ok(ops.is_modified()),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+pub fn set_backup_count(file: &PathBuf, count: usize) -> Result<(), DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => Ok(ops.set_backup_count(count)),
         None => Err(DBError::FileNotFound),
     }
 }
@@ -85,6 +205,14 @@ pub fn save_as_file(orig_file: &PathBuf, file_new: PathBuf) -> Result<(), DBErro
     }
 }
 
+///Queues a message for one user behind the updates already waiting for them, like the answer to a command.
+pub fn send_reply(file: &PathBuf, user: &UserID, msg: UpdateMsg) -> Result<(), DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => ops.send(msg, Some(user)),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
 pub fn send_read_result(file: &PathBuf, query_id: QueryID, user: &UserID, data: serde_json::Value) -> Result<(), DBError> {
     match APP_STATE.files.get(file) {
         Some(ops) => {
@@ -109,6 +237,27 @@ pub fn begin_undo_event(file: &PathBuf, user_id: &UserID, event_id: UndoEventID,
     }
 }
 
+pub fn begin_atomic_event(file: &PathBuf, user_id: &UserID, event_id: UndoEventID, desc: String) -> Result<(), DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => ops.begin_atomic_event(user_id, event_id, desc),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+///Runs an operation that changes the model as part of an event.  If the operation fails and the event is atomic,
+///the whole event is rolled back before the error is returned.
+pub fn run_in_event<T>(file: &PathBuf, event: &UndoEventID, operation: impl FnOnce() -> Result<T, DBError>) -> Result<T, DBError> {
+    let result = operation();
+    if result.is_err() {
+        if let Some(ops) = APP_STATE.files.get(file) {
+            if let Err(e) = ops.abort_event(event) {
+                error!("Error rolling back event {:?}: {:?}", event, e);
+            }
+        }
+    }
+    result
+}
+
 pub fn end_undo_event(file: &PathBuf, event: UndoEventID) -> Result<(), DBError> {
     match APP_STATE.files.get(file) {
         Some(ops) => ops.end_undo_event(event),
@@ -130,6 +279,71 @@ pub fn redo_latest(file: &PathBuf, user: &UserID) -> Result<(), DBError> {
     }
 }
 
+pub fn set_undo_limits(file: &PathBuf, limits: UndoLimits) -> Result<(), DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => ops.set_undo_limits(limits),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+pub fn set_user_info(file: &PathBuf, user: &UserID, name: String, color: String) -> Result<(), DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => ops.set_user_info(user, name, color),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+///Changes that come too quickly are held back, and sent from the user's `flush_updates`.
+pub fn set_cursor(file: &PathBuf, user: &UserID, pos: Point3f) -> Result<(), DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => ops.set_cursor(user, pos),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+///Changes that come too quickly are held back, and sent from the user's `flush_updates`.
+pub fn set_selection(file: &PathBuf, user: &UserID, ids: Vec<RefID>) -> Result<(), DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => ops.set_selection(user, ids),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+pub fn get_users(file: &PathBuf) -> Result<Vec<UserPresence>, DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => Ok(ops.get_users()),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+pub fn get_locks(file: &PathBuf) -> Result<Vec<LockInfo>, DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => Ok(ops.get_locks()),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+pub fn get_history(file: &PathBuf) -> Result<Vec<HistoryEntry>, DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => Ok(ops.get_history()),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+pub fn undo_to(file: &PathBuf, user: &UserID, event: &UndoEventID) -> Result<(), DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => ops.undo_to(user, event),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+pub fn revert_event(file: &PathBuf, user: &UserID, event: &UndoEventID) -> Result<(), DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => ops.revert_event(user, event),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
 pub fn suspend_event(file: &PathBuf, event: &UndoEventID) -> Result<(), DBError> {
     match APP_STATE.files.get(file) {
         Some(ops) => ops.suspend_event(event),
@@ -184,6 +398,13 @@ pub fn modify_obj(
     }
 }
 
+pub fn set_obj_props(file: &PathBuf, event: &UndoEventID, id: &RefID, data: serde_json::Value) -> Result<(), DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => ops.set_obj_props(event, id, data),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
 pub fn delete_obj(file: &PathBuf, event: &UndoEventID, id: &RefID) -> Result<DataObject, DBError> {
     match APP_STATE.files.get(file) {
         Some(ops) => ops.delete_obj(event, id),
@@ -225,7 +446,7 @@ pub fn set_ref(
 ) -> Result<(), DBError> {
     modify_obj(&file, &event, &obj, |owner| match owner.query_mut::<dyn UpdateFromRefs>() {
         Some(joinable) => {
-            joinable.set_ref(index, result, refer.clone(), snap_pt);
+            joinable.set_ref(&index, result, refer.clone(), snap_pt);
             Ok(())
         }
         None => Err(DBError::ObjLacksTrait),
@@ -243,16 +464,37 @@ pub fn update_deps(file: PathBuf, id: RefID) {
     });
 }
 
+///Updates dependents in the background.  Failures go to everyone as errors without a request id, since whatever
+///command changed the objects has already been answered.
 pub fn update_all_deps(file: PathBuf, ids: Vec<RefID>) {
     rayon::spawn(move || {
         if let Some(ops) = APP_STATE.files.get(&file) {
             if let Err(e) = ops.update_all_deps(ids) {
-                println!("Error in background: {:?}", e);
+                error!("Error updating dependents in {:?}: {:?}", file, e);
+                if let Err(e) = ops.send(UpdateMsg::Error { request_id: None, error: e }, None) {
+                    error!("Error sending error for {:?}: {:?}", file, e);
+                }
             }
         }
     });
 }
 
+///Updates whatever depends on the objects an operation changed.  In an atomic event that happens before returning, so a
+///failure rolls the event back with the rest of the operation.  Otherwise it's left to the background, like
+///`update_all_deps`.
+pub fn update_deps_in_event(file: &PathBuf, event: &UndoEventID, ids: Vec<RefID>) -> Result<(), DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => {
+            if ops.is_atomic(event) {
+                return ops.update_all_deps(ids);
+            }
+        }
+        None => return Err(DBError::FileNotFound),
+    }
+    update_all_deps(file.clone(), ids);
+    Ok(())
+}
+
 pub fn add_deps(file: &PathBuf, id: &RefID) -> Result<(), DBError> {
     match APP_STATE.files.get(file) {
         Some(ops) => ops.add_deps(id),
@@ -267,6 +509,71 @@ pub fn remove_dep(file: &PathBuf, publisher: &GeometryId, subscriber: &GeometryI
     }
 }
 
+pub fn get_objs_near(file: &PathBuf, pt: &Point3f, tolerance: WorldCoord) -> Result<HashSet<RefID>, DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => Ok(ops.get_objs_near(pt, tolerance)),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+pub fn get_file_header(file: &PathBuf) -> Result<FileHeader, DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => Ok(ops.get_file_header()),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+pub fn set_file_info(file: &PathBuf, units: Units, project: ProjectInfo) -> Result<(), DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => ops.set_file_info(units, project),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+pub fn set_history_depth(file: &PathBuf, depth: usize) -> Result<(), DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => ops.set_history_depth(depth),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+pub fn set_overflow_policy(file: &PathBuf, user: &UserID, policy: OverflowPolicy) -> Result<(), DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => ops.set_overflow_policy(user, policy),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+///Call this regularly while a user is connected, so updates held back while they were behind get sent, along with
+///their own cursor and selection changes that were held back.
+pub fn flush_updates(file: &PathBuf, user: &UserID) -> Result<(), DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => ops.flush_updates(user),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+pub fn get_queue_stats(file: &PathBuf) -> Result<Vec<QueueStats>, DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => Ok(ops.get_queue_stats()),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+pub fn get_dep_cycles(file: &PathBuf) -> Result<Vec<Vec<GeometryId>>, DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => Ok(ops.get_dep_cycles()),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
+pub fn export_deps(file: &PathBuf, format: GraphFormat) -> Result<serde_json::Value, DBError> {
+    match APP_STATE.files.get(file) {
+        Some(ops) => ops.export_deps(format),
+        None => Err(DBError::FileNotFound),
+    }
+}
+
 pub fn copy_obj(file: &PathBuf, event: &UndoEventID, id: &RefID) -> Result<RefID, DBError> {
     match APP_STATE.files.get(file) {
         Some(ops) => ops.copy_obj(event, id),
@@ -0,0 +1,18 @@
+//! Converts a model between JSON (.flx), binary (.flxb) and compressed binary (.flxz), so binary files can still be diffed and debugged.
+//!
+//! Usage: flx_convert <input> <output>
+use std::path::PathBuf;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <input> <output>", args[0]);
+        std::process::exit(2);
+    }
+    let from = PathBuf::from(&args[1]);
+    let to = PathBuf::from(&args[2]);
+    if let Err(e) = operations_kernel::convert_file(&from, &to) {
+        eprintln!("Couldn't convert {:?} to {:?}: {:?}", from, to, e);
+        std::process::exit(1);
+    }
+}
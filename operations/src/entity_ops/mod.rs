@@ -29,7 +29,7 @@ pub fn get_obj_data(file: &PathBuf, id: &RefID, prop_name: &str) -> Result<serde
 }
 
 pub fn set_obj_data(file: &PathBuf, event: &UndoEventID, id: &RefID, data: serde_json::Value) -> Result<(), DBError> {
-    app_state::modify_obj(file, event, id, move |obj| obj.set_data(data.clone()))
+    app_state::set_obj_props(file, event, id, data)
 }
 
 pub fn copy_objs(file: &PathBuf, event: &UndoEventID, ids: HashSet<RefID>) -> Result<(Vec<RefID>, HashMap<RefID, RefID>), DBError> {
@@ -48,12 +48,12 @@ pub fn copy_objs(file: &PathBuf, event: &UndoEventID, ids: HashSet<RefID>) -> Re
                     if let Some(this_ref) = ref_opt {
                         if let Some(ref_copy_id) = orig_to_copy.get(&this_ref.other.id) {
                             if let Some(has_ref_res) = obj.query_ref::<dyn ReferTo>() {
-                                if let Some(res) = has_ref_res.get_result(this_ref.other.index) {
+                                if let Some(res) = has_ref_res.get_result(&this_ref.other.index) {
                                     let copy_ref = GeometryId {
                                         id: *ref_copy_id,
-                                        index: this_ref.other.index,
+                                        index: this_ref.other.index.clone(),
                                     };
-                                    refs_to_set.push((this_ref.owner.index, res, copy_ref));
+                                    refs_to_set.push((this_ref.owner.index.clone(), res, copy_ref));
                                 }
                             }
                         }
@@ -67,7 +67,7 @@ pub fn copy_objs(file: &PathBuf, event: &UndoEventID, ids: HashSet<RefID>) -> Re
                 app_state::modify_obj(&file, &event, copy_id, |obj| {
                     if let Some(has_ref) = obj.query_mut::<dyn UpdateFromRefs>() {
                         for (index, res, ref_to_set) in &refs_to_set {
-                            has_ref.set_ref(*index, res, ref_to_set.clone(), &None);
+                            has_ref.set_ref(index, res, ref_to_set.clone(), &None);
                         }
                     }
                     Ok(())
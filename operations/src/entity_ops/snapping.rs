@@ -1,6 +1,7 @@
 use crate::prelude::*;
+use serde::{Deserialize, Serialize};
 
-fn get_result(file: &PathBuf, obj: &RefID, index: ResultInd) -> Result<Option<RefGeometry>, DBError> {
+fn get_result(file: &PathBuf, obj: &RefID, index: &ResultInd) -> Result<Option<RefGeometry>, DBError> {
     let mut res_opt = None;
     app_state::get_obj(file, obj, |read| match read.query_ref::<dyn ReferTo>() {
         Some(refer) => {
@@ -18,9 +19,8 @@ pub fn get_closest_result(file: &PathBuf, obj: &RefID, only_match: &RefType, gue
         Some(joinable) => {
             let results = joinable.get_all_results();
             let mut dist = std::f64::MAX;
-            let mut index = 0;
-            for ref_res in results {
-                if only_match.type_equals(&ref_res) {
+            for (index, ref_res) in results {
+                if only_match.type_equals(&ref_res) && !joinable.is_derived_result(&index) {
                     let cur_dist = ref_res.distance2(&guess);
                     if cur_dist < dist {
                         let which = GeometryId { id: *obj, index };
@@ -28,7 +28,6 @@ pub fn get_closest_result(file: &PathBuf, obj: &RefID, only_match: &RefType, gue
                         dist = cur_dist;
                     }
                 }
-                index += 1;
             }
             Ok(())
         }
@@ -44,7 +43,7 @@ fn get_closest_ref(file: &PathBuf, obj: &RefID, only_match: &RefType, guess: &Po
             let indices = joinable.get_available_refs();
             let mut dist = std::f64::MAX;
             for index in indices {
-                if let Some(ref_geom) = joinable.get_associated_geom(index) {
+                if let Some(ref_geom) = joinable.get_associated_geom(&index) {
                     if only_match.type_equals(&ref_geom) {
                         let cur_dist = ref_geom.distance2(guess);
                         if cur_dist < dist {
@@ -82,6 +81,32 @@ pub fn snap_to_ref(
     }
 }
 
+///Points one of the object's references at a given result, like the one a snap found, even if it's derived.
+pub fn set_ref(
+    file: &PathBuf,
+    event: &UndoEventID,
+    obj: &RefID,
+    index: ReferInd,
+    refer: GeometryId,
+    snap_pt: &Option<Point3f>,
+) -> Result<(), DBError> {
+    let mut available = false;
+    app_state::get_obj(file, obj, |owner| match owner.query_ref::<dyn UpdateFromRefs>() {
+        Some(joinable) => {
+            available = joinable.get_available_refs().contains(&index);
+            Ok(())
+        }
+        None => Err(DBError::ObjLacksTrait),
+    })?;
+    if !available {
+        return Err(DBError::NotFound(format!("{:?} has no reference {}", obj, index)));
+    }
+    match get_result(file, &refer.id, &refer.index)? {
+        Some(result) => app_state::set_ref(file, event, obj, index, &result, refer, snap_pt),
+        None => Err(DBError::NotFound(format!("{:?} has no result {}", refer.id, refer.index))),
+    }
+}
+
 pub fn join_refs(
     file: &PathBuf,
     event: &UndoEventID,
@@ -95,3 +120,124 @@ pub fn join_refs(
     snap_to_ref(file, event, first, second, first_wants, guess)?;
     Ok(())
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SnapResult {
+    pub snap_type: SnapType,
+    pub pt: Point3f,
+    ///The result to reference if something gets attached here, by passing it to `set_ref`.  Grid snaps don't have one.
+    pub refer: Option<GeometryId>,
+    pub geom: Option<RefGeometry>,
+    ///For intersections, the other line, which can be referenced instead.
+    pub other_refer: Option<GeometryId>,
+    pub other_geom: Option<RefGeometry>,
+}
+
+fn candidate(snap_type: SnapType, pt: Point3f, which: &GeometryId, geom: &RefGeometry) -> SnapResult {
+    SnapResult {
+        snap_type,
+        pt,
+        refer: Some(which.clone()),
+        geom: Some(geom.clone()),
+        other_refer: None,
+        other_geom: None,
+    }
+}
+
+fn get_candidates(file: &PathBuf, guess: &Point3f, tolerance: WorldCoord, from: &Option<Point3f>) -> Result<Vec<SnapResult>, DBError> {
+    let mut candidates = Vec::new();
+    //Points come first, so they win ties with the same spot found on a line
+    let mut on_lines = Vec::new();
+    let mut lines = Vec::new();
+    for id in app_state::get_objs_near(file, guess, tolerance)? {
+        let res = app_state::get_obj(file, &id, |obj| {
+            if let Some(refer) = obj.query_ref::<dyn ReferTo>() {
+                for (index, geom) in refer.get_all_results() {
+                    let derived = refer.is_derived_result(&index);
+                    let snap_type = refer.snap_type(&index, &geom);
+                    let which = GeometryId { id, index };
+                    match geom {
+                        RefGeometry::Point { pt } => candidates.push(candidate(snap_type, pt, &which, &geom)),
+                        RefGeometry::Line { pt_1, pt_2 } => {
+                            on_lines.push(candidate(snap_type, project_on_segment(&pt_1, &pt_2, guess), &which, &geom));
+                            if let Some(from_pt) = from {
+                                let foot = project_on_line(&pt_1, &pt_2, from_pt);
+                                if foot.distance2(project_on_segment(&pt_1, &pt_2, &foot)) < std::f64::EPSILON {
+                                    on_lines.push(candidate(SnapType::Perpendicular, foot, &which, &geom));
+                                }
+                            }
+                            //Derived lines, like wall faces, run alongside the main one, so their midpoints and crossings
+                            //would crowd out the ones that matter
+                            if !derived {
+                                on_lines.push(candidate(SnapType::Midpoint, pt_1.midpoint(pt_2), &which, &geom));
+                                lines.push((which, geom, pt_1, pt_2));
+                            }
+                        }
+                        RefGeometry::Rect { .. } => (),
+                    }
+                }
+            }
+            Ok(())
+        });
+        match res {
+            Ok(()) | Err(DBError::ObjNotFound) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    for (i, (which, geom, pt_1, pt_2)) in lines.iter().enumerate() {
+        for (other_which, other_geom, other_1, other_2) in lines.iter().skip(i + 1) {
+            if which.id != other_which.id {
+                if let Some(pt) = intersect_segments_2d(pt_1, pt_2, other_1, other_2) {
+                    candidates.push(SnapResult {
+                        other_refer: Some(other_which.clone()),
+                        other_geom: Some(other_geom.clone()),
+                        ..candidate(SnapType::Intersection, pt, which, geom)
+                    });
+                }
+            }
+        }
+    }
+    candidates.extend(on_lines);
+    Ok(candidates)
+}
+
+///Finds the best point to snap to within tolerance of the guess, searching every object nearby.
+///from is the point the user started drawing from, if any, and is used for perpendicular snaps.
+///grid is the grid spacing, if grid snapping is on.
+pub fn snap_point(
+    file: &PathBuf,
+    guess: &Point3f,
+    tolerance: WorldCoord,
+    from: &Option<Point3f>,
+    grid: &Option<WorldCoord>,
+) -> Result<Option<SnapResult>, DBError> {
+    let mut candidates = get_candidates(file, guess, tolerance, from)?;
+    if let Some(spacing) = grid {
+        if *spacing > 0.0 {
+            candidates.push(SnapResult {
+                snap_type: SnapType::Grid,
+                pt: Point3f::new((guess.x / spacing).round() * spacing, (guess.y / spacing).round() * spacing, guess.z),
+                refer: None,
+                geom: None,
+                other_refer: None,
+                other_geom: None,
+            });
+        }
+    }
+    let tol2 = tolerance * tolerance;
+    let mut best: Option<(SnapResult, WorldCoord)> = None;
+    for candidate in candidates {
+        let dist = candidate.pt.distance2(*guess);
+        if dist > tol2 {
+            continue;
+        }
+        let better = match &best {
+            Some((cur, cur_dist)) => candidate.snap_type < cur.snap_type || (candidate.snap_type == cur.snap_type && dist < *cur_dist),
+            None => true,
+        };
+        if better {
+            best = Some((candidate, dist));
+        }
+    }
+    Ok(best.map(|(result, _)| result))
+}
@@ -8,7 +8,10 @@ fn test_setup(desc: &str, callback: impl Fn(PathBuf, UserID, Receiver<UpdateMsg>
     let (s, r) = crossbeam_channel::unbounded();
     let user = UserID::new_v4();
     app_state::init_file(file.clone(), user, s);
-    callback(file, user, r);
+    callback(file.clone(), user, r);
+    //Closing saves the file and removes its journal
+    app_state::close_file(file.clone(), user);
+    std::fs::remove_file(file).unwrap();
 }
 
 //This makes sure that all the background updates have completed
@@ -27,11 +30,14 @@ fn test_copy_objs() {
         let mut second = Box::new(TestObj::new("second"));
         let id_2 = second.get_id().clone();
         second.set_ref(
-            0,
+            &TestObj::FIRST,
             &RefGeometry::Point {
                 pt: Point3f::new(1.0, 2.0, 3.0),
             },
-            GeometryId { id: id_1.clone(), index: 0 },
+            GeometryId {
+                id: id_1.clone(),
+                index: TestObj::FIRST,
+            },
             &None,
         );
 
@@ -54,7 +60,7 @@ fn test_copy_objs() {
         app_state::get_obj(&file, &copy_id_1, |obj| {
             let point_ref = obj.query_ref::<dyn ReferTo>().unwrap();
             assert_eq!(
-                point_ref.get_result(0),
+                point_ref.get_result(&TestObj::FIRST),
                 Some(RefGeometry::Point {
                     pt: Point3f::new(1.0, 2.0, 4.0)
                 })
@@ -65,7 +71,7 @@ fn test_copy_objs() {
         app_state::get_obj(&file, &copy_id_2, |obj| {
             let point_ref = obj.query_ref::<dyn ReferTo>().unwrap();
             assert_eq!(
-                point_ref.get_result(0),
+                point_ref.get_result(&TestObj::FIRST),
                 Some(RefGeometry::Point {
                     pt: Point3f::new(1.0, 2.0, 4.0)
                 })
@@ -101,21 +107,28 @@ fn test_join_walls() {
             &Point3f::new(2.0, 4.0, 3.0),
         )
         .unwrap();
-        empty_receiver(&rcv);
+        //Joined walls refer to each other, which clients are told about
+        let cycles = rcv
+            .try_iter()
+            .filter(|msg| match msg.clone().unsequenced() {
+                UpdateMsg::DepCycle { .. } => true,
+                _ => false,
+            })
+            .count();
+        assert!(cycles > 0);
         crate::move_obj(file.clone(), &event, id_1.clone(), &Vector3f::new(0.0, 1.0, 0.0)).unwrap();
         app_state::end_undo_event(&file, event).unwrap();
         empty_receiver(&rcv);
         app_state::get_obj(&file, &id_1, |first| {
             let read = first.query_ref::<dyn ReferTo>().unwrap();
-            let pts = read.get_all_results();
             assert_eq!(
-                pts[0],
+                read.get_result(&Wall::FIRST).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(1.0, 3.0, 3.0)
                 }
             );
             assert_eq!(
-                pts[1],
+                read.get_result(&Wall::SECOND).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(2.0, 3.0, 3.0)
                 }
@@ -125,15 +138,14 @@ fn test_join_walls() {
         .unwrap();
         app_state::get_obj(&file, &id_2, |second| {
             let read = second.query_ref::<dyn ReferTo>().unwrap();
-            let pts = read.get_all_results();
             assert_eq!(
-                pts[0],
+                read.get_result(&Wall::FIRST).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(2.0, 3.0, 3.0)
                 }
             );
             assert_eq!(
-                pts[1],
+                read.get_result(&Wall::SECOND).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(4.0, 5.0, 6.0)
                 }
@@ -174,15 +186,14 @@ fn test_join_door_and_wall() {
         empty_receiver(&rcv);
         app_state::get_obj(&file, &id_2, |second| {
             let read = second.query_ref::<dyn ReferTo>().unwrap();
-            let pts = read.get_all_results();
             assert_eq!(
-                pts[0],
+                read.get_result(&Door::FIRST).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(0.25, 0.0, 0.0)
                 }
             );
             assert_eq!(
-                pts[1],
+                read.get_result(&Door::SECOND).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(0.75, 0.0, 0.0)
                 }
@@ -197,19 +208,25 @@ fn test_join_door_and_wall() {
         empty_receiver(&rcv);
         app_state::get_obj(&file, &id_2, |second| {
             let read = second.query_ref::<dyn ReferTo>().unwrap();
-            let pts = read.get_all_results();
             assert_eq!(
-                pts[0],
+                read.get_result(&Door::FIRST).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(0.25, 1.0, 0.0)
                 }
             );
             assert_eq!(
-                pts[1],
+                read.get_result(&Door::SECOND).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(0.75, 1.0, 0.0)
                 }
             );
+            //The door's own results are driven by its line, which has to be a result for them to subscribe to
+            let results: Vec<ResultInd> = read.get_all_results().into_iter().map(|(index, _)| index).collect();
+            for refer in second.query_ref::<dyn UpdateFromRefs>().unwrap().get_refs().into_iter().flatten() {
+                if refer.other.id == id_2 {
+                    assert!(results.contains(&refer.other.index), "{:?}", refer);
+                }
+            }
             Ok(())
         })
         .unwrap();
@@ -311,21 +328,20 @@ fn test_walls_and_doors() {
         empty_receiver(&rcv);
         app_state::get_obj(&file, &wall_2_id, |second| {
             let read = second.query_ref::<dyn ReferTo>().unwrap();
-            let pts = read.get_all_results();
             assert_eq!(
-                pts[0],
+                read.get_result(&Wall::FIRST).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(2.0, 0.0, 0.0)
                 }
             );
             assert_eq!(
-                pts[1],
+                read.get_result(&Wall::SECOND).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(2.0, 1.0, 0.0)
                 }
             );
             assert_eq!(
-                pts[3],
+                read.get_result(&RefIndex::Sub(0)).unwrap(),
                 RefGeometry::Rect {
                     pt_1: Point3f::new(2.0, 0.25, 0.0),
                     pt_2: Point3f::new(2.0, 0.75, 0.0),
@@ -337,15 +353,14 @@ fn test_walls_and_doors() {
         .unwrap();
         app_state::get_obj(&file, &door_2_id, |second| {
             let read = second.query_ref::<dyn ReferTo>().unwrap();
-            let pts = read.get_all_results();
             assert_eq!(
-                pts[0],
+                read.get_result(&Door::FIRST).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(2.0, 0.25, 0.0)
                 }
             );
             assert_eq!(
-                pts[1],
+                read.get_result(&Door::SECOND).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(2.0, 0.75, 0.0)
                 }
@@ -355,15 +370,14 @@ fn test_walls_and_doors() {
         .unwrap();
         app_state::get_obj(&file, &door_1_id, |wall| {
             let read = wall.query_ref::<dyn ReferTo>().unwrap();
-            let pts = read.get_all_results();
             assert_eq!(
-                pts[0],
+                read.get_result(&Door::FIRST).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(0.5, 0.0, 0.0)
                 }
             );
             assert_eq!(
-                pts[1],
+                read.get_result(&Door::SECOND).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(1.0, 0.0, 0.0)
                 }
@@ -373,15 +387,14 @@ fn test_walls_and_doors() {
         .unwrap();
         app_state::get_obj(&file, &door_3_id, |wall| {
             let read = wall.query_ref::<dyn ReferTo>().unwrap();
-            let pts = read.get_all_results();
             assert_eq!(
-                pts[0],
+                read.get_result(&Door::FIRST).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(1.5, 1.0, 0.0)
                 }
             );
             assert_eq!(
-                pts[1],
+                read.get_result(&Door::SECOND).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(1.0, 1.0, 0.0)
                 }
@@ -391,21 +404,20 @@ fn test_walls_and_doors() {
         .unwrap();
         app_state::get_obj(&file, &wall_1_id, |wall| {
             let read = wall.query_ref::<dyn ReferTo>().unwrap();
-            let pts = read.get_all_results();
             assert_eq!(
-                pts[0],
+                read.get_result(&Wall::FIRST).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(0.0, 0.0, 0.0)
                 }
             );
             assert_eq!(
-                pts[1],
+                read.get_result(&Wall::SECOND).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(2.0, 0.0, 0.0)
                 }
             );
             assert_eq!(
-                pts[3],
+                read.get_result(&RefIndex::Sub(0)).unwrap(),
                 RefGeometry::Rect {
                     pt_1: Point3f::new(0.5, 0.0, 0.0),
                     pt_2: Point3f::new(1.0, 0.0, 0.0),
@@ -417,21 +429,20 @@ fn test_walls_and_doors() {
         .unwrap();
         app_state::get_obj(&file, &wall_3_id, |wall| {
             let read = wall.query_ref::<dyn ReferTo>().unwrap();
-            let pts = read.get_all_results();
             assert_eq!(
-                pts[0],
+                read.get_result(&Wall::FIRST).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(2.0, 1.0, 0.0)
                 }
             );
             assert_eq!(
-                pts[1],
+                read.get_result(&Wall::SECOND).unwrap(),
                 RefGeometry::Point {
                     pt: Point3f::new(0.0, 1.0, 0.0)
                 }
             );
             assert_eq!(
-                pts[3],
+                read.get_result(&RefIndex::Sub(0)).unwrap(),
                 RefGeometry::Rect {
                     pt_1: Point3f::new(1.5, 1.0, 0.0),
                     pt_2: Point3f::new(1.0, 1.0, 0.0),
@@ -443,3 +454,459 @@ fn test_walls_and_doors() {
         .unwrap();
     });
 }
+
+#[test]
+fn test_remove_opening() {
+    test_setup("remove opening", |file, user, rcv| {
+        let wall = Box::new(Wall::new(Point3f::new(0.0, 0.0, 0.0), Point3f::new(4.0, 0.0, 0.0), 0.5, 1.0));
+        let wall_id = wall.get_id().clone();
+        let door_1 = Box::new(Door::new(Point3f::new(0.5, 0.0, 0.0), Point3f::new(1.0, 0.0, 0.0), 0.25, 0.75));
+        let door_1_id = door_1.get_id().clone();
+        let door_2 = Box::new(Door::new(Point3f::new(2.0, 0.0, 0.0), Point3f::new(3.0, 0.0, 0.0), 0.25, 0.75));
+        let door_2_id = door_2.get_id().clone();
+        let event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &user, event.clone(), String::from("add objs")).unwrap();
+        app_state::add_obj(&file, &event, wall).unwrap();
+        app_state::add_obj(&file, &event, door_1).unwrap();
+        app_state::add_obj(&file, &event, door_2).unwrap();
+        join_refs(
+            &file,
+            &event,
+            &door_1_id,
+            &wall_id,
+            &RefType::Line,
+            &RefType::Rect,
+            &Point3f::new(0.5, 0.0, 0.0),
+        )
+        .unwrap();
+        join_refs(
+            &file,
+            &event,
+            &door_2_id,
+            &wall_id,
+            &RefType::Line,
+            &RefType::Rect,
+            &Point3f::new(2.0, 0.0, 0.0),
+        )
+        .unwrap();
+        app_state::end_undo_event(&file, event).unwrap();
+        empty_receiver(&rcv);
+
+        let event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &user, event.clone(), String::from("delete opening")).unwrap();
+        app_state::modify_obj(&file, &event, &wall_id, |wall| {
+            wall.query_mut::<dyn UpdateFromRefs>().unwrap().delete_ref(&RefIndex::Sub(0));
+            Ok(())
+        })
+        .unwrap();
+        app_state::end_undo_event(&file, event).unwrap();
+
+        let event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &user, event.clone(), String::from("move wall")).unwrap();
+        crate::move_obj(file.clone(), &event, wall_id.clone(), &Vector3f::new(0.0, 1.0, 0.0)).unwrap();
+        app_state::end_undo_event(&file, event).unwrap();
+        empty_receiver(&rcv);
+        app_state::get_obj(&file, &wall_id, |wall| {
+            let read = wall.query_ref::<dyn ReferTo>().unwrap();
+            assert_eq!(read.get_result(&RefIndex::Sub(0)), None);
+            assert_eq!(
+                read.get_result(&RefIndex::Sub(1)),
+                Some(RefGeometry::Rect {
+                    pt_1: Point3f::new(2.0, 1.0, 0.0),
+                    pt_2: Point3f::new(3.0, 1.0, 0.0),
+                    pt_3: Point3f::new(3.0, 1.0, 0.75)
+                })
+            );
+            Ok(())
+        })
+        .unwrap();
+    });
+}
+
+#[test]
+fn test_dimension_to_wall_faces() {
+    test_setup("dimension to wall faces", |file, user, rcv| {
+        let wall = Box::new(Wall::new(Point3f::new(0.0, 0.0, 0.0), Point3f::new(10.0, 0.0, 0.0), 1.0, 1.0));
+        let wall_id = wall.get_id().clone();
+        let dim = Box::new(Dimension::new(Point3f::new(5.0, -1.0, 0.0), Point3f::new(10.0, 1.0, 0.0), 1.0));
+        let dim_id = dim.get_id().clone();
+        let event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &user, event.clone(), String::from("add objs")).unwrap();
+        app_state::add_obj(&file, &event, wall).unwrap();
+        app_state::add_obj(&file, &event, dim).unwrap();
+        app_state::end_undo_event(&file, event).unwrap();
+
+        let mut face = None;
+        let mut corner = None;
+        app_state::get_obj(&file, &wall_id, |wall| {
+            let read = wall.query_ref::<dyn ReferTo>().unwrap();
+            face = read.get_result(&Wall::RIGHT_FACE);
+            corner = read.get_result(&Wall::SECOND_LEFT);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            face,
+            Some(RefGeometry::Line {
+                pt_1: Point3f::new(0.0, -1.0, 0.0),
+                pt_2: Point3f::new(10.0, -1.0, 0.0)
+            })
+        );
+        assert_eq!(
+            corner,
+            Some(RefGeometry::Point {
+                pt: Point3f::new(10.0, 1.0, 0.0)
+            })
+        );
+
+        let event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &user, event.clone(), String::from("snap dimension")).unwrap();
+        let snap = Some(Point3f::new(5.0, -1.0, 0.0));
+        let face_id = GeometryId::new(wall_id, Wall::RIGHT_FACE);
+        let corner_id = GeometryId::new(wall_id, Wall::SECOND_LEFT);
+        //Derived results are never picked by the closest result search, so they're attached by id
+        crate::set_ref(file.clone(), &event, dim_id, Dimension::FIRST, face_id, &snap).unwrap();
+        crate::set_ref(file.clone(), &event, dim_id, Dimension::SECOND, corner_id.clone(), &None).unwrap();
+        match crate::set_ref(file.clone(), &event, dim_id, RefIndex::named("Third"), corner_id, &None) {
+            Err(DBError::NotFound(_)) => (),
+            other => panic!("Expected a missing reference, got {:?}", other),
+        }
+        let missing = GeometryId::new(wall_id, RefIndex::named("Nowhere"));
+        match crate::set_ref(file.clone(), &event, dim_id, Dimension::FIRST, missing, &None) {
+            Err(DBError::NotFound(_)) => (),
+            other => panic!("Expected a missing result, got {:?}", other),
+        }
+        crate::set_obj_data(file.clone(), &event, wall_id.clone(), json!({"Width": 2.0})).unwrap();
+        app_state::end_undo_event(&file, event).unwrap();
+        empty_receiver(&rcv);
+        app_state::get_obj(&file, &dim_id, |dim| {
+            let read = dim.query_ref::<dyn UpdateFromRefs>().unwrap();
+            assert_eq!(
+                read.get_associated_geom(&Dimension::FIRST),
+                Some(RefGeometry::Point {
+                    pt: Point3f::new(5.0, -2.0, 0.0)
+                })
+            );
+            assert_eq!(
+                read.get_associated_geom(&Dimension::SECOND),
+                Some(RefGeometry::Point {
+                    pt: Point3f::new(10.0, 2.0, 0.0)
+                })
+            );
+            Ok(())
+        })
+        .unwrap();
+
+        let event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &user, event.clone(), String::from("move wall")).unwrap();
+        crate::move_obj(file.clone(), &event, wall_id.clone(), &Vector3f::new(0.0, 1.0, 0.0)).unwrap();
+        app_state::end_undo_event(&file, event).unwrap();
+        empty_receiver(&rcv);
+        app_state::get_obj(&file, &dim_id, |dim| {
+            let read = dim.query_ref::<dyn UpdateFromRefs>().unwrap();
+            assert_eq!(
+                read.get_associated_geom(&Dimension::FIRST),
+                Some(RefGeometry::Point {
+                    pt: Point3f::new(5.0, -1.0, 0.0)
+                })
+            );
+            assert_eq!(
+                read.get_associated_geom(&Dimension::SECOND),
+                Some(RefGeometry::Point {
+                    pt: Point3f::new(10.0, 3.0, 0.0)
+                })
+            );
+            Ok(())
+        })
+        .unwrap();
+    });
+}
+
+#[test]
+fn test_snap_point() {
+    test_setup("snap point", |file, user, rcv| {
+        let first = Box::new(Wall::new(Point3f::new(0.0, 0.0, 0.0), Point3f::new(10.0, 0.0, 0.0), 1.0, 1.0));
+        let id_1 = first.get_id().clone();
+        let second = Box::new(Wall::new(Point3f::new(8.0, -5.0, 0.0), Point3f::new(8.0, 5.0, 0.0), 1.0, 1.0));
+        let id_2 = second.get_id().clone();
+        let event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &user, event.clone(), String::from("add objs")).unwrap();
+        crate::add_obj(&file, &event, first).unwrap();
+        crate::add_obj(&file, &event, second).unwrap();
+        app_state::end_undo_event(&file, event).unwrap();
+        empty_receiver(&rcv);
+
+        let snap = snap_point(&file, &Point3f::new(0.3, 0.2, 0.0), 1.0, &None, &None).unwrap().unwrap();
+        assert_eq!(snap.snap_type, SnapType::Endpoint);
+        assert_eq!(snap.pt, Point3f::new(0.0, 0.0, 0.0));
+        assert_eq!(snap.refer, Some(GeometryId::new(id_1, Wall::FIRST)));
+
+        //Walls expose their midpoint as a result, which is a better reference than the middle of the center line
+        let snap = snap_point(&file, &Point3f::new(5.2, 0.3, 0.0), 1.0, &None, &None).unwrap().unwrap();
+        assert_eq!(snap.snap_type, SnapType::Midpoint);
+        assert_eq!(snap.pt, Point3f::new(5.0, 0.0, 0.0));
+        assert_eq!(snap.refer, Some(GeometryId::new(id_1, Wall::MIDPOINT)));
+
+        let snap = snap_point(&file, &Point3f::new(8.2, 0.3, 0.0), 1.0, &None, &None).unwrap().unwrap();
+        assert_eq!(snap.snap_type, SnapType::Intersection);
+        assert_eq!(snap.pt, Point3f::new(8.0, 0.0, 0.0));
+        let mut ids = vec![snap.refer.unwrap().id, snap.other_refer.unwrap().id];
+        ids.sort();
+        let mut expected = vec![id_1, id_2];
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        let snap = snap_point(&file, &Point3f::new(8.1, 3.0, 0.0), 0.5, &None, &None).unwrap().unwrap();
+        assert_eq!(snap.snap_type, SnapType::Nearest);
+        assert_eq!(snap.pt, Point3f::new(8.0, 3.0, 0.0));
+        assert_eq!(snap.refer, Some(GeometryId::new(id_2, Wall::CENTER)));
+
+        let from = Some(Point3f::new(2.0, 3.0, 0.0));
+        let snap = snap_point(&file, &Point3f::new(8.2, 3.3, 0.0), 0.5, &from, &None).unwrap().unwrap();
+        assert_eq!(snap.snap_type, SnapType::Perpendicular);
+        assert_eq!(snap.pt, Point3f::new(8.0, 3.0, 0.0));
+
+        let snap = snap_point(&file, &Point3f::new(10.2, 0.9, 0.0), 0.5, &None, &None).unwrap().unwrap();
+        assert_eq!(snap.snap_type, SnapType::Corner);
+        assert_eq!(snap.pt, Point3f::new(10.0, 1.0, 0.0));
+
+        let snap = snap_point(&file, &Point3f::new(2.9, 3.9, 0.0), 0.5, &None, &Some(1.0)).unwrap().unwrap();
+        assert_eq!(snap.snap_type, SnapType::Grid);
+        assert_eq!(snap.pt, Point3f::new(3.0, 4.0, 0.0));
+        assert_eq!(snap.refer, None);
+
+        assert_eq!(snap_point(&file, &Point3f::new(50.0, 50.0, 0.0), 1.0, &None, &None).unwrap(), None);
+
+        //Whatever the snap found can be attached to, corners included
+        let corner = snap_point(&file, &Point3f::new(10.2, 0.9, 0.0), 0.5, &None, &None).unwrap().unwrap();
+        let dim = Box::new(Dimension::new(Point3f::new(20.0, 20.0, 0.0), Point3f::new(25.0, 20.0, 0.0), 1.0));
+        let dim_id = dim.get_id().clone();
+        let event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &user, event.clone(), String::from("attach to corner")).unwrap();
+        crate::add_obj(&file, &event, dim).unwrap();
+        crate::set_ref(file.clone(), &event, dim_id, Dimension::SECOND, corner.refer.unwrap(), &Some(corner.pt)).unwrap();
+        crate::move_obj(file.clone(), &event, id_1, &Vector3f::new(0.0, 2.0, 0.0)).unwrap();
+        app_state::end_undo_event(&file, event).unwrap();
+        empty_receiver(&rcv);
+        app_state::get_obj(&file, &dim_id, |dim| {
+            let read = dim.query_ref::<dyn UpdateFromRefs>().unwrap();
+            assert_eq!(
+                read.get_associated_geom(&Dimension::SECOND),
+                Some(RefGeometry::Point {
+                    pt: Point3f::new(10.0, 3.0, 0.0)
+                })
+            );
+            Ok(())
+        })
+        .unwrap();
+    });
+}
+
+#[test]
+fn test_snap_types() {
+    let wall = Wall::new(Point3f::new(0.0, 0.0, 0.0), Point3f::new(10.0, 0.0, 0.0), 1.0, 1.0);
+    let types: HashMap<ResultInd, SnapType> = wall
+        .get_all_results()
+        .into_iter()
+        .map(|(index, geom)| (index.clone(), wall.snap_type(&index, &geom)))
+        .collect();
+    assert_eq!(types[&Wall::FIRST], SnapType::Endpoint);
+    assert_eq!(types[&Wall::CENTER], SnapType::Nearest);
+    assert_eq!(types[&Wall::MIDPOINT], SnapType::Midpoint);
+    assert_eq!(types[&Wall::SECOND_LEFT], SnapType::Corner);
+    assert_eq!(types[&Wall::RIGHT_FACE], SnapType::Nearest);
+
+    //Other entities decide for themselves, whatever their results are called
+    let door = Door::new(Point3f::new(0.0, 0.0, 0.0), Point3f::new(1.0, 0.0, 0.0), 1.0, 1.0);
+    for (index, geom) in door.get_all_results() {
+        assert_eq!(door.snap_type(&index, &geom), SnapType::for_geometry(&geom));
+    }
+}
+
+#[test]
+fn test_autosave() {
+    test_setup("autosave", |file, user, rcv| {
+        let get_saves = || {
+            rcv.try_iter()
+                .filter_map(|msg| match msg.unsequenced() {
+                    UpdateMsg::Saved { autosave } => Some(autosave),
+                    _ => None,
+                })
+                .collect::<Vec<bool>>()
+        };
+        let minute = std::time::Duration::from_secs(60);
+        assert!(app_state::set_autosave(&file, Some(std::time::Duration::from_secs(0))).is_err());
+        app_state::set_autosave(&file, Some(minute)).unwrap();
+        let start = std::time::Instant::now();
+        //Nothing has changed yet, so there's nothing to save
+        app_state::autosave_tick(start + minute);
+        assert!(get_saves().is_empty());
+        assert!(!file.exists());
+
+        let event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &user, event.clone(), String::from("add obj")).unwrap();
+        app_state::add_obj(&file, &event, Box::new(TestObj::new("first"))).unwrap();
+        app_state::end_undo_event(&file, event).unwrap();
+        app_state::autosave_tick(start + minute + minute / 2);
+        assert!(get_saves().is_empty());
+        app_state::autosave_tick(start + minute * 2);
+        assert_eq!(get_saves(), vec![true]);
+        assert!(file.exists());
+        app_state::autosave_tick(start + minute * 3);
+        assert!(get_saves().is_empty());
+
+        app_state::set_autosave(&file, None).unwrap();
+        let event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &user, event.clone(), String::from("add obj")).unwrap();
+        app_state::add_obj(&file, &event, Box::new(TestObj::new("second"))).unwrap();
+        app_state::end_undo_event(&file, event).unwrap();
+        app_state::autosave_tick(start + minute * 4);
+        assert!(get_saves().is_empty());
+    });
+}
+
+#[test]
+fn test_close_one_of_two_files() {
+    test_setup("close_first", |first, _, _| {
+        test_setup("close_second", |second, user, _| {
+            let other = UserID::new_v4();
+            let (s_1, r_1) = crossbeam_channel::unbounded();
+            let (s_2, r_2) = crossbeam_channel::unbounded();
+            app_state::init_file(first.clone(), other, s_1);
+            app_state::init_file(second.clone(), other, s_2);
+            let (queue_1, queue_2) = (crate::UpdateQueue::new(r_1, false), crate::UpdateQueue::new(r_2, false));
+
+            //What the server does when the user loses access to the first file
+            let error = UpdateMsg::Error {
+                request_id: None,
+                error: DBError::Forbidden(String::from("No access")),
+            };
+            app_state::send_reply(&first, &other, error.clone()).unwrap();
+            app_state::close_file(first.clone(), other);
+            assert_eq!(queue_1.drain().last(), Some(&error));
+            assert!(queue_1.is_closed());
+
+            //The second file still sends them updates
+            let obj = Box::new(TestObj::new("second"));
+            let id = obj.get_id().clone();
+            let event = UndoEventID::new_v4();
+            app_state::begin_undo_event(&second, &user, event.clone(), String::from("add obj")).unwrap();
+            crate::add_obj(&second, &event, obj).unwrap();
+            app_state::end_undo_event(&second, event).unwrap();
+            let msg = queue_2.wait().unwrap().into_iter().find(|msg| msg.get_key() == Some(id));
+            assert!(msg.is_some());
+            assert!(!queue_2.is_closed());
+            app_state::close_file(second.clone(), other);
+        });
+    });
+}
+
+#[test]
+fn test_atomic_event() {
+    test_setup("atomic_event", |file, user, rcv| {
+        let first = Box::new(TestObj::new("first"));
+        let id_1 = first.get_id().clone();
+        let event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &user, event.clone(), String::from("add first")).unwrap();
+        crate::add_obj(&file, &event, first).unwrap();
+        assert_eq!(crate::delete_obj(&file, &event, &RefID::new_v4()), Err(DBError::ObjNotFound));
+        //Without being atomic, the event stays open and keeps what was done
+        app_state::end_undo_event(&file, event).unwrap();
+        app_state::get_obj(&file, &id_1, |_| Ok(())).unwrap();
+
+        let mut before = json!(null);
+        app_state::get_obj(&file, &id_1, |obj| {
+            before = json!(obj);
+            Ok(())
+        })
+        .unwrap();
+        let second = Box::new(TestObj::new("second"));
+        let id_2 = second.get_id().clone();
+        let event = UndoEventID::new_v4();
+        app_state::begin_atomic_event(&file, &user, event.clone(), String::from("add second")).unwrap();
+        crate::add_obj(&file, &event, second).unwrap();
+        crate::move_obj(file.clone(), &event, id_1.clone(), &Vector3f::new(1.0, 0.0, 0.0)).unwrap();
+        assert_eq!(crate::delete_obj(&file, &event, &RefID::new_v4()), Err(DBError::ObjNotFound));
+        empty_receiver(&rcv);
+        assert_eq!(app_state::get_obj(&file, &id_2, |_| Ok(())), Err(DBError::ObjNotFound));
+        let mut after = json!(null);
+        app_state::get_obj(&file, &id_1, |obj| {
+            after = json!(obj);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(before, after);
+        assert_eq!(app_state::end_undo_event(&file, event), Err(DBError::NoUndoEvent));
+    });
+}
+
+#[test]
+fn test_merge_props() {
+    test_setup("merge_props", |file, user, rcv| {
+        let other_user = UserID::new_v4();
+        let wall = Box::new(Wall::new(Point3f::new(0.0, 0.0, 0.0), Point3f::new(1.0, 0.0, 0.0), 1.0, 1.0));
+        let id = wall.get_id().clone();
+        let event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &user, event.clone(), String::from("add wall")).unwrap();
+        app_state::add_obj(&file, &event, wall).unwrap();
+        app_state::end_undo_event(&file, event).unwrap();
+        let get_props = || {
+            let width = get_obj_data(&file, &id, "Width").unwrap();
+            let height = get_obj_data(&file, &id, "Height").unwrap();
+            (width, height)
+        };
+
+        //Both users edit the wall at the same time
+        let event = UndoEventID::new_v4();
+        let other_event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &user, event.clone(), String::from("set width")).unwrap();
+        app_state::begin_undo_event(&file, &other_user, other_event.clone(), String::from("set height")).unwrap();
+        set_obj_data(&file, &event, &id, json!({"Width": 2.0})).unwrap();
+        set_obj_data(&file, &other_event, &id, json!({"Height": 3.0})).unwrap();
+        //Each user only has the property they set locked
+        let mut locks: Vec<(Option<String>, UserID)> = app_state::get_locks(&file)
+            .unwrap()
+            .into_iter()
+            .map(|lock| (lock.prop, lock.owner))
+            .collect();
+        locks.sort();
+        let mut expected = vec![(Some(String::from("Width")), user), (Some(String::from("Height")), other_user)];
+        expected.sort();
+        assert_eq!(locks, expected);
+        assert!(set_obj_data(&file, &other_event, &id, json!({"Width": 5.0})).is_err());
+        app_state::end_undo_event(&file, other_event).unwrap();
+        app_state::end_undo_event(&file, event).unwrap();
+        assert_eq!(get_props(), (json!(2.0), json!(3.0)));
+
+        //Undoing only puts back the user's own property
+        app_state::undo_latest(&file, &user).unwrap();
+        assert_eq!(get_props(), (json!(1.0), json!(3.0)));
+        app_state::redo_latest(&file, &user).unwrap();
+        assert_eq!(get_props(), (json!(2.0), json!(3.0)));
+
+        //A later change by someone else wins over undo
+        let other_event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &other_user, other_event.clone(), String::from("set width")).unwrap();
+        set_obj_data(&file, &other_event, &id, json!({"Width": 4.0})).unwrap();
+        app_state::end_undo_event(&file, other_event).unwrap();
+        app_state::undo_latest(&file, &user).unwrap();
+        assert_eq!(get_props(), (json!(4.0), json!(3.0)));
+        app_state::undo_latest(&file, &other_user).unwrap();
+        assert_eq!(get_props(), (json!(2.0), json!(3.0)));
+
+        //Edits that snapshot the whole object only undo the fields they changed
+        let start = get_obj_data(&file, &id, "First").unwrap();
+        let event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &user, event.clone(), String::from("move wall")).unwrap();
+        move_obj(&file, &event, &id, &Vector3f::new(0.0, 1.0, 0.0)).unwrap();
+        app_state::end_undo_event(&file, event).unwrap();
+        let other_event = UndoEventID::new_v4();
+        app_state::begin_undo_event(&file, &other_user, other_event.clone(), String::from("set height")).unwrap();
+        set_obj_data(&file, &other_event, &id, json!({"Height": 6.0})).unwrap();
+        app_state::end_undo_event(&file, other_event).unwrap();
+        app_state::undo_latest(&file, &user).unwrap();
+        assert_eq!(get_obj_data(&file, &id, "First").unwrap(), start);
+        assert_eq!(get_props(), (json!(2.0), json!(6.0)));
+        empty_receiver(&rcv);
+    });
+}
@@ -14,6 +14,7 @@ extern crate bincode;
 #[macro_use]
 extern crate log;
 extern crate indexmap;
+extern crate miniz_oxide;
 
 #[cfg(test)]
 mod tests;
@@ -21,6 +22,12 @@ mod tests;
 pub mod app_state;
 pub mod entity_ops;
 mod operation_manager;
+mod update_queue;
+
+pub use operation_manager::{
+    write_atomic, FileHeader, GraphFormat, HistoryEntry, LockInfo, OverflowPolicy, ProjectInfo, QueueStats, UndoLimits, Units, UserPresence,
+};
+pub use update_queue::UpdateQueue;
 
 mod prelude {
     pub use crate::app_state;
@@ -37,39 +44,48 @@ use prelude::*;
 type LibResult = Result<(), DBError>;
 
 pub use app_state::{
-    begin_undo_event, cancel_event, close_file, copy_obj, end_undo_event, init_file, redo_latest, resume_event, save_as_file, save_file,
-    suspend_event, take_undo_snapshot, undo_latest,
+    begin_atomic_event, begin_undo_event, cancel_event, close_file, end_undo_event, flush_updates, init_file, reconnect_file, redo_latest,
+    resume_event, revert_event, save_as_file, save_file, send_reply, set_autosave, set_backup_count, set_cursor, set_file_info, set_history_depth,
+    set_overflow_policy, set_selection, set_undo_limits, set_user_info, suspend_event, take_undo_snapshot, undo_latest, undo_to,
 };
 
+pub fn copy_obj(file: &PathBuf, event: &UndoEventID, obj_id: &RefID) -> Result<RefID, DBError> {
+    app_state::run_in_event(file, event, || app_state::copy_obj(file, event, obj_id))
+}
+
 pub fn get_obj(file: &PathBuf, obj_id: &RefID, query_id: QueryID, user_id: &UserID) -> LibResult {
     app_state::get_obj(file, obj_id, |obj| app_state::send_read_result(file, query_id, user_id, json!(obj)))
 }
 
 pub fn delete_obj(file: &PathBuf, event: &UndoEventID, obj_id: &RefID) -> LibResult {
-    app_state::delete_obj(file, event, obj_id)?;
-    app_state::update_deps(file.clone(), obj_id.clone());
-    Ok(())
+    app_state::run_in_event(file, event, || {
+        app_state::delete_obj(file, event, obj_id)?;
+        app_state::update_deps_in_event(file, event, vec![obj_id.clone()])
+    })
 }
 
 pub fn add_obj(file: &PathBuf, event: &UndoEventID, obj: DataObject) -> LibResult {
     let obj_id = obj.get_id().clone();
-    app_state::add_obj(file, event, obj)?;
-    app_state::update_deps(file.clone(), obj_id.clone());
-    Ok(())
+    app_state::run_in_event(file, event, || {
+        app_state::add_obj(file, event, obj)?;
+        app_state::update_deps_in_event(file, event, vec![obj_id])
+    })
 }
 
 pub fn move_obj(file: PathBuf, event: &UndoEventID, obj_id: RefID, delta: &Vector3f) -> LibResult {
-    entity_ops::move_obj(&file, event, &obj_id, delta)?;
-    app_state::update_deps(file, obj_id);
-    Ok(())
+    app_state::run_in_event(&file, event, || {
+        entity_ops::move_obj(&file, event, &obj_id, delta)?;
+        app_state::update_deps_in_event(&file, event, vec![obj_id])
+    })
 }
 
 pub fn move_objs(file: PathBuf, event: &UndoEventID, ids: HashSet<RefID>, delta: &Vector3f) -> LibResult {
-    for id in &ids {
-        entity_ops::move_obj(&file, event, id, delta)?;
-    }
-    app_state::update_all_deps(file, ids.into_iter().collect());
-    Ok(())
+    app_state::run_in_event(&file, event, || {
+        for id in &ids {
+            entity_ops::move_obj(&file, event, id, delta)?;
+        }
+        app_state::update_deps_in_event(&file, event, ids.into_iter().collect())
+    })
 }
 
 pub fn get_obj_data(file: &PathBuf, obj_id: &RefID, prop_name: &str, query_id: QueryID, user_id: &UserID) -> LibResult {
@@ -78,32 +94,44 @@ pub fn get_obj_data(file: &PathBuf, obj_id: &RefID, prop_name: &str, query_id: Q
 }
 
 pub fn set_obj_data(file: PathBuf, event: &UndoEventID, obj_id: RefID, data: serde_json::Value) -> LibResult {
-    entity_ops::set_obj_data(&file, event, &obj_id, data)?;
-    app_state::update_deps(file, obj_id);
-    Ok(())
+    app_state::run_in_event(&file, event, || {
+        entity_ops::set_obj_data(&file, event, &obj_id, data)?;
+        app_state::update_deps_in_event(&file, event, vec![obj_id])
+    })
 }
 
 pub fn set_objs_data(file: PathBuf, event: &UndoEventID, data: Vec<(RefID, serde_json::Value)>) -> LibResult {
-    let mut keys = HashSet::new();
-    for (id, val) in data {
-        entity_ops::set_obj_data(&file, event, &id, val)?;
-        keys.insert(id);
-    }
-    app_state::update_all_deps(file, keys.into_iter().collect());
-    Ok(())
+    app_state::run_in_event(&file, event, || {
+        let mut keys = HashSet::new();
+        for (id, val) in data {
+            entity_ops::set_obj_data(&file, event, &id, val)?;
+            keys.insert(id);
+        }
+        app_state::update_deps_in_event(&file, event, keys.into_iter().collect())
+    })
 }
 
 pub fn copy_objs(file: PathBuf, event: &UndoEventID, ids: HashSet<RefID>, query_id: QueryID, user_id: &UserID) -> LibResult {
-    let (to_update, copied) = entity_ops::copy_objs(&file, event, ids)?;
-    app_state::send_read_result(&file, query_id, user_id, json!(copied))?;
-    app_state::update_all_deps(file, to_update);
-    Ok(())
+    let copied = app_state::run_in_event(&file, event, || {
+        let (to_update, copied) = entity_ops::copy_objs(&file, event, ids)?;
+        app_state::update_deps_in_event(&file, event, to_update)?;
+        Ok(copied)
+    })?;
+    app_state::send_read_result(&file, query_id, user_id, json!(copied))
 }
 
 pub fn snap_obj_to_other(file: PathBuf, event: &UndoEventID, obj: RefID, other_obj: &RefID, only_match: &RefType, guess: &Point3f) -> LibResult {
-    entity_ops::snap_to_ref(&file, event, &obj, other_obj, only_match, guess)?;
-    app_state::update_deps(file, obj);
-    Ok(())
+    app_state::run_in_event(&file, event, || {
+        entity_ops::snap_to_ref(&file, event, &obj, other_obj, only_match, guess)?;
+        app_state::update_deps_in_event(&file, event, vec![obj])
+    })
+}
+
+pub fn set_ref(file: PathBuf, event: &UndoEventID, obj: RefID, index: ReferInd, refer: GeometryId, snap_pt: &Option<Point3f>) -> LibResult {
+    app_state::run_in_event(&file, event, || {
+        entity_ops::set_ref(&file, event, &obj, index, refer, snap_pt)?;
+        app_state::update_deps_in_event(&file, event, vec![obj])
+    })
 }
 
 pub fn join_objs(
@@ -115,9 +143,10 @@ pub fn join_objs(
     second_wants: &RefType,
     guess: &Point3f,
 ) -> LibResult {
-    entity_ops::join_refs(&file, event, &first, &second, first_wants, second_wants, guess)?;
-    app_state::update_all_deps(file, vec![first, second]);
-    Ok(())
+    app_state::run_in_event(&file, event, || {
+        entity_ops::join_refs(&file, event, &first, &second, first_wants, second_wants, guess)?;
+        app_state::update_deps_in_event(&file, event, vec![first, second])
+    })
 }
 
 pub fn get_closest_result(file: &PathBuf, obj_id: &RefID, only_match: &RefType, guess: &Point3f, query_id: QueryID, user_id: &UserID) -> LibResult {
@@ -125,6 +154,66 @@ pub fn get_closest_result(file: &PathBuf, obj_id: &RefID, only_match: &RefType,
     app_state::send_read_result(file, query_id, user_id, json!(res))
 }
 
+pub fn snap_point(
+    file: &PathBuf,
+    guess: &Point3f,
+    tolerance: WorldCoord,
+    from: &Option<Point3f>,
+    grid: &Option<WorldCoord>,
+    query_id: QueryID,
+    user_id: &UserID,
+) -> LibResult {
+    let res = entity_ops::snap_point(file, guess, tolerance, from, grid)?;
+    app_state::send_read_result(file, query_id, user_id, json!(res))
+}
+
+///Whether the file has changes that haven't been saved.
+pub fn get_modified(file: &PathBuf, query_id: QueryID, user_id: &UserID) -> LibResult {
+    let res = app_state::is_modified(file)?;
+    app_state::send_read_result(file, query_id, user_id, json!(res))
+}
+
+pub fn get_users(file: &PathBuf, query_id: QueryID, user_id: &UserID) -> LibResult {
+    let res = app_state::get_users(file)?;
+    app_state::send_read_result(file, query_id, user_id, json!(res))
+}
+
+pub fn get_locks(file: &PathBuf, query_id: QueryID, user_id: &UserID) -> LibResult {
+    let res = app_state::get_locks(file)?;
+    app_state::send_read_result(file, query_id, user_id, json!(res))
+}
+
+pub fn get_history(file: &PathBuf, query_id: QueryID, user_id: &UserID) -> LibResult {
+    let res = app_state::get_history(file)?;
+    app_state::send_read_result(file, query_id, user_id, json!(res))
+}
+
+///How far behind each user's updates are.
+pub fn get_queue_stats(file: &PathBuf, query_id: QueryID, user_id: &UserID) -> LibResult {
+    let res = app_state::get_queue_stats(file)?;
+    app_state::send_read_result(file, query_id, user_id, json!(res))
+}
+
+pub fn get_file_header(file: &PathBuf, query_id: QueryID, user_id: &UserID) -> LibResult {
+    let res = app_state::get_file_header(file)?;
+    app_state::send_read_result(file, query_id, user_id, json!(res))
+}
+
+pub fn get_dep_cycles(file: &PathBuf, query_id: QueryID, user_id: &UserID) -> LibResult {
+    let res = app_state::get_dep_cycles(file)?;
+    app_state::send_read_result(file, query_id, user_id, json!(res))
+}
+
+pub fn export_deps(file: &PathBuf, format: GraphFormat, query_id: QueryID, user_id: &UserID) -> LibResult {
+    let res = app_state::export_deps(file, format)?;
+    app_state::send_read_result(file, query_id, user_id, res)
+}
+
+///Rewrites a file as JSON (.flx), binary (.flxb) or compressed binary (.flxz), depending on the destination's extension.
+pub fn convert_file(from: &PathBuf, to: &PathBuf) -> LibResult {
+    operation_manager::convert_file(from, to)
+}
+
 pub fn demo(file: &PathBuf, user: &UserID, position: &Point3f) -> Result<(), DBError> {
     let side_length = 50.0;
     let width = 1.0;
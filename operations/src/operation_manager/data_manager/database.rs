@@ -1,8 +1,58 @@
-use super::undo::{Change, UndoEvent};
+use super::file_format::{self, FileHeader};
+use super::undo::{serialized_fields, Change, PropChange, UndoEvent};
 use crate::prelude::*;
 use ccl::dhashmap::{DHashMap, TryGetError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::time::Duration;
 
+///Puts back each property that still has the value the change gave it.  If someone has changed it since, their change wins.
+///Returns the changes that would redo what was undone.
+pub fn undo_props(obj: &mut DataObject, props: &Vec<PropChange>) -> Result<Vec<PropChange>, DBError> {
+    let mut redo = Vec::new();
+    for prop in props.iter().rev() {
+        let after: serde_json::Value = serde_json::from_str(&prop.after).map_err(error_other)?;
+        if obj.get_data(&prop.name).ok() != Some(after) {
+            debug!("Not undoing {:?}, it's been changed since", prop.name);
+            continue;
+        }
+        let mut data = serde_json::Map::new();
+        data.insert(prop.name.clone(), serde_json::from_str(&prop.before).map_err(error_other)?);
+        obj.set_data(serde_json::Value::Object(data))?;
+        redo.push(PropChange {
+            name: prop.name.clone(),
+            before: prop.after.clone(),
+            after: prop.before.clone(),
+        });
+    }
+    Ok(redo)
+}
+
+///Like `undo_props`, but for fields of the object's serialized form.  The object is rebuilt from its fields afterwards.
+pub fn undo_fields(obj: &mut DataObject, fields: &Vec<PropChange>) -> Result<Vec<PropChange>, DBError> {
+    let (tag, mut current) = serialized_fields(obj)?;
+    let mut redo = Vec::new();
+    for field in fields.iter().rev() {
+        let after: serde_json::Value = serde_json::from_str(&field.after).map_err(error_other)?;
+        if current.get(&field.name) != Some(&after) {
+            debug!("Not undoing {:?}, it's been changed since", field.name);
+            continue;
+        }
+        current.insert(field.name.clone(), serde_json::from_str(&field.before).map_err(error_other)?);
+        redo.push(PropChange {
+            name: field.name.clone(),
+            before: field.after.clone(),
+            after: field.before.clone(),
+        });
+    }
+    if redo.len() > 0 {
+        let mut tagged = serde_json::Map::new();
+        tagged.insert(tag, serde_json::Value::Object(current));
+        *obj = serde_json::from_value(serde_json::Value::Object(tagged)).map_err(error_other)?;
+    }
+    Ok(redo)
+}
+
 fn print_stacktrace() {
     backtrace::trace(|frame| {
         backtrace::resolve_frame(frame, |symbol| {
@@ -38,13 +88,85 @@ fn run_timeout(mut callback: impl FnMut() -> Result<(), DBError>) -> Result<(),
     }
 }
 
+fn with_suffix(path: &PathBuf, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn backup_path(path: &PathBuf, number: usize) -> PathBuf {
+    match number {
+        1 => with_suffix(path, ".bak"),
+        _ => with_suffix(path, &format!(".bak.{}", number)),
+    }
+}
+
+///Shifts the existing backups along, dropping the oldest, and copies the current file into the newest slot.
+fn rotate_backups(path: &PathBuf, count: usize) -> Result<(), DBError> {
+    if count == 0 || !path.exists() {
+        return Ok(());
+    }
+    for number in (1..count).rev() {
+        let older = backup_path(path, number);
+        if older.exists() {
+            std::fs::rename(&older, backup_path(path, number + 1)).map_err(error_other)?;
+        }
+    }
+    std::fs::copy(path, backup_path(path, 1)).map_err(error_other)?;
+    Ok(())
+}
+
+///The rename is only durable once the directory entry itself is flushed.  Windows doesn't allow opening a directory.
+fn sync_dir(path: &PathBuf) {
+    if cfg!(unix) {
+        if let Some(dir) = path.parent() {
+            let dir = if dir.as_os_str().is_empty() { std::path::Path::new(".") } else { dir };
+            if let Err(e) = std::fs::File::open(dir).and_then(|handle| handle.sync_all()) {
+                warn!("Couldn't sync directory {:?}: {:?}", dir, e);
+            }
+        }
+    }
+}
+
+///Writes to a temporary file next to the destination and renames it over the top once it's safely on disk,
+///so a failed write never destroys the last good copy.  Each write gets its own temporary file, so writers can't
+///clobber each other's half written output.
+pub fn write_atomic(
+    path: &PathBuf,
+    backups: usize,
+    write: impl FnOnce(&mut std::io::BufWriter<std::fs::File>) -> Result<(), DBError>,
+) -> Result<(), DBError> {
+    let temp_path = with_suffix(path, &format!(".{}.tmp", RefID::new_v4().to_simple()));
+    let write_temp = || {
+        let file = std::fs::File::create(&temp_path).map_err(error_other)?;
+        let mut writer = std::io::BufWriter::new(file);
+        write(&mut writer)?;
+        let file = writer.into_inner().map_err(error_other)?;
+        file.sync_all().map_err(error_other)
+    };
+    if let Err(e) = write_temp() {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    rotate_backups(path, backups)?;
+    std::fs::rename(&temp_path, path).map_err(error_other)?;
+    sync_dir(path);
+    Ok(())
+}
+
 pub struct FileDatabase {
     db: DHashMap<RefID, DataObject>,
+    header: Mutex<FileHeader>,
+    backup_count: AtomicUsize,
 }
 
 impl FileDatabase {
     pub fn new() -> FileDatabase {
-        FileDatabase { db: DHashMap::default() }
+        FileDatabase {
+            db: DHashMap::default(),
+            header: Mutex::new(FileHeader::new()),
+            backup_count: AtomicUsize::new(0),
+        }
     }
 
     pub fn add(&self, obj: DataObject) -> Result<(), DBError> {
@@ -58,6 +180,11 @@ impl FileDatabase {
         }
     }
 
+    ///Adds the object, overwriting any object with the same id.
+    pub fn replace(&self, obj: DataObject) {
+        self.db.insert(obj.get_id().clone(), obj);
+    }
+
     pub fn get(&self, key: &RefID, mut callback: impl FnMut(&DataObject) -> Result<(), DBError>) -> Result<(), DBError> {
         if *key == RefID::nil() {
             return Err(DBError::ObjNotFound);
@@ -155,26 +282,68 @@ impl FileDatabase {
                     redo.changes.push(Change::Add { key: obj.get_id().clone() });
                     self.db.insert(obj.get_id().clone(), obj.clone());
                 }
+                Change::Props { key, props } => match self.db.get_mut(key) {
+                    Some(mut obj) => {
+                        let redo_props = undo_props(&mut obj, props)?;
+                        redo.changes.push(Change::Props {
+                            key: key.clone(),
+                            props: redo_props,
+                        });
+                    }
+                    None => return Err(DBError::ObjNotFound),
+                },
+                Change::Fields { key, fields } => match self.db.get_mut(key) {
+                    Some(mut obj) => {
+                        let redo_fields = undo_fields(&mut obj, fields)?;
+                        redo.changes.push(Change::Fields {
+                            key: key.clone(),
+                            fields: redo_fields,
+                        });
+                    }
+                    None => return Err(DBError::ObjNotFound),
+                },
             }
         }
         Ok(redo)
     }
 
-    pub fn save(&self, path: &PathBuf) -> Result<(), DBError> {
-        let file = std::fs::File::create(path).map_err(error_other)?;
-        let mut vals = Vec::new();
+    ///A copy of every object, as it is right now.
+    pub fn get_all(&self) -> HashMap<RefID, DataObject> {
+        let mut objects = HashMap::new();
         for chunk in self.db.chunks() {
-            for (_, val) in chunk.iter() {
-                vals.push(val.clone());
+            for (key, val) in chunk.iter() {
+                objects.insert(key.clone(), val.clone());
             }
         }
-        serde_json::to_writer(file, &vals).map_err(error_other)?;
-        Ok(())
+        objects
     }
 
-    pub fn open(&self, path: &PathBuf) -> Result<Vec<RefID>, DBError> {
+    pub fn save(&self, path: &PathBuf, history: &Vec<UndoEvent>) -> Result<(), DBError> {
+        let objects = self.get_all().into_iter().map(|(_, obj)| obj).collect();
+        self.write(path, &objects, history)
+    }
+
+    ///Saves the given objects rather than what's in the database.
+    pub fn write(&self, path: &PathBuf, objects: &Vec<DataObject>, history: &Vec<UndoEvent>) -> Result<(), DBError> {
+        write_atomic(path, self.get_backup_count(), |writer| {
+            file_format::write(writer, file_format::Encoding::from_path(path), &self.get_header(), objects, history)
+        })
+    }
+
+    pub fn get_backup_count(&self) -> usize {
+        self.backup_count.load(Ordering::SeqCst)
+    }
+
+    ///How many backups to keep beside the file.  The newest is file.bak, then file.bak.2 and so on.
+    pub fn set_backup_count(&self, count: usize) {
+        self.backup_count.store(count, Ordering::SeqCst);
+    }
+
+    ///Returns the keys of the objects that were read along with any undo history saved in the file.
+    pub fn open(&self, path: &PathBuf) -> Result<(Vec<RefID>, Vec<UndoEvent>), DBError> {
         let file = std::fs::File::open(path).map_err(error_other)?;
-        let objects: Vec<DataObject> = serde_json::from_reader(file).map_err(error_other)?;
+        let (header, objects, history) = file_format::read(file)?;
+        self.set_header(header);
         let mut keys = Vec::new();
         for obj in objects {
             keys.push(obj.get_id().clone());
@@ -182,7 +351,15 @@ impl FileDatabase {
                 error!("Error adding object during open: {:?}", e);
             }
         }
-        Ok(keys)
+        Ok((keys, history))
+    }
+
+    pub fn get_header(&self) -> FileHeader {
+        self.header.lock().unwrap().clone()
+    }
+
+    pub fn set_header(&self, header: FileHeader) {
+        *self.header.lock().unwrap() = header;
     }
 
     pub fn debug_state(&self, output: &mut String) {
@@ -197,6 +374,7 @@ impl FileDatabase {
 
 #[cfg(test)]
 mod tests {
+    use super::super::DataManager;
     use super::*;
     use crate::tests::*;
 
@@ -269,7 +447,7 @@ mod tests {
             db.add(obj_1).unwrap();
             db.add(obj_2).unwrap();
             db.add(obj_3).unwrap();
-            db.save(&path).unwrap();
+            db.save(&path, &Vec::new()).unwrap();
         }
         let db = FileDatabase::new();
         db.open(&path).unwrap();
@@ -293,4 +471,166 @@ mod tests {
         .unwrap();
         std::fs::remove_file(path).unwrap();
     }
+
+    #[test]
+    fn test_legacy_indices() {
+        let path = PathBuf::from("./test_legacy_indices.flx");
+        let mut wall = Wall::new(Point3f::new(0.0, 0.0, 0.0), Point3f::new(1.0, 0.0, 0.0), 0.5, 1.0);
+        let mut door = Door::new(Point3f::new(0.25, 0.0, 0.0), Point3f::new(0.75, 0.0, 0.0), 0.25, 0.75);
+        let wall_id = wall.get_id().clone();
+        let door_id = door.get_id().clone();
+        let opening = door.get_result(&Door::OPENING).unwrap();
+        let center = wall.get_result(&Wall::CENTER).unwrap();
+        wall.add_ref(&opening, GeometryId::new(door_id, Door::OPENING), &None);
+        door.set_ref(&Door::DIR, &center, GeometryId::new(wall_id, Wall::CENTER), &None);
+
+        //Write the objects the way files used to store them, with positional indices and an array of openings
+        let wall_obj: DataObject = Box::new(wall);
+        let door_obj: DataObject = Box::new(door);
+        let mut wall_json = serde_json::to_value(&wall_obj).unwrap();
+        let mut door_json = serde_json::to_value(&door_obj).unwrap();
+        let mut open = wall_json["Wall"]["openings"]["0"].take();
+        open["refer"]["index"] = json!(2);
+        wall_json["Wall"]["openings"] = json!([open]);
+        wall_json["Wall"].as_object_mut().unwrap().remove("next_opening");
+        door_json["Door"]["dir"]["refer"]["index"] = json!(2);
+        std::fs::write(&path, serde_json::to_string(&json!([wall_json, door_json])).unwrap()).unwrap();
+
+        let db = FileDatabase::new();
+        db.open(&path).unwrap();
+        db.get(&wall_id, |obj| {
+            let refs = obj.query_ref::<dyn UpdateFromRefs>().unwrap().get_refs();
+            assert!(refs.contains(&Some(Reference::new(wall_id, RefIndex::Sub(0), GeometryId::new(door_id, Door::OPENING)))));
+            Ok(())
+        })
+        .unwrap();
+        db.get(&door_id, |obj| {
+            let refs = obj.query_ref::<dyn UpdateFromRefs>().unwrap().get_refs();
+            assert_eq!(refs[0], Some(Reference::new(door_id, Door::DIR, GeometryId::new(wall_id, Wall::CENTER))));
+            Ok(())
+        })
+        .unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_file_header() {
+        let path = PathBuf::from("./test_file_header.flx");
+        let obj = Box::new(TestObj::new("first"));
+        let id = obj.get_id().clone();
+        let mut header = FileHeader::new();
+        header.units = file_format::Units::Feet;
+        header.project.name = String::from("House");
+        {
+            let db = FileDatabase::new();
+            db.add(obj).unwrap();
+            db.set_header(header.clone());
+            db.save(&path, &Vec::new()).unwrap();
+        }
+        let db = FileDatabase::new();
+        assert_eq!(db.open(&path).unwrap().0, vec![id]);
+        assert_eq!(db.get_header(), header);
+
+        //An object that can't be read would be deleted by the next save, so the file shouldn't open at all
+        let mut contents: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let objects = contents["objects"].as_array_mut().unwrap();
+        objects.push(json!({"NotAnObject": {"id": RefID::new_v4()}}));
+        std::fs::write(&path, serde_json::to_string(&contents).unwrap()).unwrap();
+        let db = FileDatabase::new();
+        assert!(db.open(&path).is_err());
+        contents["objects"].as_array_mut().unwrap().pop();
+
+        contents["header"]["format_version"] = json!(file_format::FORMAT_VERSION + 1);
+        std::fs::write(&path, serde_json::to_string(&contents).unwrap()).unwrap();
+        let db = FileDatabase::new();
+        assert!(db.open(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_encodings() {
+        let json_path = PathBuf::from("./test_encodings.flx");
+        let binary_path = PathBuf::from("./test_encodings.flxb");
+        let compressed_path = PathBuf::from("./test_encodings.flxz");
+        let round_trip_path = PathBuf::from("./test_encodings_round_trip.flx");
+        let mut wall = Wall::new(Point3f::new(0.0, 0.0, 0.0), Point3f::new(10.0, 0.0, 0.0), 0.5, 1.0);
+        let door = Door::new(Point3f::new(2.0, 0.0, 0.0), Point3f::new(4.0, 0.0, 0.0), 0.5, 0.75);
+        let opening = door.get_result(&Door::OPENING).unwrap();
+        wall.add_ref(&opening, GeometryId::new(door.get_id().clone(), Door::OPENING), &None);
+        let objects: Vec<DataObject> = vec![Box::new(wall), Box::new(door), Box::new(TestObj::new("some data"))];
+        let get_json = |db: &FileDatabase| {
+            objects
+                .iter()
+                .map(|obj| {
+                    let mut json = serde_json::Value::Null;
+                    db.get(obj.get_id(), |read| {
+                        json = serde_json::to_value(read).unwrap();
+                        Ok(())
+                    })
+                    .unwrap();
+                    json
+                })
+                .collect::<Vec<serde_json::Value>>()
+        };
+
+        let db = FileDatabase::new();
+        for obj in &objects {
+            db.add(obj.clone()).unwrap();
+        }
+        let expected = get_json(&db);
+        db.save(&json_path, &Vec::new()).unwrap();
+        db.save(&binary_path, &Vec::new()).unwrap();
+        db.save(&compressed_path, &Vec::new()).unwrap();
+        assert!(!std::fs::read(&binary_path).unwrap().starts_with(b"{"));
+        for path in &[&json_path, &binary_path, &compressed_path] {
+            let db = FileDatabase::new();
+            assert_eq!(db.open(path).unwrap().0.len(), objects.len());
+            assert_eq!(get_json(&db), expected);
+        }
+
+        DataManager::convert_file(&compressed_path, &round_trip_path).unwrap();
+        assert!(std::fs::read(&round_trip_path).unwrap().starts_with(b"{"));
+        let db = FileDatabase::new();
+        db.open(&round_trip_path).unwrap();
+        assert_eq!(get_json(&db), expected);
+        for path in &[json_path, binary_path, compressed_path, round_trip_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_backups() {
+        let path = PathBuf::from("./test_backups.flx");
+        let obj = Box::new(TestObj::new("0"));
+        let id = obj.get_id().clone();
+        let db = FileDatabase::new();
+        db.add(obj).unwrap();
+        db.set_backup_count(2);
+        for i in 0..4 {
+            db.get_mut(&id, |obj| {
+                obj.query_mut::<dyn Store>().unwrap().set_store_data(i.to_string());
+                Ok(())
+            })
+            .unwrap();
+            db.save(&path, &Vec::new()).unwrap();
+        }
+        let temp_files = std::fs::read_dir(".")
+            .unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+            .filter(|name| name.starts_with("test_backups.flx.") && name.ends_with(".tmp"))
+            .count();
+        assert_eq!(temp_files, 0);
+        assert!(!backup_path(&path, 3).exists());
+        for (number, expected) in &[(0, "3"), (1, "2"), (2, "1")] {
+            let saved = if *number == 0 { path.clone() } else { backup_path(&path, *number) };
+            let db = FileDatabase::new();
+            db.open(&saved).unwrap();
+            db.get(&id, |obj| {
+                assert_eq!(obj.query_ref::<dyn Store>().unwrap().get_store_data(), *expected);
+                Ok(())
+            })
+            .unwrap();
+            std::fs::remove_file(saved).unwrap();
+        }
+    }
 }
@@ -0,0 +1,531 @@
+use super::undo::UndoEvent;
+use crate::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+///Bump this and add a migration whenever the layout of a saved object changes.
+pub const FORMAT_VERSION: u32 = 3;
+const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+///Each migration upgrades the objects of a file by one version, starting from version 1.
+const MIGRATIONS: [fn(&mut Vec<Value>); 2] = [upgrade_legacy_indices, upgrade_binary_layout];
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub enum Units {
+    Millimeters,
+    Meters,
+    Inches,
+    Feet,
+}
+
+impl Default for Units {
+    fn default() -> Units {
+        Units::Meters
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ProjectInfo {
+    pub name: String,
+    pub author: String,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FileHeader {
+    pub format_version: u32,
+    ///Version of the kernel that first created the file.
+    pub created_with: String,
+    ///Version of the kernel that last saved the file.
+    pub saved_with: String,
+    #[serde(default)]
+    pub units: Units,
+    #[serde(default)]
+    pub project: ProjectInfo,
+    ///How many undo events to keep in the file.
+    #[serde(default)]
+    pub history_depth: usize,
+}
+
+impl FileHeader {
+    pub fn new() -> FileHeader {
+        FileHeader {
+            format_version: FORMAT_VERSION,
+            created_with: String::from(APP_VERSION),
+            saved_with: String::from(APP_VERSION),
+            units: Units::default(),
+            project: ProjectInfo::default(),
+            history_depth: 0,
+        }
+    }
+
+    ///Files written before there was a header were a bare array of objects.
+    fn legacy() -> FileHeader {
+        FileHeader {
+            format_version: 1,
+            created_with: String::new(),
+            saved_with: String::new(),
+            units: Units::default(),
+            project: ProjectInfo::default(),
+            history_depth: 0,
+        }
+    }
+}
+
+///Which container a file is written in, chosen by its extension.  Files are recognized by their contents when opened.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Encoding {
+    ///.flx, readable and easy to diff
+    Json,
+    ///.flxb
+    Binary,
+    ///.flxz, binary with the objects deflated
+    Compressed,
+}
+
+impl Encoding {
+    pub fn from_path(path: &PathBuf) -> Encoding {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("flxb") => Encoding::Binary,
+            Some("flxz") => Encoding::Compressed,
+            _ => Encoding::Json,
+        }
+    }
+}
+
+const MAGIC: &[u8; 4] = b"FLXB";
+
+#[derive(Serialize)]
+struct FileContentsRef<'a> {
+    header: &'a FileHeader,
+    objects: &'a Vec<DataObject>,
+    history: &'a Vec<UndoEvent>,
+}
+
+#[derive(Deserialize)]
+struct FileContents {
+    header: FileHeader,
+    objects: Vec<Value>,
+    #[serde(default)]
+    history: Vec<Value>,
+}
+
+///Each object is encoded separately so that one that can't be read can be reported on its own.  Since version 3 an
+///object is bincode of its struct behind its type name, and the history is bincode of the undo events.  Before that both
+///were `Tree`s.  The format version comes first in the header, so it can be checked before the payloads are decoded.
+#[derive(Serialize, Deserialize)]
+struct BinaryContents {
+    header: FileHeader,
+    compressed: bool,
+    objects: Vec<u8>,
+    history: Vec<u8>,
+}
+
+///A JSON value in a form bincode can read back.  Binary files before version 3 stored objects and history this way,
+///so they're read back into values and upgraded by the same migrations as JSON files.
+#[derive(Serialize, Deserialize)]
+enum Tree {
+    Null,
+    Bool(bool),
+    Unsigned(u64),
+    Signed(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Tree>),
+    Object(Vec<(String, Tree)>),
+}
+
+impl From<Tree> for Value {
+    fn from(tree: Tree) -> Value {
+        match tree {
+            Tree::Null => Value::Null,
+            Tree::Bool(val) => Value::Bool(val),
+            Tree::Unsigned(val) => Value::from(val),
+            Tree::Signed(val) => Value::from(val),
+            Tree::Float(val) => serde_json::Number::from_f64(val).map_or(Value::Null, Value::Number),
+            Tree::String(val) => Value::String(val),
+            Tree::Array(trees) => Value::Array(trees.into_iter().map(Value::from).collect()),
+            Tree::Object(fields) => Value::Object(fields.into_iter().map(|(key, tree)| (key, Value::from(tree))).collect()),
+        }
+    }
+}
+
+fn compress(payload: Vec<u8>, compressed: bool) -> Vec<u8> {
+    if compressed {
+        miniz_oxide::deflate::compress_to_vec(&payload, 6)
+    } else {
+        payload
+    }
+}
+
+fn decompress(payload: Vec<u8>, compressed: bool) -> Result<Vec<u8>, DBError> {
+    if compressed {
+        miniz_oxide::inflate::decompress_to_vec(&payload).map_err(error_other)
+    } else {
+        Ok(payload)
+    }
+}
+
+pub fn write<W: std::io::Write>(
+    writer: W,
+    encoding: Encoding,
+    header: &FileHeader,
+    objects: &Vec<DataObject>,
+    history: &Vec<UndoEvent>,
+) -> Result<(), DBError> {
+    let mut header = header.clone();
+    header.format_version = FORMAT_VERSION;
+    header.saved_with = String::from(APP_VERSION);
+    match encoding {
+        Encoding::Json => serde_json::to_writer(
+            writer,
+            &FileContentsRef {
+                header: &header,
+                objects,
+                history,
+            },
+        )
+        .map_err(error_other),
+        Encoding::Binary | Encoding::Compressed => {
+            let mut encoded = Vec::with_capacity(objects.len());
+            for obj in objects {
+                encoded.push(bincode::serialize(obj).map_err(error_other)?);
+            }
+            let history = bincode::serialize(history).map_err(error_other)?;
+            write_binary(writer, header, &encoded, history, encoding == Encoding::Compressed)
+        }
+    }
+}
+
+///Writes the container around objects and history that are already encoded.
+fn write_binary<W: std::io::Write>(
+    mut writer: W,
+    header: FileHeader,
+    objects: &Vec<Vec<u8>>,
+    history: Vec<u8>,
+    compressed: bool,
+) -> Result<(), DBError> {
+    let objects = compress(bincode::serialize(objects).map_err(error_other)?, compressed);
+    let history = compress(history, compressed);
+    writer.write_all(MAGIC).map_err(error_other)?;
+    bincode::serialize_into(
+        writer,
+        &BinaryContents {
+            header,
+            compressed,
+            objects,
+            history,
+        },
+    )
+    .map_err(error_other)
+}
+
+///Reads a file of any version and encoding and upgrades it to the current version.  Fails if any object still can't be
+///read, since leaving it out would delete it from the file on the next save.
+///The migrations don't upgrade undo history, so it's kept as long as it can still be read, and dropped as a whole if not.
+pub fn read<R: std::io::Read>(mut reader: R) -> Result<(FileHeader, Vec<DataObject>, Vec<UndoEvent>), DBError> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).map_err(error_other)?;
+    if !bytes.starts_with(MAGIC) {
+        let (header, values, history) = read_json(&bytes)?;
+        return upgrade(header, values, history);
+    }
+    let bytes = &bytes[MAGIC.len()..];
+    //The version comes first, so a newer file is turned away before the rest of the layout is assumed
+    let version: u32 = bincode::deserialize(bytes).map_err(error_other)?;
+    if version > FORMAT_VERSION {
+        return Err(DBError::Other(format!("Unsupported file version {}", version)));
+    }
+    let contents: BinaryContents = bincode::deserialize(bytes).map_err(error_other)?;
+    if version == FORMAT_VERSION {
+        read_binary(contents)
+    } else {
+        let (header, values, history) = read_tree_binary(contents)?;
+        upgrade(header, values, history)
+    }
+}
+
+///Decodes a file in the current layout straight into objects, without going through JSON values.
+fn read_binary(contents: BinaryContents) -> Result<(FileHeader, Vec<DataObject>, Vec<UndoEvent>), DBError> {
+    let payload = decompress(contents.objects, contents.compressed)?;
+    let encoded: Vec<Vec<u8>> = bincode::deserialize(&payload).map_err(error_other)?;
+    let mut objects = Vec::with_capacity(encoded.len());
+    for (i, bytes) in encoded.iter().enumerate() {
+        objects.push(bincode::deserialize(bytes).map_err(|e| unreadable(i, e))?);
+    }
+    let history = match bincode::deserialize(&decompress(contents.history, contents.compressed)?) {
+        Ok(history) => history,
+        Err(e) => {
+            error!("Dropping undo history that couldn't be read: {:?}", e);
+            Vec::new()
+        }
+    };
+    Ok((contents.header, objects, history))
+}
+
+///Runs the migrations on the values of an older or JSON file, then reads the objects and history out of them.
+fn upgrade(
+    mut header: FileHeader,
+    mut values: Vec<Value>,
+    history_values: Vec<Value>,
+) -> Result<(FileHeader, Vec<DataObject>, Vec<UndoEvent>), DBError> {
+    let mut history = Vec::new();
+    for val in history_values {
+        match serde_json::from_value(val) {
+            Ok(event) => history.push(event),
+            Err(e) => {
+                error!(
+                    "Dropping undo history from file version {} that couldn't be read: {:?}",
+                    header.format_version, e
+                );
+                history.clear();
+                break;
+            }
+        }
+    }
+    migrate(&mut header, &mut values)?;
+    let mut objects = Vec::with_capacity(values.len());
+    for (i, val) in values.into_iter().enumerate() {
+        objects.push(serde_json::from_value(val).map_err(|e| unreadable(i, e))?);
+    }
+    Ok((header, objects, history))
+}
+
+fn read_json(bytes: &[u8]) -> Result<(FileHeader, Vec<Value>, Vec<Value>), DBError> {
+    match serde_json::from_slice(bytes).map_err(error_other)? {
+        Value::Array(values) => Ok((FileHeader::legacy(), values, Vec::new())),
+        contents => {
+            let contents: FileContents = serde_json::from_value(contents).map_err(error_other)?;
+            Ok((contents.header, contents.objects, contents.history))
+        }
+    }
+}
+
+///Reads a binary file from before version 3, when objects and history were stored as `Tree`s.
+fn read_tree_binary(contents: BinaryContents) -> Result<(FileHeader, Vec<Value>, Vec<Value>), DBError> {
+    let payload = decompress(contents.objects, contents.compressed)?;
+    let encoded: Vec<Vec<u8>> = bincode::deserialize(&payload).map_err(error_other)?;
+    let mut values = Vec::with_capacity(encoded.len());
+    for (i, bytes) in encoded.iter().enumerate() {
+        let tree: Tree = bincode::deserialize(bytes).map_err(|e| unreadable(i, e))?;
+        values.push(Value::from(tree));
+    }
+    let history = match bincode::deserialize::<Vec<Tree>>(&decompress(contents.history, contents.compressed)?) {
+        Ok(trees) => trees.into_iter().map(Value::from).collect(),
+        Err(e) => {
+            error!("Dropping undo history that couldn't be read: {:?}", e);
+            Vec::new()
+        }
+    };
+    Ok((contents.header, values, history))
+}
+
+fn unreadable<T: std::fmt::Debug>(index: usize, err: T) -> DBError {
+    DBError::Other(format!("Object {} in the file couldn't be read: {:?}", index, err))
+}
+
+fn migrate(header: &mut FileHeader, objects: &mut Vec<Value>) -> Result<(), DBError> {
+    if header.format_version == 0 || header.format_version > FORMAT_VERSION {
+        return Err(DBError::Other(format!("Unsupported file version {}", header.format_version)));
+    }
+    while header.format_version < FORMAT_VERSION {
+        info!("Upgrading file from version {}", header.format_version);
+        MIGRATIONS[header.format_version as usize - 1](objects);
+        header.format_version += 1;
+    }
+    Ok(())
+}
+
+///Version 3 only changed how binary files store objects, so values don't need anything done to them.
+fn upgrade_binary_layout(_objects: &mut Vec<Value>) {}
+
+///Where each type kept its references in version 1.  Each of these fields held an `UpdatableGeometry`, and wall
+///openings held them too.
+const LEGACY_REF_FIELDS: [(&str, &[&str]); 3] = [
+    ("Wall", &["first_pt", "second_pt"]),
+    ("Door", &["dir"]),
+    ("Dimension", &["first", "second"]),
+];
+
+///Gives a reference slot's numeric index the name or sub id it maps to.  One that can't be mapped is left as it was,
+///so the object fails to load instead of quietly losing what it was attached to.
+fn upgrade_geometry_id(slot: Option<&mut Value>, types: &HashMap<RefID, String>) {
+    let refer = match slot.and_then(|slot| slot.get_mut("refer")) {
+        Some(Value::Object(refer)) => refer,
+        _ => return,
+    };
+    let old_index = match refer.get("index").and_then(|index| index.as_u64()) {
+        Some(index) => index,
+        None => return,
+    };
+    let id: Option<RefID> = refer.get("id").and_then(|id| serde_json::from_value(id.clone()).ok());
+    let new_index = id
+        .and_then(|id| types.get(&id))
+        .and_then(|type_str| legacy_result_index(type_str, old_index));
+    match new_index.map(serde_json::to_value) {
+        Some(Ok(index)) => {
+            refer.insert(String::from("index"), index);
+        }
+        _ => warn!("Couldn't upgrade reference to result {} of {:?}", old_index, refer.get("id")),
+    }
+}
+
+fn upgrade_wall_openings(fields: &mut Value) {
+    if let Some(Value::Array(openings)) = fields.get_mut("openings") {
+        let mut by_sub = serde_json::Map::new();
+        for (sub, open) in openings.drain(..).enumerate() {
+            by_sub.insert(sub.to_string(), open);
+        }
+        let next_opening = by_sub.len();
+        fields["openings"] = Value::Object(by_sub);
+        fields["next_opening"] = json!(next_opening);
+    }
+}
+
+///Results used to be referred to by their position in get_all_results, which shifted whenever something before them was removed.
+///This rewrites the references in the slots listed in `LEGACY_REF_FIELDS`, and gives wall openings the sub ids those
+///positions map to.
+pub fn upgrade_legacy_indices(objects: &mut Vec<Value>) {
+    let mut types = HashMap::new();
+    for obj in objects.iter() {
+        if let Value::Object(tagged) = obj {
+            for (type_str, fields) in tagged {
+                if let Ok(id) = serde_json::from_value::<RefID>(fields["id"].clone()) {
+                    types.insert(id, type_str.clone());
+                }
+            }
+        }
+    }
+    for obj in objects.iter_mut() {
+        if let Value::Object(tagged) = obj {
+            for (type_str, fields) in tagged.iter_mut() {
+                if type_str == "Wall" {
+                    upgrade_wall_openings(fields);
+                    if let Some(Value::Object(openings)) = fields.get_mut("openings") {
+                        for open in openings.values_mut() {
+                            upgrade_geometry_id(Some(open), &types);
+                        }
+                    }
+                }
+                let slots = LEGACY_REF_FIELDS.iter().find(|(name, _)| name == type_str);
+                for field in slots.map_or(&[][..], |(_, fields)| fields) {
+                    upgrade_geometry_id(fields.get_mut(*field), &types);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    impl From<Value> for Tree {
+        fn from(value: Value) -> Tree {
+            match value {
+                Value::Null => Tree::Null,
+                Value::Bool(val) => Tree::Bool(val),
+                Value::Number(num) => match (num.as_u64(), num.as_i64()) {
+                    (Some(val), _) => Tree::Unsigned(val),
+                    (None, Some(val)) => Tree::Signed(val),
+                    (None, None) => num.as_f64().map_or(Tree::Null, Tree::Float),
+                },
+                Value::String(val) => Tree::String(val),
+                Value::Array(vals) => Tree::Array(vals.into_iter().map(Tree::from).collect()),
+                Value::Object(map) => Tree::Object(map.into_iter().map(|(key, val)| (key, Tree::from(val))).collect()),
+            }
+        }
+    }
+
+    ///Writes a binary file in the layout used before version 3.
+    fn write_tree_binary(bytes: &mut Vec<u8>, header: FileHeader, objects: Vec<Value>, compressed: bool) {
+        let encoded: Vec<Vec<u8>> = objects.into_iter().map(|val| bincode::serialize(&Tree::from(val)).unwrap()).collect();
+        let history = bincode::serialize(&Vec::<Tree>::new()).unwrap();
+        write_binary(bytes, header, &encoded, history, compressed).unwrap();
+    }
+
+    #[test]
+    fn test_binary_migration() {
+        let mut wall = Wall::new(Point3f::new(0.0, 0.0, 0.0), Point3f::new(1.0, 0.0, 0.0), 0.5, 1.0);
+        let door = Door::new(Point3f::new(0.25, 0.0, 0.0), Point3f::new(0.75, 0.0, 0.0), 0.25, 0.75);
+        let wall_id = wall.get_id().clone();
+        let door_id = door.get_id().clone();
+        let opening = door.get_result(&Door::OPENING).unwrap();
+        wall.add_ref(&opening, GeometryId::new(door_id, Door::OPENING), &None);
+
+        //The opening the way version 1 stored it, with a positional index and in an array
+        let wall_obj: DataObject = Box::new(wall);
+        let door_obj: DataObject = Box::new(door);
+        let mut wall_json = serde_json::to_value(&wall_obj).unwrap();
+        let mut open = wall_json["Wall"]["openings"]["0"].take();
+        open["refer"]["index"] = json!(2);
+        wall_json["Wall"]["openings"] = json!([open]);
+        wall_json["Wall"].as_object_mut().unwrap().remove("next_opening");
+        let mut bytes = Vec::new();
+        write_tree_binary(
+            &mut bytes,
+            FileHeader::legacy(),
+            vec![wall_json, serde_json::to_value(&door_obj).unwrap()],
+            true,
+        );
+
+        let (header, objects, _) = read(&bytes[..]).unwrap();
+        assert_eq!(header.format_version, FORMAT_VERSION);
+        let refs = objects[0].query_ref::<dyn UpdateFromRefs>().unwrap().get_refs();
+        assert!(refs.contains(&Some(Reference::new(wall_id, RefIndex::Sub(0), GeometryId::new(door_id, Door::OPENING)))));
+    }
+
+    #[test]
+    fn test_upgrade_only_references() {
+        let (door_id, missing_id) = (RefID::new_v4(), RefID::new_v4());
+        let legacy_ref = |id: &RefID, index: u64| json!({"refer": {"id": id, "index": index}, "geom": {"pt": [0.0, 0.0, 0.0]}});
+        let mut objects = vec![
+            json!({"Door": {"id": door_id, "dir": {"refer": null}}}),
+            json!({"Dimension": {"id": RefID::new_v4(), "first": legacy_ref(&door_id, 1), "second": legacy_ref(&missing_id, 0)}}),
+            //Shaped like a reference, but not where any version 1 type kept one
+            json!({"Other": {"id": RefID::new_v4(), "data": {"id": door_id, "index": 0}}}),
+        ];
+        let expected_other = objects[2].clone();
+        upgrade_legacy_indices(&mut objects);
+        assert_eq!(
+            objects[1]["Dimension"]["first"]["refer"]["index"],
+            serde_json::to_value(&Door::SECOND).unwrap()
+        );
+        //Nothing to map it to, so it's left for loading to report
+        assert_eq!(objects[1]["Dimension"]["second"], legacy_ref(&missing_id, 0));
+        assert_eq!(objects[2], expected_other);
+    }
+
+    #[test]
+    fn test_binary_size() {
+        let objects: Vec<DataObject> = (0..10000)
+            .map(|i| {
+                let start = Point3f::new(i as f64, 0.0, 0.0);
+                let wall: DataObject = Box::new(Wall::new(start, Point3f::new(i as f64, 1.0, 0.0), 0.5, 1.0));
+                wall
+            })
+            .collect();
+        let mut sizes = Vec::new();
+        for encoding in [Encoding::Json, Encoding::Binary, Encoding::Compressed].iter() {
+            let mut bytes = Vec::new();
+            let start = Instant::now();
+            write(&mut bytes, *encoding, &FileHeader::new(), &objects, &Vec::new()).unwrap();
+            let written = start.elapsed();
+            let start = Instant::now();
+            let (_, read_back, _) = read(&bytes[..]).unwrap();
+            println!(
+                "{:?}: {} bytes, written in {:?}, read in {:?}",
+                encoding,
+                bytes.len(),
+                written,
+                start.elapsed()
+            );
+            assert_eq!(read_back.len(), objects.len());
+            sizes.push(bytes.len());
+        }
+        assert!(sizes[1] < sizes[0]);
+        assert!(sizes[2] < sizes[1]);
+    }
+}
@@ -0,0 +1,145 @@
+use super::database::{write_atomic, FileDatabase};
+use crate::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Action {
+    Commit(UndoEventID),
+    Cancel(UndoEventID),
+    Undo(UserID),
+    Redo(UserID),
+    UndoTo(UndoEventID),
+    Revert(UndoEventID),
+}
+
+///The state of every object an action touched, after the action.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    action: Action,
+    objects: Vec<DataObject>,
+    deleted: Vec<RefID>,
+}
+
+pub fn journal_path(file: &PathBuf) -> PathBuf {
+    let mut name = file.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    name.push(".journal");
+    file.with_file_name(name)
+}
+
+///Everything that's happened since the last save, one JSON entry per line, kept beside the file.
+///The journal is removed when the file is closed cleanly, so finding one on open means the last session crashed.
+pub struct Journal {
+    path: Mutex<Option<PathBuf>>,
+}
+
+impl Journal {
+    pub fn new() -> Journal {
+        Journal { path: Mutex::new(None) }
+    }
+
+    ///Replays whatever a crashed session left behind on top of the saved file, then keeps appending to the same journal.
+    ///Returns the objects that were recovered.
+    pub fn open(&self, file: &PathBuf, db: &FileDatabase) -> Result<HashSet<RefID>, DBError> {
+        let path = journal_path(file);
+        let mut changed = HashSet::new();
+        if path.exists() {
+            warn!("Found journal {:?}, recovering changes since the last save", path);
+            let contents = std::fs::read(&path).map_err(error_other)?;
+            let mut complete = 0;
+            for line in contents.split_inclusive(|byte| *byte == b'\n') {
+                if !line.ends_with(b"\n") {
+                    warn!("Discarding an entry that was only partly written");
+                    break;
+                }
+                complete += line.len();
+                match serde_json::from_slice::<Entry>(line) {
+                    Ok(entry) => {
+                        for obj in entry.objects {
+                            changed.insert(obj.get_id().clone());
+                            db.replace(obj);
+                        }
+                        for id in entry.deleted {
+                            changed.insert(id);
+                            if let Err(e) = db.remove(&id) {
+                                debug!("Journaled delete of {:?} failed: {:?}", id, e);
+                            }
+                        }
+                    }
+                    Err(e) => error!("Skipping journal entry that couldn't be read: {:?}", e),
+                }
+            }
+            if complete < contents.len() {
+                let file = std::fs::OpenOptions::new().write(true).open(&path).map_err(error_other)?;
+                file.set_len(complete as u64).map_err(error_other)?;
+            }
+        }
+        *self.path.lock().expect("Poisoned mutex") = Some(path);
+        Ok(changed)
+    }
+
+    pub fn record(&self, db: &FileDatabase, action: Action, changed: &HashSet<RefID>) -> Result<(), DBError> {
+        let path = self.path.lock().expect("Poisoned mutex");
+        if let Some(path) = &*path {
+            let mut entry = Entry {
+                action,
+                objects: Vec::new(),
+                deleted: Vec::new(),
+            };
+            for id in changed {
+                match db.get(id, |obj| {
+                    entry.objects.push(obj.clone());
+                    Ok(())
+                }) {
+                    Err(DBError::ObjNotFound) => entry.deleted.push(id.clone()),
+                    result => result?,
+                }
+            }
+            let mut line = serde_json::to_vec(&entry).map_err(error_other)?;
+            line.push(b'\n');
+            let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path).map_err(error_other)?;
+            file.write_all(&line).map_err(error_other)?;
+            file.sync_data().map_err(error_other)?;
+        }
+        Ok(())
+    }
+
+    ///How much has been journaled so far.
+    pub fn len(&self) -> u64 {
+        match &*self.path.lock().expect("Poisoned mutex") {
+            Some(path) => std::fs::metadata(path).map(|meta| meta.len()).unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    ///The first `saved_len` bytes of the journal are in the saved file now, so only what was journaled after them is
+    ///kept.  Saving under a new name moves the journal along with it.
+    pub fn saved(&self, file: &PathBuf, saved_len: u64) -> Result<(), DBError> {
+        let mut path = self.path.lock().expect("Poisoned mutex");
+        if let Some(old_path) = &*path {
+            let mut rest = Vec::new();
+            if old_path.exists() {
+                let mut contents = std::fs::read(old_path).map_err(error_other)?;
+                rest = contents.split_off((saved_len as usize).min(contents.len()));
+            }
+            let new_path = journal_path(file);
+            write_atomic(&new_path, 0, |writer| writer.write_all(&rest).map_err(error_other))?;
+            if *old_path != new_path && old_path.exists() {
+                std::fs::remove_file(old_path).map_err(error_other)?;
+            }
+            *path = Some(new_path);
+        }
+        Ok(())
+    }
+
+    pub fn close(&self) -> Result<(), DBError> {
+        let mut path = self.path.lock().expect("Poisoned mutex");
+        if let Some(old_path) = path.take() {
+            if old_path.exists() {
+                std::fs::remove_file(old_path).map_err(error_other)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,16 +1,31 @@
 use crate::prelude::*;
+use journal::{Action, Journal};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 
 #[cfg(test)]
 mod tests;
 
 mod database;
+mod file_format;
+mod journal;
 mod undo;
 
+pub use database::write_atomic;
+pub use file_format::{FileHeader, ProjectInfo, Units};
+pub use undo::{HistoryEntry, UndoLimits};
+
 pub struct DataManager {
     db: database::FileDatabase,
     pending: undo::PendingEvents,
     undo: Mutex<undo::UndoStack>,
+    journal: Journal,
+    ///The events that had been applied when the file was last saved.  Undoing back to this point makes the file clean again.
+    saved_events: Mutex<HashSet<UndoEventID>>,
+    ///Set when the model changes outside of the undo stack, like when changes are recovered from the journal.
+    untracked_changes: AtomicBool,
+    ///Held for the whole of a save, so saves, closes and autosaves don't write or trim the journal out of order.
+    saving: Mutex<()>,
 }
 
 impl DataManager {
@@ -19,33 +34,164 @@ impl DataManager {
             db: database::FileDatabase::new(),
             pending: undo::PendingEvents::new(),
             undo: Mutex::new(undo::UndoStack::new()),
+            journal: Journal::new(),
+            saved_events: Mutex::new(HashSet::new()),
+            untracked_changes: AtomicBool::new(false),
+            saving: Mutex::new(()),
         }
     }
 
     pub fn open(path: &PathBuf) -> Result<(DataManager, Vec<RefID>), DBError> {
         let db = database::FileDatabase::new();
-        let keys = db.open(path)?;
+        let (keys, history) = db.open(path)?;
+        let mut undo = undo::UndoStack::new();
+        undo.set_history(history);
+        let saved_events = undo.get_event_ids();
         Ok((
             DataManager {
                 db: db,
                 pending: undo::PendingEvents::new(),
-                undo: Mutex::new(undo::UndoStack::new()),
+                undo: Mutex::new(undo),
+                journal: Journal::new(),
+                saved_events: Mutex::new(saved_events),
+                untracked_changes: AtomicBool::new(false),
+                saving: Mutex::new(()),
             },
             keys,
         ))
     }
 
+    ///Saves only committed work.  Events are committed and cancelled under the undo lock, so everything journaled
+    ///before the objects are read is in the file, and everything after stays in the journal.
     pub fn save(&self, path: &PathBuf) -> Result<(), DBError> {
-        self.db.save(path)
+        let _saving = self.saving.lock().expect("Poisoned mutex");
+        let (events, history, objects, journaled, untracked) = {
+            let undo = self.undo.lock().expect("Poisoned mutex");
+            let mut objects = self.db.get_all();
+            self.pending.roll_back(&mut objects)?;
+            (
+                undo.get_event_ids(),
+                undo.get_history(self.db.get_header().history_depth),
+                objects.into_iter().map(|(_, obj)| obj).collect(),
+                self.journal.len(),
+                self.untracked_changes.swap(false, Ordering::SeqCst),
+            )
+        };
+        if let Err(e) = self.db.write(path, &objects, &history) {
+            if untracked {
+                self.untracked_changes.store(true, Ordering::SeqCst);
+            }
+            return Err(e);
+        }
+        *self.saved_events.lock().expect("Poisoned mutex") = events;
+        self.journal.saved(path, journaled)
+    }
+
+    ///Whether the model differs from what was last saved.
+    pub fn is_modified(&self) -> bool {
+        if self.untracked_changes.load(Ordering::SeqCst) {
+            return true;
+        }
+        let events = self.undo.lock().expect("Poisoned mutex").get_event_ids();
+        events != *self.saved_events.lock().expect("Poisoned mutex")
+    }
+
+    ///Starts journaling changes beside the file.  Returns the objects recovered from a previous session that didn't close cleanly.
+    pub fn open_journal(&self, path: &PathBuf) -> Result<HashSet<RefID>, DBError> {
+        let recovered = self.journal.open(path, &self.db)?;
+        if recovered.len() > 0 {
+            self.untracked_changes.store(true, Ordering::SeqCst);
+        }
+        Ok(recovered)
+    }
+
+    pub fn close_journal(&self) -> Result<(), DBError> {
+        self.journal.close()
+    }
+
+    ///Events dropped from the undo stack can't be undone any more.  If getting back to the saved state meant undoing one,
+    ///the file stays modified until it's saved again.
+    fn forget_evicted(&self, stack: &mut undo::UndoStack) {
+        let evicted = stack.take_evicted();
+        if evicted.len() > 0 {
+            let mut saved = self.saved_events.lock().expect("Poisoned mutex");
+            for event_id in evicted {
+                if !saved.remove(&event_id) {
+                    self.untracked_changes.store(true, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+
+    pub fn set_undo_limits(&self, limits: UndoLimits) {
+        let mut stack = self.undo.lock().expect("Poisoned mutex");
+        stack.set_limits(limits);
+        self.forget_evicted(&mut stack);
+    }
+
+    fn record(&self, action: Action, changed: &HashSet<RefID>) {
+        if let Err(e) = self.journal.record(&self.db, action, changed) {
+            error!("Error writing to journal: {:?}", e);
+        }
+    }
+
+    ///Rewrites a file in the encoding given by the destination's extension.
+    pub fn convert_file(from: &PathBuf, to: &PathBuf) -> Result<(), DBError> {
+        let db = database::FileDatabase::new();
+        let (_, history) = db.open(from)?;
+        db.save(to, &history)
+    }
+
+    pub fn set_backup_count(&self, count: usize) {
+        self.db.set_backup_count(count)
+    }
+
+    pub fn get_header(&self) -> FileHeader {
+        self.db.get_header()
+    }
+
+    ///The header isn't part of the undo stack, so changing it leaves the file modified until it's saved.
+    pub fn set_header(&self, header: FileHeader) {
+        if header != self.db.get_header() {
+            self.db.set_header(header);
+            self.untracked_changes.store(true, Ordering::SeqCst);
+        }
     }
 
     pub fn begin_undo_event(&self, user: &UserID, event_id: UndoEventID, desc: String) -> Result<(), DBError> {
         self.pending.begin_event(user, event_id, desc)
     }
 
+    pub fn begin_atomic_event(&self, user: &UserID, event_id: UndoEventID, desc: String) -> Result<(), DBError> {
+        self.pending.begin_event(user, event_id.clone(), desc)?;
+        self.pending.set_atomic(&event_id)
+    }
+
+    pub fn get_event_user(&self, event_id: &UndoEventID) -> Result<UserID, DBError> {
+        self.pending.get_user(event_id)
+    }
+
+    ///Whether the event has begun and hasn't been ended or cancelled yet.
+    pub fn is_pending(&self, event_id: &UndoEventID) -> bool {
+        self.pending.is_pending(event_id)
+    }
+
+    ///The events the user has begun and not yet ended or cancelled.
+    pub fn get_user_events(&self, user: &UserID) -> Vec<UndoEventID> {
+        self.pending.get_user_events(user)
+    }
+
+    pub fn is_atomic(&self, event_id: &UndoEventID) -> bool {
+        self.pending.is_atomic(event_id)
+    }
+
     pub fn end_undo_event(&self, event_id: UndoEventID) -> Result<(), DBError> {
         let mut stack = self.undo.lock().expect("Poisoned mutex");
-        self.pending.end_event(&mut stack, &event_id)
+        if let Some(changed) = self.pending.end_event(&self.db, &mut stack, &event_id)? {
+            self.forget_evicted(&mut stack);
+            self.record(Action::Commit(event_id), &changed);
+        }
+        Ok(())
     }
 
     pub fn suspend_event(&self, event_id: &UndoEventID) -> Result<(), DBError> {
@@ -57,7 +203,10 @@ impl DataManager {
     }
 
     pub fn cancel_event(&self, event_id: &UndoEventID) -> Result<HashSet<RefID>, DBError> {
-        self.pending.cancel_event(&self.db, event_id)
+        let _stack = self.undo.lock().expect("Poisoned mutex");
+        let changed = self.pending.cancel_event(&self.db, event_id)?;
+        self.record(Action::Cancel(event_id.clone()), &changed);
+        Ok(changed)
     }
 
     pub fn add_obj(&self, event_id: &UndoEventID, obj: DataObject) -> Result<(), DBError> {
@@ -89,6 +238,10 @@ impl DataManager {
         self.pending.get_mut_obj(&self.db, event_id, key, callback)
     }
 
+    pub fn set_props(&self, event_id: &UndoEventID, key: &RefID, data: serde_json::Value) -> Result<(), DBError> {
+        self.pending.set_props(&self.db, event_id, key, data)
+    }
+
     pub fn get_mut_obj_no_undo(&self, key: &RefID, callback: impl FnMut(&mut DataObject) -> Result<(), DBError>) -> Result<(), DBError> {
         self.db.get_mut(key, callback)
     }
@@ -97,18 +250,42 @@ impl DataManager {
         self.db.duplicate(key)
     }
 
-    pub fn undo_latest(&self, user: &UserID) -> Result<HashSet<RefID>, DBError> {
+    ///`check` is called for every object the undo would change, and nothing is undone if it fails.
+    pub fn undo_latest(&self, user: &UserID, check: impl Fn(&RefID) -> Result<(), DBError>) -> Result<HashSet<RefID>, DBError> {
+        let mut stack = self.undo.lock().expect("Poisoned mutex");
+        let changed = stack.undo_latest(user, &self.db, check)?;
+        self.record(Action::Undo(user.clone()), &changed);
+        Ok(changed)
+    }
+
+    pub fn get_history(&self) -> Vec<HistoryEntry> {
+        self.undo.lock().expect("Poisoned mutex").get_entries()
+    }
+
+    pub fn undo_to(&self, event_id: &UndoEventID, check: impl Fn(&RefID) -> Result<(), DBError>) -> Result<HashSet<RefID>, DBError> {
+        let mut stack = self.undo.lock().expect("Poisoned mutex");
+        let changed = stack.undo_to(event_id, &self.db, check)?;
+        self.record(Action::UndoTo(event_id.clone()), &changed);
+        Ok(changed)
+    }
+
+    pub fn revert_event(&self, event_id: &UndoEventID, check: impl Fn(&RefID) -> Result<(), DBError>) -> Result<HashSet<RefID>, DBError> {
         let mut stack = self.undo.lock().expect("Poisoned mutex");
-        stack.undo_latest(user, &self.db)
+        let changed = stack.revert_event(event_id, &self.db, &self.pending.get_changed_objects(), check)?;
+        self.record(Action::Revert(event_id.clone()), &changed);
+        Ok(changed)
     }
 
     pub fn take_undo_snapshot(&self, event_id: &UndoEventID, key: &RefID) -> Result<(), DBError> {
         self.pending.take_snapshot(&self.db, event_id, key)
     }
 
-    pub fn redo_latest(&self, user: &UserID) -> Result<HashSet<RefID>, DBError> {
+    pub fn redo_latest(&self, user: &UserID, check: impl Fn(&RefID) -> Result<(), DBError>) -> Result<HashSet<RefID>, DBError> {
         let mut stack = self.undo.lock().expect("Poisoned mutex");
-        stack.redo_latest(user, &self.db)
+        let changed = stack.redo_latest(user, &self.db, check)?;
+        self.forget_evicted(&mut stack);
+        self.record(Action::Redo(user.clone()), &changed);
+        Ok(changed)
     }
 
     pub fn debug_state(&self, output: &mut String) {
@@ -7,6 +7,11 @@ lazy_static! {
     static ref DB: DataManager = DataManager::new();
 }
 
+//Locks are checked further up, in the operation manager
+fn unlocked(_: &RefID) -> Result<(), DBError> {
+    Ok(())
+}
+
 #[test]
 fn test_add() {
     let user = RefID::new_v4();
@@ -68,7 +73,7 @@ fn test_add_undo() {
     let id = obj.get_id().clone();
     DB.add_obj(&event, obj).unwrap();
     DB.end_undo_event(event).unwrap();
-    DB.undo_latest(&user).unwrap();
+    DB.undo_latest(&user, unlocked).unwrap();
     assert!(DB.get_obj(&id, |_| { Ok(()) }).is_err());
 }
 
@@ -85,7 +90,7 @@ fn test_delete_undo() {
     DB.begin_undo_event(&user, event2.clone(), String::from("delete obj")).unwrap();
     DB.delete_obj(&event2, &id).unwrap();
     DB.end_undo_event(event2).unwrap();
-    DB.undo_latest(&user).unwrap();
+    DB.undo_latest(&user, unlocked).unwrap();
     DB.get_obj(&id, |read| {
         let data = read.query_ref::<dyn Store>().unwrap().get_store_data();
         assert_eq!(String::from("some data"), data);
@@ -111,7 +116,7 @@ fn test_modify_undo() {
     })
     .unwrap();
     DB.end_undo_event(event_2).unwrap();
-    DB.undo_latest(&user).unwrap();
+    DB.undo_latest(&user, unlocked).unwrap();
     DB.get_obj(&id, |read| {
         let data = read.query_ref::<dyn Store>().unwrap().get_store_data();
         assert_eq!(String::from("some data"), data);
@@ -137,8 +142,8 @@ fn test_modify_redo() {
     })
     .unwrap();
     DB.end_undo_event(event_2).unwrap();
-    DB.undo_latest(&user).unwrap();
-    DB.redo_latest(&user).unwrap();
+    DB.undo_latest(&user, unlocked).unwrap();
+    DB.redo_latest(&user, unlocked).unwrap();
     DB.get_obj(&id, |read| {
         let data = read.query_ref::<dyn Store>().unwrap().get_store_data();
         assert_eq!(String::from("new data"), data);
@@ -192,3 +197,206 @@ fn test_contest() {
     t_2.join().unwrap();
     t_3.join().unwrap();
 }
+
+#[test]
+fn test_journal_recovery() {
+    let path = PathBuf::from("./test_journal.flx");
+    let journal = journal::journal_path(&path);
+    let user = RefID::new_v4();
+    let obj = Box::new(TestObj::new("saved"));
+    let id = obj.get_id().clone();
+    let other = Box::new(TestObj::new("added"));
+    let other_id = other.get_id().clone();
+    {
+        let data = DataManager::new();
+        data.open_journal(&path).unwrap();
+        let event = UndoEventID::new_v4();
+        data.begin_undo_event(&user, event.clone(), String::from("add obj")).unwrap();
+        data.add_obj(&event, obj).unwrap();
+        data.end_undo_event(event).unwrap();
+        data.save(&path).unwrap();
+        assert_eq!(std::fs::read(&journal).unwrap().len(), 0);
+
+        let event = UndoEventID::new_v4();
+        data.begin_undo_event(&user, event.clone(), String::from("modify obj")).unwrap();
+        data.get_mut_obj(&event, &id, |write| {
+            write.query_mut::<dyn Store>().unwrap().set_store_data(String::from("journaled"));
+            Ok(())
+        })
+        .unwrap();
+        data.add_obj(&event, other).unwrap();
+        data.end_undo_event(event).unwrap();
+        data.undo_latest(&user, unlocked).unwrap();
+        data.redo_latest(&user, unlocked).unwrap();
+        //Crash without closing, halfway through writing another entry
+    }
+    let mut contents = std::fs::read(&journal).unwrap();
+    contents.extend_from_slice(b"{\"action\":{\"Commit\"");
+    std::fs::write(&journal, contents).unwrap();
+
+    let (data, _) = DataManager::open(&path).unwrap();
+    let recovered = data.open_journal(&path).unwrap();
+    assert_eq!(recovered, [id.clone(), other_id.clone()].iter().cloned().collect());
+    data.get_obj(&id, |read| {
+        assert_eq!(read.query_ref::<dyn Store>().unwrap().get_store_data(), String::from("journaled"));
+        Ok(())
+    })
+    .unwrap();
+    data.get_obj(&other_id, |_| Ok(())).unwrap();
+    assert!(std::fs::read(&journal).unwrap().ends_with(b"\n"));
+
+    data.save(&path).unwrap();
+    data.close_journal().unwrap();
+    assert!(!journal.exists());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_save_pending() {
+    let path = PathBuf::from("./test_save_pending.flx");
+    let user = RefID::new_v4();
+    let obj = Box::new(TestObj::new("committed"));
+    let id = obj.get_id().clone();
+    let other = Box::new(TestObj::new("added"));
+    let other_id = other.get_id().clone();
+    let data = DataManager::new();
+    data.open_journal(&path).unwrap();
+    let event = UndoEventID::new_v4();
+    data.begin_undo_event(&user, event.clone(), String::from("add obj")).unwrap();
+    data.add_obj(&event, obj).unwrap();
+    data.end_undo_event(event).unwrap();
+
+    //Work from an event that's still open isn't saved, and is journaled once it's committed
+    let event = UndoEventID::new_v4();
+    data.begin_undo_event(&user, event.clone(), String::from("modify obj")).unwrap();
+    data.get_mut_obj(&event, &id, |write| {
+        write.query_mut::<dyn Store>().unwrap().set_store_data(String::from("pending"));
+        Ok(())
+    })
+    .unwrap();
+    data.add_obj(&event, other).unwrap();
+    data.save(&path).unwrap();
+
+    //As if the commit landed while the file was being written
+    let journaled = data.journal.len();
+    data.end_undo_event(event).unwrap();
+    data.journal.saved(&path, journaled).unwrap();
+
+    let (saved, keys) = DataManager::open(&path).unwrap();
+    assert_eq!(keys, vec![id.clone()]);
+    saved
+        .get_obj(&id, |read| {
+            assert_eq!(read.query_ref::<dyn Store>().unwrap().get_store_data(), String::from("committed"));
+            Ok(())
+        })
+        .unwrap();
+    let recovered = saved.open_journal(&path).unwrap();
+    assert_eq!(recovered, [id.clone(), other_id.clone()].iter().cloned().collect());
+    saved
+        .get_obj(&id, |read| {
+            assert_eq!(read.query_ref::<dyn Store>().unwrap().get_store_data(), String::from("pending"));
+            Ok(())
+        })
+        .unwrap();
+    saved.get_obj(&other_id, |_| Ok(())).unwrap();
+    saved.close_journal().unwrap();
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_saved_history() {
+    for path in &[PathBuf::from("./test_history.flx"), PathBuf::from("./test_history.flxz")] {
+        let user = RefID::new_v4();
+        let obj = Box::new(TestObj::new("first"));
+        let id = obj.get_id().clone();
+        {
+            let data = DataManager::new();
+            let mut header = data.get_header();
+            header.history_depth = 1;
+            data.set_header(header);
+            let event = UndoEventID::new_v4();
+            data.begin_undo_event(&user, event.clone(), String::from("add obj")).unwrap();
+            data.add_obj(&event, obj).unwrap();
+            data.end_undo_event(event).unwrap();
+            let event = UndoEventID::new_v4();
+            data.begin_undo_event(&user, event.clone(), String::from("modify obj")).unwrap();
+            data.get_mut_obj(&event, &id, |write| {
+                write.query_mut::<dyn Store>().unwrap().set_store_data(String::from("second"));
+                Ok(())
+            })
+            .unwrap();
+            data.end_undo_event(event).unwrap();
+            data.save(path).unwrap();
+        }
+
+        let (data, _) = DataManager::open(path).unwrap();
+        assert!(!data.is_modified());
+        data.undo_latest(&user, unlocked).unwrap();
+        data.get_obj(&id, |read| {
+            assert_eq!(read.query_ref::<dyn Store>().unwrap().get_store_data(), String::from("first"));
+            Ok(())
+        })
+        .unwrap();
+        assert!(data.is_modified());
+        //Only the latest event was kept
+        assert!(data.undo_latest(&user, unlocked).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn test_undo_limits() {
+    let data = DataManager::new();
+    let user = RefID::new_v4();
+    let obj = Box::new(TestObj::new("0000"));
+    let id = obj.get_id().clone();
+    let event = UndoEventID::new_v4();
+    data.begin_undo_event(&user, event.clone(), String::from("add obj")).unwrap();
+    data.add_obj(&event, obj).unwrap();
+    data.end_undo_event(event).unwrap();
+    let modify = |values: &[&str]| {
+        let start = data.undo.lock().unwrap().get_size();
+        let event = UndoEventID::new_v4();
+        data.begin_undo_event(&user, event.clone(), String::from("modify obj")).unwrap();
+        for value in values {
+            data.get_mut_obj(&event, &id, |write| {
+                write.query_mut::<dyn Store>().unwrap().set_store_data(String::from(*value));
+                Ok(())
+            })
+            .unwrap();
+        }
+        data.end_undo_event(event).unwrap();
+        data.undo.lock().unwrap().get_size() - start
+    };
+    //Only the first snapshot of each object is kept
+    assert_eq!(modify(&["1111"]), modify(&["2222", "3333", "4444"]));
+    data.undo_latest(&user, unlocked).unwrap();
+    data.get_obj(&id, |read| {
+        assert_eq!(read.query_ref::<dyn Store>().unwrap().get_store_data(), String::from("1111"));
+        Ok(())
+    })
+    .unwrap();
+    data.redo_latest(&user, unlocked).unwrap();
+    assert_eq!(data.get_history().len(), 3);
+
+    data.set_undo_limits(UndoLimits {
+        max_events: Some(2),
+        max_bytes: None,
+    });
+    assert_eq!(data.get_history().len(), 2);
+    //The add can't be undone any more, so the empty file that was never saved can't be reached
+    data.undo_latest(&user, unlocked).unwrap();
+    data.undo_latest(&user, unlocked).unwrap();
+    assert!(data.undo_latest(&user, unlocked).is_err());
+    assert!(data.is_modified());
+    data.redo_latest(&user, unlocked).unwrap();
+    data.redo_latest(&user, unlocked).unwrap();
+
+    data.set_undo_limits(UndoLimits {
+        max_events: None,
+        max_bytes: Some(1),
+    });
+    //The latest event is kept even though it's over the limit on its own
+    assert_eq!(data.get_history().len(), 1);
+    assert!(data.undo.lock().unwrap().get_size() > 1);
+}
@@ -1,28 +1,113 @@
-use super::database::FileDatabase;
+use super::database::{undo_fields, undo_props, FileDatabase};
 use crate::prelude::*;
 use ccl::dhashmap::DHashMap;
+use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 fn get_time() -> u128 {
     SystemTime::now().duration_since(UNIX_EPOCH).expect("Backwards time?").as_millis()
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Change {
-    Add { key: RefID },
-    Modify { obj: DataObject },
-    Delete { obj: DataObject },
+    Add {
+        key: RefID,
+    },
+    Modify {
+        obj: DataObject,
+    },
+    Delete {
+        obj: DataObject,
+    },
+    Props {
+        key: RefID,
+        props: Vec<PropChange>,
+    },
+    ///Fields of the object's serialized form.  Snapshots are narrowed down to these when the event ends, so undoing an edit
+    ///that didn't go through `Data::set_data`, like a move, still leaves other users' changes to the object alone.
+    Fields {
+        key: RefID,
+        fields: Vec<PropChange>,
+    },
 }
 
-#[derive(Debug, Clone)]
+///Splits an object's serialized form into its type tag and its fields.
+pub fn serialized_fields(obj: &DataObject) -> Result<(String, serde_json::Map<String, serde_json::Value>), DBError> {
+    match serde_json::to_value(obj).map_err(error_other)? {
+        serde_json::Value::Object(tagged) => match tagged.into_iter().next() {
+            Some((tag, serde_json::Value::Object(fields))) => Ok((tag, fields)),
+            _ => Err(DBError::Other(String::from("Object isn't serialized as a map"))),
+        },
+        _ => Err(DBError::Other(String::from("Object isn't serialized as a map"))),
+    }
+}
+
+///The fields that differ between two versions of an object, or None if they aren't the same type.
+fn diff_fields(before: &DataObject, after: &DataObject) -> Result<Option<Vec<PropChange>>, DBError> {
+    let (before_tag, before) = serialized_fields(before)?;
+    let (after_tag, after) = serialized_fields(after)?;
+    if before_tag != after_tag || before.len() != after.len() {
+        return Ok(None);
+    }
+    let mut fields = Vec::new();
+    for (name, before_val) in before {
+        match after.get(&name) {
+            Some(after_val) if *after_val == before_val => (),
+            Some(after_val) => fields.push(PropChange {
+                name,
+                before: before_val.to_string(),
+                after: after_val.to_string(),
+            }),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(fields))
+}
+
+///One property set through `Data::set_data`.  Values are kept as JSON text so the binary file format can read them back.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PropChange {
+    pub name: String,
+    pub before: String,
+    pub after: String,
+}
+
+impl Change {
+    ///Snapshots are only measured once, when they're taken, rather than every time the event is.
+    fn estimate_size(&self) -> u64 {
+        let contents = match self {
+            Change::Add { .. } => 0,
+            Change::Modify { obj } | Change::Delete { obj } => bincode::serialized_size(obj).unwrap_or(0),
+            Change::Props { props: fields, .. } | Change::Fields { fields, .. } => fields
+                .iter()
+                .map(|prop| (prop.name.len() + prop.before.len() + prop.after.len()) as u64)
+                .sum(),
+        };
+        std::mem::size_of::<Change>() as u64 + contents
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UndoEvent {
     event_id: UndoEventID,
     user_id: UserID,
     pub changes: Vec<Change>,
     timestamp: u128,
     desc: String,
+    #[serde(skip)]
     nested: usize,
+    #[serde(skip)]
     suspended: usize,
+    ///Atomic events are rolled back as a whole if any operation in them fails.
+    #[serde(skip)]
+    atomic: bool,
+    ///Objects that already have a change in this event, so later modifications don't need another snapshot.
+    #[serde(skip)]
+    touched: HashSet<RefID>,
+    ///Roughly how much memory the changes use, added up as they're made.  Undoing an event takes its size along to the
+    ///event that redoes it, since that holds the same objects.
+    #[serde(skip)]
+    size: u64,
 }
 
 impl UndoEvent {
@@ -35,6 +120,41 @@ impl UndoEvent {
             desc: desc,
             nested: 0,
             suspended: 0,
+            atomic: false,
+            touched: HashSet::new(),
+            size: 0,
+        }
+    }
+
+    fn push(&mut self, change: Change) {
+        if self.suspended > 0 {
+            return;
+        }
+        let first = match &change {
+            Change::Add { key } => self.touched.insert(key.clone()),
+            Change::Modify { obj } | Change::Delete { obj } => self.touched.insert(obj.get_id().clone()),
+            //Property changes don't cover the rest of the object, so they don't stop a later snapshot
+            Change::Props { key, .. } | Change::Fields { key, .. } => !self.touched.contains(key),
+        };
+        //Only the first snapshot is needed to put the object back how it was before the event
+        match change {
+            Change::Modify { .. } | Change::Props { .. } | Change::Fields { .. } if !first => return,
+            _ => {
+                self.size += change.estimate_size();
+                self.changes.push(change);
+            }
+        }
+    }
+
+    fn get_entry(&self) -> HistoryEntry {
+        let mut objects: Vec<RefID> = self.get_changed_objects().into_iter().collect();
+        objects.sort();
+        HistoryEntry {
+            event_id: self.event_id.clone(),
+            user_id: self.user_id.clone(),
+            desc: self.desc.clone(),
+            timestamp: self.timestamp,
+            objects,
         }
     }
 
@@ -45,15 +165,79 @@ impl UndoEvent {
                 Change::Add { key } => results.insert(key.clone()),
                 Change::Modify { obj } => results.insert(obj.get_id().clone()),
                 Change::Delete { obj } => results.insert(obj.get_id().clone()),
+                Change::Props { key, .. } | Change::Fields { key, .. } => results.insert(key.clone()),
             };
         }
         results
     }
+
+    ///Narrows each snapshot down to the fields the event changed.  Snapshots of objects that were deleted later in the event,
+    ///or that can't be compared, are kept whole.
+    fn diff_snapshots(&mut self, db: &FileDatabase) {
+        for change in self.changes.iter_mut() {
+            let fields = match change {
+                Change::Modify { obj: before } => {
+                    let mut fields = None;
+                    let diffed = db.get(before.get_id(), |after| {
+                        fields = diff_fields(before, after)?;
+                        Ok(())
+                    });
+                    if let Err(e) = diffed {
+                        debug!("Keeping snapshot of {:?}: {:?}", before.get_id(), e);
+                    }
+                    fields.map(|fields| (before.get_id().clone(), fields))
+                }
+                _ => None,
+            };
+            if let Some((key, fields)) = fields {
+                *change = Change::Fields { key, fields };
+            }
+        }
+        self.size = self.changes.iter().map(|change| change.estimate_size()).sum();
+    }
+
+    ///Fails if `check` fails for any object the event changed, in a consistent order so the same error comes back each time.
+    fn check_objects(&self, check: impl Fn(&RefID) -> Result<(), DBError>) -> Result<(), DBError> {
+        let mut objects: Vec<RefID> = self.get_changed_objects().into_iter().collect();
+        objects.sort();
+        objects.iter().try_for_each(check)
+    }
+}
+
+///What a client needs to show an event in the undo history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub event_id: UndoEventID,
+    pub user_id: UserID,
+    pub desc: String,
+    pub timestamp: u128,
+    pub objects: Vec<RefID>,
+}
+
+///Caps on the undo stack.  Once either is passed the oldest events are dropped, though the latest event is always kept.
+///By default the stack keeps 1000 events or 256MB, whichever is reached first.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UndoLimits {
+    pub max_events: Option<usize>,
+    pub max_bytes: Option<u64>,
+}
+
+impl Default for UndoLimits {
+    fn default() -> Self {
+        UndoLimits {
+            max_events: Some(1000),
+            max_bytes: Some(256 * 1024 * 1024),
+        }
+    }
 }
 
 pub struct UndoStack {
     stack: VecDeque<UndoEvent>,
     redo_stack: VecDeque<UndoEvent>,
+    limits: UndoLimits,
+    size: u64,
+    ///Events dropped to stay within the limits that haven't been collected with `take_evicted` yet.
+    evicted: Vec<UndoEventID>,
 }
 
 impl UndoStack {
@@ -61,14 +245,164 @@ impl UndoStack {
         UndoStack {
             stack: VecDeque::new(),
             redo_stack: VecDeque::new(),
+            limits: UndoLimits::default(),
+            size: 0,
+            evicted: Vec::new(),
+        }
+    }
+
+    pub fn get_limits(&self) -> UndoLimits {
+        self.limits.clone()
+    }
+
+    pub fn set_limits(&mut self, limits: UndoLimits) {
+        self.limits = limits;
+        self.evict();
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn take_evicted(&mut self) -> Vec<UndoEventID> {
+        std::mem::replace(&mut self.evicted, Vec::new())
+    }
+
+    fn push_event(&mut self, mut event: UndoEvent) {
+        //History read from a file wasn't measured as it was made
+        if event.size == 0 {
+            event.size = event.changes.iter().map(|change| change.estimate_size()).sum();
+        }
+        self.size += event.size;
+        self.stack.push_back(event);
+        self.evict();
+    }
+
+    fn take_event(&mut self, index: usize) -> Option<UndoEvent> {
+        let event = self.stack.remove(index)?;
+        self.size -= event.size;
+        Some(event)
+    }
+
+    fn evict(&mut self) {
+        while self.stack.len() > 1 {
+            let too_many = self.limits.max_events.map_or(false, |max| self.stack.len() > max);
+            let too_big = self.limits.max_bytes.map_or(false, |max| self.size > max);
+            if !too_many && !too_big {
+                break;
+            }
+            if let Some(event) = self.take_event(0) {
+                debug!("Dropping undo event {:?} to stay within {:?}", event.event_id, self.limits);
+                self.evicted.push(event.event_id);
+            }
+        }
+    }
+
+    pub fn get_event_ids(&self) -> HashSet<UndoEventID> {
+        self.stack.iter().map(|event| event.event_id.clone()).collect()
+    }
+
+    ///The most recent events, oldest first, for saving with the file.
+    pub fn get_history(&self, depth: usize) -> Vec<UndoEvent> {
+        let start = self.stack.len().saturating_sub(depth);
+        self.stack.iter().skip(start).cloned().collect()
+    }
+
+    ///Restores history saved with the file.  Anything already on the stack is dropped.
+    pub fn set_history(&mut self, history: Vec<UndoEvent>) {
+        self.stack.clear();
+        self.redo_stack.clear();
+        self.size = 0;
+        for event in history {
+            self.push_event(event);
         }
     }
 
-    pub fn undo_latest(&mut self, user_id: &UserID, db: &FileDatabase) -> Result<HashSet<RefID>, DBError> {
+    ///Every event that can be undone, oldest first.
+    pub fn get_entries(&self) -> Vec<HistoryEntry> {
+        self.stack.iter().map(|event| event.get_entry()).collect()
+    }
+
+    fn find_event(&self, event_id: &UndoEventID) -> Result<usize, DBError> {
+        self.stack
+            .iter()
+            .position(|event| event.event_id == *event_id)
+            .ok_or(DBError::NoUndoEvent)
+    }
+
+    ///Undoes everything after the given event, whoever made it, so the model is back how it was when the event finished.
+    ///Each undone event goes on the redo stack for the user that made it.  Nothing is undone unless `check` passes for
+    ///every object the undone events changed.
+    pub fn undo_to(
+        &mut self,
+        event_id: &UndoEventID,
+        db: &FileDatabase,
+        check: impl Fn(&RefID) -> Result<(), DBError>,
+    ) -> Result<HashSet<RefID>, DBError> {
+        let index = self.find_event(event_id)?;
+        for later in self.stack.iter().skip(index + 1) {
+            later.check_objects(&check)?;
+        }
+        let mut changed = HashSet::new();
+        while self.stack.len() > index + 1 {
+            if let Some(event) = self.take_event(self.stack.len() - 1) {
+                let redo = db.undo(event)?;
+                changed.extend(redo.get_changed_objects());
+                self.redo_stack.push_back(redo);
+            }
+        }
+        Ok(changed)
+    }
+
+    ///Undoes a single event from anywhere in the history, as long as nothing since has changed the same objects.
+    ///`pending` is what events that haven't been committed yet have changed.  They hold snapshots taken after the
+    ///event being reverted, so they count as later changes too.
+    pub fn revert_event(
+        &mut self,
+        event_id: &UndoEventID,
+        db: &FileDatabase,
+        pending: &HashSet<RefID>,
+        check: impl Fn(&RefID) -> Result<(), DBError>,
+    ) -> Result<HashSet<RefID>, DBError> {
+        let index = self.find_event(event_id)?;
+        self.stack[index].check_objects(&check)?;
+        let objects = self.stack[index].get_changed_objects();
+        let mut touched: Vec<RefID> = self
+            .stack
+            .iter()
+            .skip(index + 1)
+            .flat_map(|later| later.get_changed_objects())
+            .chain(pending.iter().cloned())
+            .filter(|id| objects.contains(id))
+            .collect::<HashSet<RefID>>()
+            .into_iter()
+            .collect();
+        if touched.len() > 0 {
+            touched.sort();
+            return Err(DBError::ModifiedSince(touched));
+        }
+        match self.take_event(index) {
+            Some(event) => {
+                let redo = db.undo(event)?;
+                let changed = redo.get_changed_objects();
+                self.redo_stack.push_back(redo);
+                Ok(changed)
+            }
+            None => Err(DBError::NoUndoEvent),
+        }
+    }
+
+    pub fn undo_latest(
+        &mut self,
+        user_id: &UserID,
+        db: &FileDatabase,
+        check: impl Fn(&RefID) -> Result<(), DBError>,
+    ) -> Result<HashSet<RefID>, DBError> {
         for i in (0..self.stack.len()).rev() {
             if let Some(event) = self.stack.get(i) {
                 if event.user_id == *user_id {
-                    if let Some(event) = self.stack.remove(i) {
+                    event.check_objects(&check)?;
+                    if let Some(event) = self.take_event(i) {
                         let redo = db.undo(event)?;
                         let objs = redo.get_changed_objects();
                         self.redo_stack.push_back(redo);
@@ -80,14 +414,20 @@ impl UndoStack {
         Err(DBError::NoUndoEvent)
     }
 
-    pub fn redo_latest(&mut self, user_id: &UserID, db: &FileDatabase) -> Result<HashSet<RefID>, DBError> {
+    pub fn redo_latest(
+        &mut self,
+        user_id: &UserID,
+        db: &FileDatabase,
+        check: impl Fn(&RefID) -> Result<(), DBError>,
+    ) -> Result<HashSet<RefID>, DBError> {
         for i in (0..self.redo_stack.len()).rev() {
             if let Some(event) = self.redo_stack.get(i) {
                 if event.user_id == *user_id {
+                    event.check_objects(&check)?;
                     if let Some(event) = self.redo_stack.remove(i) {
                         let undo = db.undo(event)?;
                         let objs = undo.get_changed_objects();
-                        self.stack.push_back(undo);
+                        self.push_event(undo);
                         return Ok(objs);
                     }
                 }
@@ -97,7 +437,7 @@ impl UndoStack {
     }
 
     pub fn debug_state(&self, output: &mut String) {
-        output.push_str(&format!("Undo Stack:\n{:?}", self.stack));
+        output.push_str(&format!("Undo Stack ({} bytes):\n{:?}", self.size, self.stack));
         output.push_str(&"\n");
         output.push_str(&format!("Redo Stack:\n{:?}", self.redo_stack));
         output.push_str(&"\n");
@@ -127,14 +467,15 @@ impl PendingEvents {
         }
     }
 
+    ///Returns the objects the event changed once the outermost nesting ends and the event is committed.
     #[allow(unused_assignments)]
-    pub fn end_event(&self, undo_stack: &mut UndoStack, event_id: &UndoEventID) -> Result<(), DBError> {
+    pub fn end_event(&self, db: &FileDatabase, undo_stack: &mut UndoStack, event_id: &UndoEventID) -> Result<Option<HashSet<RefID>>, DBError> {
         let mut remove = false;
         match self.events.get_mut(event_id) {
             Some(mut event) => {
                 if event.nested > 0 {
                     event.nested = event.nested - 1;
-                    return Ok(());
+                    return Ok(None);
                 } else {
                     remove = true;
                 }
@@ -145,22 +486,22 @@ impl PendingEvents {
         }
         if remove {
             match self.events.remove(event_id) {
-                Some(event) => {
-                    undo_stack.stack.push_back(event.1);
-                    return Ok(());
+                Some((_, mut event)) => {
+                    event.diff_snapshots(db);
+                    let changed = event.get_changed_objects();
+                    undo_stack.push_event(event);
+                    return Ok(Some(changed));
                 }
                 None => return Err(DBError::NoUndoEvent),
             }
         }
-        Ok(())
+        Ok(None)
     }
 
     pub fn take_snapshot(&self, db: &FileDatabase, event_id: &UndoEventID, obj_id: &RefID) -> Result<(), DBError> {
         match self.events.get_mut(event_id) {
             Some(mut event) => db.get(obj_id, &mut |obj: &DataObject| {
-                if event.suspended == 0 {
-                    event.changes.push(Change::Modify { obj: obj.clone() });
-                }
+                event.push(Change::Modify { obj: obj.clone() });
                 Ok(())
             }),
             None => Err(DBError::NoUndoEvent),
@@ -177,6 +518,57 @@ impl PendingEvents {
         }
     }
 
+    pub fn set_atomic(&self, event_id: &UndoEventID) -> Result<(), DBError> {
+        match self.events.get_mut(event_id) {
+            Some(mut event) => {
+                event.atomic = true;
+                Ok(())
+            }
+            None => Err(DBError::NoUndoEvent),
+        }
+    }
+
+    pub fn get_user(&self, event_id: &UndoEventID) -> Result<UserID, DBError> {
+        match self.events.get(event_id) {
+            Some(event) => Ok(event.user_id.clone()),
+            None => Err(DBError::NoUndoEvent),
+        }
+    }
+
+    pub fn is_pending(&self, event_id: &UndoEventID) -> bool {
+        self.events.contains_key(event_id)
+    }
+
+    pub fn get_user_events(&self, user: &UserID) -> Vec<UndoEventID> {
+        let mut events = Vec::new();
+        for chunk in self.events.chunks() {
+            for (event_id, event) in chunk.iter() {
+                if event.user_id == *user {
+                    events.push(event_id.clone());
+                }
+            }
+        }
+        events
+    }
+
+    ///Every object that an event still in progress has changed.
+    pub fn get_changed_objects(&self) -> HashSet<RefID> {
+        let mut changed = HashSet::new();
+        for chunk in self.events.chunks() {
+            for (_, event) in chunk.iter() {
+                changed.extend(event.get_changed_objects());
+            }
+        }
+        changed
+    }
+
+    pub fn is_atomic(&self, event_id: &UndoEventID) -> bool {
+        match self.events.get(event_id) {
+            Some(event) => event.atomic,
+            None => false,
+        }
+    }
+
     pub fn resume_event(&self, event_id: &UndoEventID) -> Result<(), DBError> {
         match self.events.get_mut(event_id) {
             Some(mut event) => {
@@ -205,9 +597,7 @@ impl PendingEvents {
                 let key = obj.get_id().clone();
                 match db.add(obj) {
                     Ok(()) => {
-                        if event.suspended == 0 {
-                            event.changes.push(Change::Add { key: key });
-                        }
+                        event.push(Change::Add { key: key });
                         Ok(())
                     }
                     Err(e) => Err(e),
@@ -224,9 +614,7 @@ impl PendingEvents {
             let obj = db.remove(key)?;
             match self.events.get_mut(event_id) {
                 Some(mut event) => {
-                    if event.suspended == 0 {
-                        event.changes.push(Change::Delete { obj: obj.clone() });
-                    }
+                    event.push(Change::Delete { obj: obj.clone() });
                     Ok(obj)
                 }
                 None => Err(DBError::NoUndoEvent),
@@ -234,6 +622,46 @@ impl PendingEvents {
         }
     }
 
+    ///Sets properties on an object, recording only the ones that changed so that undoing the event leaves the rest alone.
+    ///If a property can't be read back, a snapshot of the whole object is kept instead.
+    pub fn set_props(&self, db: &FileDatabase, event_id: &UndoEventID, key: &RefID, data: serde_json::Value) -> Result<(), DBError> {
+        let names: Vec<String> = match data.as_object() {
+            Some(map) => map.keys().cloned().collect(),
+            None => return Err(DBError::PropertyNotFound),
+        };
+        match self.events.get_mut(event_id) {
+            Some(mut event) => db.get_mut(key, |obj| {
+                let mut before = Vec::new();
+                for name in &names {
+                    match obj.get_data(name) {
+                        Ok(val) => before.push(val),
+                        Err(_) => {
+                            event.push(Change::Modify { obj: obj.clone() });
+                            return obj.set_data(data.clone());
+                        }
+                    }
+                }
+                let result = obj.set_data(data.clone());
+                let mut props = Vec::new();
+                for (name, before) in names.iter().zip(before) {
+                    let after = obj.get_data(name)?;
+                    if after != before {
+                        props.push(PropChange {
+                            name: name.clone(),
+                            before: before.to_string(),
+                            after: after.to_string(),
+                        });
+                    }
+                }
+                if props.len() > 0 {
+                    event.push(Change::Props { key: key.clone(), props });
+                }
+                result
+            }),
+            None => Err(DBError::NoUndoEvent),
+        }
+    }
+
     pub fn get_mut_obj(
         &self,
         db: &FileDatabase,
@@ -244,9 +672,7 @@ impl PendingEvents {
         match self.events.get_mut(&event_id) {
             Some(mut event) => {
                 db.get(key, &mut |obj: &DataObject| {
-                    if event.suspended == 0 {
-                        event.changes.push(Change::Modify { obj: obj.clone() });
-                    }
+                    event.push(Change::Modify { obj: obj.clone() });
                     Ok(())
                 })?;
                 db.get_mut(key, callback)
@@ -255,6 +681,37 @@ impl PendingEvents {
         }
     }
 
+    ///Puts the objects back how they were before any pending event touched them, so only committed work is left.
+    ///The database has to be read before this is called.  Edits take a snapshot before they change anything, so one
+    ///that lands in between is still rolled back here.
+    pub fn roll_back(&self, objects: &mut HashMap<RefID, DataObject>) -> Result<(), DBError> {
+        for chunk in self.events.chunks() {
+            for (_, event) in chunk.iter() {
+                for change in event.changes.iter().rev() {
+                    match change {
+                        Change::Add { key } => {
+                            objects.remove(key);
+                        }
+                        Change::Modify { obj } | Change::Delete { obj } => {
+                            objects.insert(obj.get_id().clone(), obj.clone());
+                        }
+                        Change::Props { key, props } => {
+                            if let Some(obj) = objects.get_mut(key) {
+                                undo_props(obj, props)?;
+                            }
+                        }
+                        Change::Fields { key, fields } => {
+                            if let Some(obj) = objects.get_mut(key) {
+                                undo_fields(obj, fields)?;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn debug_state(&self, output: &mut String) {
         output.push_str(&format!("Pending:\n"));
         for chunk in self.events.chunks() {
@@ -0,0 +1,60 @@
+use crate::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub enum GraphFormat {
+    Dot,
+    Json,
+}
+
+///The type of every object in the graph, keyed by id.  Objects that aren't in the database any more have no entry.
+pub type ObjectTypes = BTreeMap<RefID, String>;
+
+fn get_type<'a>(types: &'a ObjectTypes, id: &RefID) -> &'a str {
+    types.get(id).map_or("Unknown", |type_str| type_str.as_str())
+}
+
+fn node_name(id: &GeometryId) -> String {
+    format!("\"{}:{}\"", id.id, id.index)
+}
+
+///Graphviz output, with each object's results grouped into a cluster.  Edges point from publisher to subscriber.
+pub fn to_dot(edges: &Vec<(GeometryId, GeometryId)>, types: &ObjectTypes) -> String {
+    let mut objects: BTreeMap<&RefID, BTreeSet<&GeometryId>> = BTreeMap::new();
+    for (publisher, sub) in edges {
+        objects.entry(&publisher.id).or_insert_with(BTreeSet::new).insert(publisher);
+        objects.entry(&sub.id).or_insert_with(BTreeSet::new).insert(sub);
+    }
+    let mut output = String::from("digraph deps {\n");
+    for (id, results) in objects {
+        output.push_str(&format!("    subgraph \"cluster_{}\" {{\n", id));
+        output.push_str(&format!("        label=\"{} {}\";\n", get_type(types, id), id));
+        for result in results {
+            output.push_str(&format!("        {} [label=\"{}\"];\n", node_name(result), result.index));
+        }
+        output.push_str("    }\n");
+    }
+    for (publisher, sub) in edges {
+        output.push_str(&format!("    {} -> {};\n", node_name(publisher), node_name(sub)));
+    }
+    output.push_str("}\n");
+    output
+}
+
+pub fn to_json(edges: &Vec<(GeometryId, GeometryId)>, types: &ObjectTypes) -> serde_json::Value {
+    let mut ids = BTreeSet::new();
+    for (publisher, sub) in edges {
+        ids.insert(&publisher.id);
+        ids.insert(&sub.id);
+    }
+    let objects: Vec<serde_json::Value> = ids.into_iter().map(|id| json!({"id": id, "type": types.get(id)})).collect();
+    let edges: Vec<serde_json::Value> = edges
+        .iter()
+        .map(|(publisher, sub)| json!({"publisher": publisher, "subscriber": sub}))
+        .collect();
+    json!({
+        "objects": objects,
+        "edges": edges,
+    })
+}
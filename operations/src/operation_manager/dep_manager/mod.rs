@@ -1,6 +1,11 @@
+mod export;
+
 use crate::prelude::*;
 use ccl::dhashmap::DHashMap;
 use indexmap::IndexSet;
+use std::collections::{BTreeMap, BTreeSet};
+
+pub use export::*;
 
 pub struct DependencyManager {
     pub_subs: DHashMap<GeometryId, HashSet<GeometryId>>,
@@ -13,45 +18,118 @@ impl DependencyManager {
         }
     }
 
-    fn breadth_first_search(&self, id: &GeometryId) -> IndexSet<Reference> {
-        let mut processing = std::collections::VecDeque::new();
-        let mut visited = HashSet::new();
-        let mut result = IndexSet::new();
-        visited.insert(id.clone());
-        processing.push_back(id.clone());
-        while processing.len() > 0 {
-            if let Some(current) = processing.pop_front() {
-                if let Some(sub_set) = self.pub_subs.get(&current) {
-                    for sub in &(*sub_set) {
-                        if let None = visited.get(sub) {
-                            visited.insert(sub.clone());
-                            result.insert(Reference {
-                                owner: sub.clone(),
-                                other: current.clone(),
-                            });
-                            processing.push_back(sub.clone());
-                        }
-                    }
+    fn get_subs(&self, id: &GeometryId) -> Vec<GeometryId> {
+        let mut subs: Vec<GeometryId> = match self.pub_subs.get(id) {
+            Some(set) => set.iter().cloned().collect(),
+            None => Vec::new(),
+        };
+        subs.sort();
+        subs
+    }
+
+    ///Everything reachable from the starting ids.  Subscribers are sorted so the evaluation order doesn't depend on hashing.
+    fn get_reachable(&self, starts: &BTreeSet<GeometryId>) -> BTreeMap<GeometryId, Vec<GeometryId>> {
+        let mut graph = BTreeMap::new();
+        let mut processing: VecDeque<GeometryId> = starts.iter().cloned().collect();
+        while let Some(current) = processing.pop_front() {
+            if !graph.contains_key(&current) {
+                let subs = self.get_subs(&current);
+                processing.extend(subs.iter().cloned());
+                graph.insert(current, subs);
+            }
+        }
+        graph
+    }
+
+    fn get_whole_graph(&self) -> BTreeMap<GeometryId, Vec<GeometryId>> {
+        let mut graph = BTreeMap::new();
+        for chunk in self.pub_subs.chunks() {
+            for (publisher, set) in chunk.iter() {
+                let mut subs: Vec<GeometryId> = set.iter().cloned().collect();
+                subs.sort();
+                for sub in &subs {
+                    graph.entry(sub.clone()).or_insert_with(Vec::new);
                 }
+                graph.insert(publisher.clone(), subs);
+            }
+        }
+        graph
+    }
+
+    fn is_reachable(&self, from: &GeometryId, to: &GeometryId) -> bool {
+        let mut processing = vec![from.clone()];
+        let mut visited = HashSet::new();
+        while let Some(current) = processing.pop() {
+            if current == *to {
+                return true;
+            }
+            if visited.insert(current.clone()) {
+                processing.extend(self.get_subs(&current));
             }
         }
-        result
+        false
     }
 
+    ///Returns every result that needs to be updated when the given results change, in the order they should be updated.
+    ///Results are ordered topologically.  Results that depend on each other are updated as a group, starting from wherever
+    ///the change came in, and each result is updated from a publisher that is already up to date.
     pub fn get_all_deps<T>(&self, ids: T) -> IndexSet<Reference>
     where
         T: IntoIterator<Item = GeometryId>,
     {
-        /*let mut debug = String::new();
-        self.debug_state(&mut debug);
-        println!("{}", debug);*/
-        let mut results: IndexSet<Reference> = IndexSet::new();
-        for id in ids.into_iter() {
-            results.extend(self.breadth_first_search(&id));
+        let starts: BTreeSet<GeometryId> = ids.into_iter().collect();
+        let graph = self.get_reachable(&starts);
+        let mut pubs: HashMap<&GeometryId, Vec<&GeometryId>> = HashMap::new();
+        for (publisher, subs) in &graph {
+            for sub in subs {
+                pubs.entry(sub).or_insert_with(Vec::new).push(publisher);
+            }
+        }
+        let mut processed = HashSet::new();
+        let mut results = IndexSet::new();
+        for component in get_evaluation_order(&graph) {
+            for node in order_component(&component, &graph, &pubs, &starts, &processed) {
+                if !starts.contains(&node) {
+                    let publisher = pubs
+                        .get(&node)
+                        .and_then(|publishers| publishers.iter().find(|publisher| processed.contains(**publisher)));
+                    if let Some(publisher) = publisher {
+                        results.insert(Reference {
+                            owner: node.clone(),
+                            other: (*publisher).clone(),
+                        });
+                    }
+                }
+                processed.insert(node);
+            }
         }
         results
     }
 
+    ///Lists every group of results that depend on each other.
+    pub fn get_cycles(&self) -> Vec<Vec<GeometryId>> {
+        let mut cycles: Vec<Vec<GeometryId>> = strongly_connected(&self.get_whole_graph())
+            .into_iter()
+            .filter(|component| component.len() > 1)
+            .collect();
+        cycles.sort();
+        cycles
+    }
+
+    ///Every subscription as (publisher, subscriber), sorted.
+    pub fn get_edges(&self) -> Vec<(GeometryId, GeometryId)> {
+        let mut edges = Vec::new();
+        for chunk in self.pub_subs.chunks() {
+            for (publisher, set) in chunk.iter() {
+                for sub in set {
+                    edges.push((publisher.clone(), sub.clone()));
+                }
+            }
+        }
+        edges.sort();
+        edges
+    }
+
     pub fn debug_state(&self, output: &mut String) {
         output.push_str(&format!("{:?} Dependencies:\n", self.pub_subs.len()));
         for chunk in self.pub_subs.chunks() {
@@ -61,19 +139,25 @@ impl DependencyManager {
         }
     }
 
-    pub fn register_sub(&self, publisher: &GeometryId, sub: GeometryId) {
+    ///Returns true if the new subscription closes a cycle.  Cycles are allowed, since joined objects refer to each other,
+    ///but they're evaluated as a group.  Subscriptions that already exist aren't checked again.
+    pub fn register_sub(&self, publisher: &GeometryId, sub: GeometryId) -> bool {
         if sub.id != RefID::nil() && *publisher != sub {
-            match self.pub_subs.get_mut(publisher) {
-                Some(mut set) => {
-                    set.insert(sub);
-                }
+            let added = match self.pub_subs.get_mut(publisher) {
+                Some(mut set) => set.insert(sub.clone()),
                 None => {
                     let mut set = HashSet::new();
-                    set.insert(sub);
+                    set.insert(sub.clone());
                     self.pub_subs.insert(publisher.clone(), set);
+                    true
                 }
+            };
+            if added && self.is_reachable(&sub, publisher) {
+                debug!("Subscribing {:?} to {:?} closes a dependency cycle", sub, publisher);
+                return true;
             }
         }
+        false
     }
 
     pub fn delete_sub(&self, publisher: &GeometryId, sub: &GeometryId) {
@@ -94,6 +178,145 @@ impl DependencyManager {
     }
 }
 
+///Tarjan's algorithm, without recursion so long chains of references can't overflow the stack.
+///Each component is sorted.
+fn strongly_connected(graph: &BTreeMap<GeometryId, Vec<GeometryId>>) -> Vec<Vec<GeometryId>> {
+    let mut next_index = 0;
+    let mut indices: HashMap<&GeometryId, usize> = HashMap::new();
+    let mut lowlinks: HashMap<&GeometryId, usize> = HashMap::new();
+    let mut stack: Vec<&GeometryId> = Vec::new();
+    let mut on_stack: HashSet<&GeometryId> = HashSet::new();
+    let mut components = Vec::new();
+    let no_subs = Vec::new();
+    for start in graph.keys() {
+        if indices.contains_key(start) {
+            continue;
+        }
+        let mut work = vec![(start, 0)];
+        while let Some((node, child)) = work.pop() {
+            if child == 0 {
+                indices.insert(node, next_index);
+                lowlinks.insert(node, next_index);
+                next_index += 1;
+                stack.push(node);
+                on_stack.insert(node);
+            }
+            let subs = graph.get(node).unwrap_or(&no_subs);
+            if child < subs.len() {
+                let sub = &subs[child];
+                work.push((node, child + 1));
+                if !indices.contains_key(sub) {
+                    work.push((sub, 0));
+                } else if on_stack.contains(sub) {
+                    let low = lowlinks[node].min(indices[sub]);
+                    lowlinks.insert(node, low);
+                }
+            } else {
+                if lowlinks[node] == indices[node] {
+                    let mut component = Vec::new();
+                    while let Some(member) = stack.pop() {
+                        on_stack.remove(member);
+                        component.push(member.clone());
+                        if member == node {
+                            break;
+                        }
+                    }
+                    component.sort();
+                    components.push(component);
+                }
+                if let Some((parent, _)) = work.last() {
+                    let low = lowlinks[parent].min(lowlinks[node]);
+                    lowlinks.insert(parent, low);
+                }
+            }
+        }
+    }
+    components
+}
+
+///Orders the groups of results topologically, a level at a time, with ties broken by id.
+fn get_evaluation_order(graph: &BTreeMap<GeometryId, Vec<GeometryId>>) -> Vec<Vec<GeometryId>> {
+    let components = strongly_connected(graph);
+    let mut component_of = HashMap::new();
+    for (i, component) in components.iter().enumerate() {
+        for node in component {
+            component_of.insert(node, i);
+        }
+    }
+    let mut edges = vec![BTreeSet::new(); components.len()];
+    let mut in_degree = vec![0; components.len()];
+    for (publisher, subs) in graph {
+        for sub in subs {
+            let from = component_of[publisher];
+            let to = component_of[sub];
+            if from != to && edges[from].insert(to) {
+                in_degree[to] += 1;
+            }
+        }
+    }
+    let mut ready: BTreeSet<(&GeometryId, usize)> = BTreeSet::new();
+    for (i, component) in components.iter().enumerate() {
+        if in_degree[i] == 0 {
+            ready.insert((&component[0], i));
+        }
+    }
+    let mut order = Vec::with_capacity(components.len());
+    while ready.len() > 0 {
+        let mut next = BTreeSet::new();
+        for (_, i) in ready {
+            for to in &edges[i] {
+                in_degree[*to] -= 1;
+                if in_degree[*to] == 0 {
+                    next.insert((&components[*to][0], *to));
+                }
+            }
+            order.push(i);
+        }
+        ready = next;
+    }
+    order.into_iter().map(|i| components[i].clone()).collect()
+}
+
+///Within a cycle, start from the results that changed or that have an up to date publisher outside of the cycle,
+///and work outwards from there.
+fn order_component(
+    component: &Vec<GeometryId>,
+    graph: &BTreeMap<GeometryId, Vec<GeometryId>>,
+    pubs: &HashMap<&GeometryId, Vec<&GeometryId>>,
+    starts: &BTreeSet<GeometryId>,
+    processed: &HashSet<GeometryId>,
+) -> Vec<GeometryId> {
+    if component.len() == 1 {
+        return component.clone();
+    }
+    let members: HashSet<&GeometryId> = component.iter().collect();
+    let mut processing: VecDeque<&GeometryId> = component
+        .iter()
+        .filter(|node| {
+            starts.contains(*node)
+                || pubs
+                    .get(*node)
+                    .map_or(false, |publishers| publishers.iter().any(|publisher| processed.contains(*publisher)))
+        })
+        .collect();
+    if processing.len() == 0 {
+        processing.push_back(&component[0]);
+    }
+    let mut visited: HashSet<&GeometryId> = processing.iter().cloned().collect();
+    let mut order = Vec::with_capacity(component.len());
+    while let Some(current) = processing.pop_front() {
+        order.push(current.clone());
+        if let Some(subs) = graph.get(current) {
+            for sub in subs {
+                if members.contains(sub) && visited.insert(sub) {
+                    processing.push_back(sub);
+                }
+            }
+        }
+    }
+    order
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -140,17 +363,17 @@ mod tests {
     fn test_get_all_deps() {
         let deps = DependencyManager::new();
         let a = RefID::new_v4();
-        let a_0 = GeometryId::new(a.clone(), 0);
-        let a_1 = GeometryId::new(a.clone(), 1);
-        let a_2 = GeometryId::new(a.clone(), 2);
+        let a_0 = GeometryId::new(a.clone(), RefIndex::Sub(0));
+        let a_1 = GeometryId::new(a.clone(), RefIndex::Sub(1));
+        let a_2 = GeometryId::new(a.clone(), RefIndex::Sub(2));
         let b = RefID::new_v4();
-        let b_0 = GeometryId::new(b.clone(), 0);
-        let b_1 = GeometryId::new(b.clone(), 1);
-        let b_2 = GeometryId::new(b.clone(), 2);
+        let b_0 = GeometryId::new(b.clone(), RefIndex::Sub(0));
+        let b_1 = GeometryId::new(b.clone(), RefIndex::Sub(1));
+        let b_2 = GeometryId::new(b.clone(), RefIndex::Sub(2));
         let c = RefID::new_v4();
-        let c_0 = GeometryId::new(c.clone(), 0);
-        let c_1 = GeometryId::new(c.clone(), 1);
-        let c_2 = GeometryId::new(c.clone(), 2);
+        let c_0 = GeometryId::new(c.clone(), RefIndex::Sub(0));
+        let c_1 = GeometryId::new(c.clone(), RefIndex::Sub(1));
+        let c_2 = GeometryId::new(c.clone(), RefIndex::Sub(2));
 
         deps.register_sub(&a_0, b_0.clone());
         deps.register_sub(&a_1, c_2.clone());
@@ -175,4 +398,52 @@ mod tests {
         ];
         assert!(deps_equals(results, answer));
     }
+
+    #[test]
+    fn test_cycles() {
+        let deps = DependencyManager::new();
+        let a = GeometryId::new(RefID::new_v4(), RefIndex::Sub(0));
+        let b = GeometryId::new(RefID::new_v4(), RefIndex::Sub(0));
+        let c = GeometryId::new(RefID::new_v4(), RefIndex::Sub(0));
+        let d = GeometryId::new(RefID::new_v4(), RefIndex::Sub(0));
+
+        assert!(!deps.register_sub(&a, b.clone()));
+        assert!(!deps.register_sub(&b, c.clone()));
+        assert!(!deps.register_sub(&c, d.clone()));
+        assert_eq!(deps.get_cycles().len(), 0);
+
+        assert!(deps.register_sub(&c, a.clone()));
+        assert!(!deps.register_sub(&c, a.clone()));
+        let mut ring = vec![a.clone(), b.clone(), c.clone()];
+        ring.sort();
+        assert_eq!(deps.get_cycles(), vec![ring]);
+
+        deps.delete_sub(&c, &a);
+        assert_eq!(deps.get_cycles().len(), 0);
+    }
+
+    #[test]
+    fn test_ring_order() {
+        let deps = DependencyManager::new();
+        let ids: Vec<GeometryId> = (0..6).map(|_| GeometryId::new(RefID::new_v4(), RefIndex::Sub(0))).collect();
+        for i in 0..ids.len() {
+            let next = &ids[(i + 1) % ids.len()];
+            deps.register_sub(&ids[i], next.clone());
+            deps.register_sub(next, ids[i].clone());
+        }
+        let outside = GeometryId::new(RefID::new_v4(), RefIndex::Sub(0));
+        deps.register_sub(&ids[5], outside.clone());
+
+        for start in &ids {
+            let results = deps.get_all_deps(vec![start.clone()]);
+            assert_eq!(results.len(), ids.len());
+            let mut updated = set![start.clone()];
+            for refer in &results {
+                assert!(updated.contains(&refer.other));
+                assert!(updated.insert(refer.owner.clone()));
+            }
+            assert_eq!(results.get_index(results.len() - 1).unwrap().owner, outside);
+            assert_eq!(results, deps.get_all_deps(vec![start.clone()]));
+        }
+    }
 }
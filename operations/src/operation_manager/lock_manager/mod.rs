@@ -0,0 +1,213 @@
+use crate::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Lock {
+    user: UserID,
+    ///Every event of the user's that has changed the object.  It stays locked until all of them have ended.
+    events: HashSet<UndoEventID>,
+}
+
+///A locked object, or just one of its properties.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockInfo {
+    pub key: RefID,
+    pub prop: Option<String>,
+    pub owner: UserID,
+}
+
+#[derive(Default)]
+struct Locks {
+    ///The locks on each object, by the property they cover.  `None` covers the whole object.
+    by_obj: HashMap<RefID, HashMap<Option<String>, Lock>>,
+    by_event: HashMap<UndoEventID, HashSet<(RefID, Option<String>)>>,
+}
+
+impl Locks {
+    ///Whole-object locks get in the way of any other edit to the object, property locks only of edits to the same property.
+    fn check(&self, user: &UserID, id: &RefID, prop: &Option<String>) -> Result<(), DBError> {
+        if let Some(obj_locks) = self.by_obj.get(id) {
+            for (locked, lock) in obj_locks {
+                let overlaps = prop.is_none() || locked.is_none() || locked == prop;
+                if overlaps && lock.user != *user {
+                    return Err(DBError::Locked(format!("{:?} is being edited by {:?}", id, lock.user)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+///Soft locks on the objects users are editing.  The first event to change an object locks it for that event's user,
+///and it stays locked until every event of theirs that changed it ends or is cancelled.  Until then, events from other
+///users can't change it.  Setting properties only locks those properties, so other users can still set the rest.
+pub struct LockManager {
+    locks: Mutex<Locks>,
+}
+
+impl LockManager {
+    pub fn new() -> LockManager {
+        LockManager {
+            locks: Mutex::new(Locks::default()),
+        }
+    }
+
+    ///Locks the whole object, or just one property of it.  Returns true if it wasn't locked already, so clients need to be told.
+    pub fn lock(&self, user: &UserID, event: &UndoEventID, id: &RefID, prop: Option<&str>) -> Result<bool, DBError> {
+        let prop = prop.map(String::from);
+        let mut locks = self.locks.lock().expect("Poisoned mutex");
+        locks.check(user, id, &prop)?;
+        let obj_locks = locks.by_obj.entry(id.clone()).or_insert_with(HashMap::new);
+        let added = match obj_locks.get_mut(&prop) {
+            Some(lock) => {
+                lock.events.insert(event.clone());
+                false
+            }
+            None => {
+                let mut events = HashSet::new();
+                events.insert(event.clone());
+                obj_locks.insert(prop.clone(), Lock { user: user.clone(), events });
+                true
+            }
+        };
+        locks
+            .by_event
+            .entry(event.clone())
+            .or_insert_with(HashSet::new)
+            .insert((id.clone(), prop));
+        Ok(added)
+    }
+
+    ///Fails if someone else has the object or any of its properties locked, without locking it.
+    pub fn check(&self, user: &UserID, id: &RefID) -> Result<(), DBError> {
+        self.locks.lock().expect("Poisoned mutex").check(user, id, &None)
+    }
+
+    ///Lets go of everything the event locked, returning the objects and properties that no other event is holding on to.
+    pub fn release_event(&self, event: &UndoEventID) -> Vec<(RefID, Option<String>)> {
+        let mut locks = self.locks.lock().expect("Poisoned mutex");
+        let mut released = Vec::new();
+        if let Some(targets) = locks.by_event.remove(event) {
+            for (id, prop) in targets {
+                let unused = match locks.by_obj.get_mut(&id) {
+                    Some(obj_locks) => {
+                        let unused = match obj_locks.get_mut(&prop) {
+                            Some(lock) => {
+                                lock.events.remove(event);
+                                lock.events.is_empty()
+                            }
+                            None => false,
+                        };
+                        if unused {
+                            obj_locks.remove(&prop);
+                        }
+                        unused
+                    }
+                    None => false,
+                };
+                if locks.by_obj.get(&id).map(|obj_locks| obj_locks.is_empty()).unwrap_or(false) {
+                    locks.by_obj.remove(&id);
+                }
+                if unused {
+                    released.push((id, prop));
+                }
+            }
+        }
+        released
+    }
+
+    ///Releases everything a user has locked, like when they leave without finishing their events.
+    pub fn release_user(&self, user: &UserID) -> Vec<(RefID, Option<String>)> {
+        let events: Vec<UndoEventID> = {
+            let locks = self.locks.lock().expect("Poisoned mutex");
+            locks
+                .by_obj
+                .values()
+                .flat_map(|obj_locks| obj_locks.values())
+                .filter(|lock| lock.user == *user)
+                .flat_map(|lock| lock.events.iter().cloned())
+                .collect::<HashSet<UndoEventID>>()
+                .into_iter()
+                .collect()
+        };
+        let mut released = Vec::new();
+        for event in events {
+            released.extend(self.release_event(&event));
+        }
+        released
+    }
+
+    pub fn get_locks(&self) -> Vec<LockInfo> {
+        let locks = self.locks.lock().expect("Poisoned mutex");
+        locks
+            .by_obj
+            .iter()
+            .flat_map(|(id, obj_locks)| {
+                obj_locks.iter().map(move |(prop, lock)| LockInfo {
+                    key: id.clone(),
+                    prop: prop.clone(),
+                    owner: lock.user.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locks() {
+        let locks = LockManager::new();
+        let (user_1, user_2) = (UserID::new_v4(), UserID::new_v4());
+        let (event_1, event_2, event_3) = (UndoEventID::new_v4(), UndoEventID::new_v4(), UndoEventID::new_v4());
+        let (id_1, id_2) = (RefID::new_v4(), RefID::new_v4());
+        assert_eq!(locks.lock(&user_1, &event_1, &id_1, None), Ok(true));
+        assert_eq!(locks.lock(&user_1, &event_1, &id_1, None), Ok(false));
+        //The same user can keep editing from another event, which holds on to the lock too
+        assert_eq!(locks.lock(&user_1, &event_3, &id_1, None), Ok(false));
+        assert!(locks.lock(&user_2, &event_2, &id_1, None).is_err());
+        assert!(locks.lock(&user_2, &event_2, &id_1, Some("Width")).is_err());
+        assert!(locks.check(&user_2, &id_1).is_err());
+        assert_eq!(locks.check(&user_1, &id_1), Ok(()));
+        assert_eq!(locks.lock(&user_2, &event_2, &id_2, None), Ok(true));
+        let owner = |id: &RefID| locks.get_locks().into_iter().find(|info| info.key == *id).map(|info| info.owner);
+        assert_eq!(owner(&id_1), Some(user_1));
+        assert_eq!(locks.get_locks().len(), 2);
+
+        assert_eq!(locks.release_event(&event_1), vec![]);
+        assert!(locks.lock(&user_2, &event_2, &id_1, None).is_err());
+        assert_eq!(locks.release_event(&event_3), vec![(id_1, None)]);
+        assert_eq!(locks.lock(&user_2, &event_2, &id_1, None), Ok(true));
+        let mut released = locks.release_user(&user_2);
+        released.sort();
+        let mut expected = vec![(id_1, None), (id_2, None)];
+        expected.sort();
+        assert_eq!(released, expected);
+        assert_eq!(locks.get_locks().len(), 0);
+    }
+
+    #[test]
+    fn test_prop_locks() {
+        let locks = LockManager::new();
+        let (user_1, user_2) = (UserID::new_v4(), UserID::new_v4());
+        let (event_1, event_2) = (UndoEventID::new_v4(), UndoEventID::new_v4());
+        let id = RefID::new_v4();
+        assert_eq!(locks.lock(&user_1, &event_1, &id, Some("Width")), Ok(true));
+        //Other properties are still free, but the same one and the whole object aren't
+        assert_eq!(locks.lock(&user_2, &event_2, &id, Some("Height")), Ok(true));
+        assert!(locks.lock(&user_2, &event_2, &id, Some("Width")).is_err());
+        assert!(locks.lock(&user_2, &event_2, &id, None).is_err());
+        assert!(locks.check(&user_2, &id).is_err());
+        assert_eq!(locks.get_locks().len(), 2);
+
+        assert_eq!(locks.release_event(&event_1), vec![(id, Some(String::from("Width")))]);
+        assert!(locks.lock(&user_1, &event_1, &id, None).is_err());
+        assert_eq!(locks.lock(&user_2, &event_2, &id, None), Ok(true));
+        assert!(locks.lock(&user_1, &event_1, &id, Some("Width")).is_err());
+        assert_eq!(locks.release_user(&user_2).len(), 2);
+        assert_eq!(locks.get_locks().len(), 0);
+    }
+}
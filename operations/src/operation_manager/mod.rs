@@ -1,5 +1,10 @@
 mod data_manager;
 mod dep_manager;
+mod lock_manager;
+mod outbox;
+mod presence;
+mod replay;
+mod spatial_index;
 #[cfg(test)]
 mod tests;
 
@@ -8,11 +13,37 @@ use ccl::dhashmap::DHashMap;
 use crossbeam_channel::Sender;
 use data_manager::*;
 use dep_manager::*;
+use lock_manager::*;
+use outbox::*;
+use presence::*;
+use replay::*;
+use spatial_index::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub use data_manager::{write_atomic, FileHeader, HistoryEntry, ProjectInfo, UndoLimits, Units};
+pub use dep_manager::GraphFormat;
+pub use lock_manager::LockInfo;
+pub use outbox::{OverflowPolicy, QueueStats};
+pub use presence::UserPresence;
+
+pub fn convert_file(from: &PathBuf, to: &PathBuf) -> Result<(), DBError> {
+    DataManager::convert_file(from, to)
+}
 
 pub struct OperationManager {
     data: DataManager,
     deps: DependencyManager,
-    pub updates: DHashMap<UserID, Sender<UpdateMsg>>,
+    spatial: SpatialIndex,
+    locks: LockManager,
+    presence: PresenceManager,
+    replay: ReplayBuffer,
+    pub updates: DHashMap<UserID, Outbox>,
+    ///The autosave interval, and when the next autosave is due.
+    autosave: Mutex<Option<(Duration, Instant)>>,
+    ///Whether clients were last told the file has unsaved changes.
+    modified: AtomicBool,
 }
 
 impl OperationManager {
@@ -20,9 +51,18 @@ impl OperationManager {
         let ops = OperationManager {
             data: DataManager::new(),
             deps: DependencyManager::new(),
+            spatial: SpatialIndex::new(),
+            locks: LockManager::new(),
+            presence: PresenceManager::new(),
+            replay: ReplayBuffer::new(),
             updates: DHashMap::default(),
+            autosave: Mutex::new(None),
+            modified: AtomicBool::new(false),
         };
-        ops.updates.insert(user, sender);
+        if let Err(e) = ops.connect(&user, sender, None) {
+            error!("Error connecting user {:?}: {:?}", user, e);
+        }
+        ops.presence.join(&user);
         ops
     }
 
@@ -32,14 +72,22 @@ impl OperationManager {
         let ops = OperationManager {
             data: data,
             deps: DependencyManager::new(),
+            spatial: SpatialIndex::new(),
+            locks: LockManager::new(),
+            presence: PresenceManager::new(),
+            replay: ReplayBuffer::new(),
             updates: DHashMap::default(),
+            autosave: Mutex::new(None),
+            modified: AtomicBool::new(false),
         };
-        ops.updates.insert(user.clone(), sender);
+        ops.connect(&user, sender, None)?;
+        ops.presence.join(&user);
         keys.par_iter().for_each(|key| {
             if let Err(e) = ops.data.get_mut_obj_no_undo(key, |obj| {
                 ops.register_deps(&obj);
                 match obj.update() {
                     Ok(msg) => {
+                        ops.update_spatial(obj);
                         if let Err(e) = ops.send(msg, Some(&user)) {
                             error!("Error sending update: {:?}", e);
                         }
@@ -54,39 +102,295 @@ impl OperationManager {
         Ok(ops)
     }
 
+    ///Sends to everyone except the user the message is about.
+    fn send_others(&self, msg: UpdateMsg, from: &UserID) -> Result<(), DBError> {
+        let mut to_remove = Vec::new();
+        for chunk in self.updates.chunks() {
+            for (key, upd) in chunk.iter() {
+                if key != from {
+                    if let Err(e) = upd.send(msg.clone()) {
+                        debug!("Not sending to {:?} any more: {:?}", key, e);
+                        to_remove.push(key.clone());
+                    }
+                }
+            }
+        }
+        for delete in to_remove {
+            self.updates.remove(&delete);
+        }
+        Ok(())
+    }
+
+    fn presence_msg(presence: UserPresence) -> UpdateMsg {
+        UpdateMsg::Presence {
+            user: presence.user,
+            name: presence.name,
+            color: presence.color,
+        }
+    }
+
+    ///Tells a user who else is here, where their cursors are and what they have selected.
+    fn send_presence_to(&self, user: &UserID) -> Result<(), DBError> {
+        for other in self.presence.get_users() {
+            if other.user != *user {
+                self.send(OperationManager::presence_msg(other.clone()), Some(user))?;
+                if let Some(pos) = other.cursor {
+                    self.send(UpdateMsg::Cursor { user: other.user, pos }, Some(user))?;
+                }
+                if other.selection.len() > 0 {
+                    self.send(
+                        UpdateMsg::Selection {
+                            user: other.user,
+                            ids: other.selection,
+                        },
+                        Some(user),
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///Registers where to send a user's updates and tells them where the broadcasts continue from.
+    ///Returns true if everything they missed since `since` could be replayed, otherwise they need a full resync.
+    fn connect(&self, user: &UserID, sender: Sender<UpdateMsg>, since: Option<(SessionID, u64)>) -> Result<bool, DBError> {
+        let session = self.replay.get_session();
+        self.replay.connect(since, |last_seq, missed| {
+            self.updates.insert(user.clone(), Outbox::new(sender));
+            match (since, missed) {
+                (Some((_, seq)), Some(missed)) => {
+                    self.send(UpdateMsg::Synced { session, seq, full: false }, Some(user))?;
+                    for msg in missed {
+                        self.send(msg, Some(user))?;
+                    }
+                    Ok(true)
+                }
+                _ => {
+                    self.send(
+                        UpdateMsg::Synced {
+                            session,
+                            seq: last_seq,
+                            full: true,
+                        },
+                        Some(user),
+                    )?;
+                    Ok(false)
+                }
+            }
+        })
+    }
+
+    ///Adds another user to an open file, telling them who else is here and telling everyone else about them.
+    pub fn add_user(&self, user: UserID, sender: Sender<UpdateMsg>) -> Result<(), DBError> {
+        self.connect(&user, sender, None)?;
+        let presence = self.presence.join(&user);
+        self.send_presence_to(&user)?;
+        self.send_others(OperationManager::presence_msg(presence), &user)
+    }
+
+    ///Reattaches a user whose connection dropped.  If every broadcast after `last_seq` is still buffered, only those
+    ///are resent.  Otherwise the user is sent the whole file again.
+    pub fn reconnect(&self, user: UserID, sender: Sender<UpdateMsg>, session: SessionID, last_seq: u64) -> Result<(), DBError> {
+        let caught_up = self.connect(&user, sender, Some((session, last_seq)))?;
+        if !self.presence.get_users().iter().any(|presence| presence.user == user) {
+            let presence = self.presence.join(&user);
+            self.send_others(OperationManager::presence_msg(presence), &user)?;
+        }
+        self.send_presence_to(&user)?;
+        if !caught_up {
+            self.update_all(Some(&user))?;
+        }
+        Ok(())
+    }
+
+    pub fn remove_user(&self, user: &UserID) -> Result<(), DBError> {
+        self.updates.remove(user);
+        if self.presence.leave(user) {
+            self.send_others(UpdateMsg::UserLeft { user: user.clone() }, user)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_user_info(&self, user: &UserID, name: String, color: String) -> Result<(), DBError> {
+        let presence = self.presence.set_info(user, name, color)?;
+        self.send_others(OperationManager::presence_msg(presence), user)
+    }
+
+    ///Changes too soon after the last one are held back, and go out from `flush_updates`.
+    pub fn set_cursor(&self, user: &UserID, pos: Point3f) -> Result<(), DBError> {
+        let live = self.presence.set_cursor(user, pos, Instant::now())?;
+        self.send_live(user, live)
+    }
+
+    ///Changes too soon after the last one are held back, and go out from `flush_updates`.
+    pub fn set_selection(&self, user: &UserID, ids: Vec<RefID>) -> Result<(), DBError> {
+        let live = self.presence.set_selection(user, ids, Instant::now())?;
+        self.send_live(user, live)
+    }
+
+    fn send_live(&self, user: &UserID, live: Live) -> Result<(), DBError> {
+        match live {
+            Live::Now => self.send_presence(self.presence.take_changes(user, Instant::now()), user),
+            Live::Held => Ok(()),
+        }
+    }
+
+    fn send_presence(&self, msgs: Vec<UpdateMsg>, user: &UserID) -> Result<(), DBError> {
+        for msg in msgs {
+            self.send_others(msg, user)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_users(&self) -> Vec<UserPresence> {
+        self.presence.get_users()
+    }
+
+    pub fn set_overflow_policy(&self, user: &UserID, policy: OverflowPolicy) -> Result<(), DBError> {
+        match self.updates.get(user) {
+            Some(upd) => Ok(upd.set_policy(policy)),
+            None => Err(DBError::UserNotFound),
+        }
+    }
+
+    ///Sends a user any updates that were held back while their channel was full, and sends everyone else the user's
+    ///cursor and selection changes that were held back to keep to the relay rate.
+    pub fn flush_updates(&self, user: &UserID) -> Result<(), DBError> {
+        match self.updates.get(user) {
+            Some(upd) => upd.flush()?,
+            None => return Err(DBError::UserNotFound),
+        }
+        self.send_presence(self.presence.take_held(user, Instant::now()), user)
+    }
+
+    pub fn get_queue_stats(&self) -> Vec<QueueStats> {
+        let mut stats = Vec::new();
+        for chunk in self.updates.chunks() {
+            for (user, upd) in chunk.iter() {
+                stats.push(upd.stats(user));
+            }
+        }
+        stats
+    }
+
     pub fn send(&self, msg: UpdateMsg, only_to: Option<&UserID>) -> Result<(), DBError> {
         if let Some(user) = only_to {
-            if let Some(upd) = self.updates.get(user) {
-                upd.send(msg).map_err(error_other)
-            } else {
-                Err(DBError::UserNotFound)
+            let res = match self.updates.get(user) {
+                Some(upd) => upd.send(msg),
+                None => Err(DBError::UserNotFound),
+            };
+            if let Err(DBError::QueueFull) = res {
+                self.updates.remove(user);
             }
+            res
         } else {
-            let mut to_remove = Vec::new();
-            for chunk in self.updates.chunks() {
-                for (key, upd) in chunk.iter() {
-                    if let Err(_) = upd.send(msg.clone()) {
-                        to_remove.push(key.clone());
+            self.replay.broadcast(msg, |msg| {
+                let mut to_remove = Vec::new();
+                for chunk in self.updates.chunks() {
+                    for (key, upd) in chunk.iter() {
+                        if let Err(e) = upd.send(msg.clone()) {
+                            debug!("Not sending to {:?} any more: {:?}", key, e);
+                            to_remove.push(key.clone());
+                        }
                     }
                 }
-            }
-            for delete in to_remove {
-                self.updates.remove(&delete);
-            }
+                for delete in to_remove {
+                    self.updates.remove(&delete);
+                }
+            });
             Ok(())
         }
     }
 
     pub fn save(&self, path: &PathBuf) -> Result<(), DBError> {
-        self.data.save(path)
+        self.data.save(path)?;
+        self.check_modified()
+    }
+
+    pub fn is_modified(&self) -> bool {
+        self.data.is_modified()
+    }
+
+    ///Tells every client when the file goes from saved to unsaved, or back again.
+    fn check_modified(&self) -> Result<(), DBError> {
+        let modified = self.data.is_modified();
+        if self.modified.swap(modified, Ordering::SeqCst) != modified {
+            self.send(UpdateMsg::Modified { modified }, None)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_autosave(&self, interval: Option<Duration>) {
+        *self.autosave.lock().expect("Poisoned mutex") = interval.map(|interval| (interval, Instant::now() + interval));
+    }
+
+    ///Whether an autosave is due at `now`.  If it is, the next one is due a full interval later.
+    pub fn is_autosave_due(&self, now: Instant) -> bool {
+        match &mut *self.autosave.lock().expect("Poisoned mutex") {
+            Some((interval, due)) if *due <= now => {
+                *due = now + *interval;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn set_backup_count(&self, count: usize) {
+        self.data.set_backup_count(count)
+    }
+
+    ///Starts journaling changes beside the file, first replaying anything left behind by a session that crashed.
+    pub fn open_journal(&self, path: &PathBuf) -> Result<(), DBError> {
+        let recovered = self.data.open_journal(path)?;
+        if recovered.len() > 0 {
+            info!("Recovered {:?} objects from the journal", recovered.len());
+            for id in &recovered {
+                if let Err(e) = self.add_deps(id) {
+                    debug!("Not registering deps for recovered object {:?}: {:?}", id, e);
+                }
+            }
+            self.update_all_deps(recovered)?;
+        }
+        self.check_modified()
+    }
+
+    ///Only call this once the file has been saved, since the journal holds everything since the last save.
+    pub fn close_journal(&self) -> Result<(), DBError> {
+        self.data.close_journal()
     }
 
     pub fn begin_undo_event(&self, user_id: &UserID, event_id: UndoEventID, desc: String) -> Result<(), DBError> {
         self.data.begin_undo_event(user_id, event_id, desc)
     }
 
+    ///Like `begin_undo_event`, except that if anything done in the event fails, everything it did is rolled back and the event
+    ///is discarded.  Nesting a normal event inside an atomic one keeps it atomic.
+    pub fn begin_atomic_event(&self, user_id: &UserID, event_id: UndoEventID, desc: String) -> Result<(), DBError> {
+        self.data.begin_atomic_event(user_id, event_id, desc)
+    }
+
+    ///Whether the event was begun with `begin_atomic_event`.
+    pub fn is_atomic(&self, event_id: &UndoEventID) -> bool {
+        self.data.is_atomic(event_id)
+    }
+
+    ///Called when an operation in the event failed.  Atomic events are cancelled, other events are left open for the client.
+    pub fn abort_event(&self, event_id: &UndoEventID) -> Result<(), DBError> {
+        if self.data.is_atomic(event_id) {
+            warn!("Rolling back atomic event {:?}", event_id);
+            self.cancel_event(event_id)
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn end_undo_event(&self, event: UndoEventID) -> Result<(), DBError> {
-        self.data.end_undo_event(event)
+        self.data.end_undo_event(event.clone())?;
+        //Nested events only finish when the outermost one ends
+        if !self.data.is_pending(&event) {
+            self.unlock_event(&event)?;
+        }
+        self.check_modified()
     }
 
     pub fn suspend_event(&self, event_id: &UndoEventID) -> Result<(), DBError> {
@@ -99,26 +403,106 @@ impl OperationManager {
 
     pub fn cancel_event(&self, event_id: &UndoEventID) -> Result<(), DBError> {
         let set = self.data.cancel_event(event_id)?;
+        self.unlock_event(event_id)?;
         self.update_all_deps(set)
     }
 
+    ///Cancels every event the user left unfinished, like when they close the file part way through an edit.
+    pub fn cancel_user_events(&self, user: &UserID) -> Result<(), DBError> {
+        for event_id in self.data.get_user_events(user) {
+            self.cancel_event(&event_id)?;
+        }
+        Ok(())
+    }
+
     pub fn take_undo_snapshot(&self, event_id: &UndoEventID, key: &RefID) -> Result<(), DBError> {
+        self.lock(event_id, key)?;
         self.data.take_undo_snapshot(event_id, key)
     }
 
+    ///Locks the object for the event's user, or fails with `DBError::Locked` if someone else is editing it.
+    fn lock(&self, event_id: &UndoEventID, key: &RefID) -> Result<(), DBError> {
+        self.lock_prop(event_id, key, None)
+    }
+
+    ///Locks just one property of the object if `prop` is given, which only gets in the way of edits to the same property.
+    fn lock_prop(&self, event_id: &UndoEventID, key: &RefID, prop: Option<&str>) -> Result<(), DBError> {
+        let user = self.data.get_event_user(event_id)?;
+        if self.locks.lock(&user, event_id, key, prop)? {
+            self.send(
+                UpdateMsg::Lock {
+                    key: key.clone(),
+                    prop: prop.map(String::from),
+                    owner: Some(user),
+                },
+                None,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn send_unlocked(&self, released: Vec<(RefID, Option<String>)>) -> Result<(), DBError> {
+        for (key, prop) in released {
+            self.send(UpdateMsg::Lock { key, prop, owner: None }, None)?;
+        }
+        Ok(())
+    }
+
+    fn unlock_event(&self, event_id: &UndoEventID) -> Result<(), DBError> {
+        self.send_unlocked(self.locks.release_event(event_id))
+    }
+
+    ///Frees everything a user had locked, for when they leave in the middle of an event.
+    pub fn release_locks(&self, user: &UserID) -> Result<(), DBError> {
+        self.send_unlocked(self.locks.release_user(user))
+    }
+
+    ///Every locked object or property and the user editing it.
+    pub fn get_locks(&self) -> Vec<LockInfo> {
+        self.locks.get_locks()
+    }
+
+    ///Undoing and redoing fail with `DBError::Locked` if another user is editing any of the objects they'd change.
     pub fn undo_latest(&self, user: &UserID) -> Result<(), DBError> {
-        let set = self.data.undo_latest(user)?;
-        self.update_all_deps(set)
+        let set = self.data.undo_latest(user, |id| self.locks.check(user, id))?;
+        self.update_all_deps(set)?;
+        self.check_modified()
     }
 
     pub fn redo_latest(&self, user: &UserID) -> Result<(), DBError> {
-        let set = self.data.redo_latest(user)?;
-        self.update_all_deps(set)
+        let set = self.data.redo_latest(user, |id| self.locks.check(user, id))?;
+        self.update_all_deps(set)?;
+        self.check_modified()
+    }
+
+    ///The undo history of every user, oldest first.
+    pub fn get_history(&self) -> Vec<HistoryEntry> {
+        self.data.get_history()
+    }
+
+    pub fn undo_to(&self, user: &UserID, event_id: &UndoEventID) -> Result<(), DBError> {
+        let set = self.data.undo_to(event_id, |id| self.locks.check(user, id))?;
+        self.update_all_deps(set)?;
+        self.check_modified()
+    }
+
+    ///Fails with `DBError::ModifiedSince` if a later event, or one still in progress, changed any of the same objects.
+    pub fn revert_event(&self, user: &UserID, event_id: &UndoEventID) -> Result<(), DBError> {
+        let set = self.data.revert_event(event_id, |id| self.locks.check(user, id))?;
+        self.update_all_deps(set)?;
+        self.check_modified()
+    }
+
+    ///Dropping old events to fit new limits can leave the saved state out of reach.
+    pub fn set_undo_limits(&self, limits: UndoLimits) -> Result<(), DBError> {
+        self.data.set_undo_limits(limits);
+        self.check_modified()
     }
 
     pub fn update_all(&self, only_to: Option<&UserID>) -> Result<(), DBError> {
         self.data.iterate_all_mut(&mut |obj: &mut DataObject| {
             let msg = obj.update()?;
+            self.update_spatial(obj);
             self.send(msg, only_to)
         })
     }
@@ -127,7 +511,7 @@ impl OperationManager {
         let mut result = None;
         match self.get_obj(&refer.id, |obj| match obj.query_ref::<dyn ReferTo>() {
             Some(update_from) => {
-                result = update_from.get_result(refer.index);
+                result = update_from.get_result(&refer.index);
                 Ok(())
             }
             None => Err(DBError::ObjLacksTrait),
@@ -143,10 +527,13 @@ impl OperationManager {
             self.data
                 .get_mut_obj_no_undo(&refer.owner.id, |obj| match obj.query_mut::<dyn UpdateFromRefs>() {
                     Some(updatable) => {
-                        updatable.set_associated_geom(refer.owner.index, &result);
+                        updatable.set_associated_geom(&refer.owner.index, &result);
                         let update_msg = obj.update();
                         match update_msg {
-                            Ok(msg) => self.send(msg, None),
+                            Ok(msg) => {
+                                self.update_spatial(obj);
+                                self.send(msg, None)
+                            }
                             Err(DBError::ObjNotFound) => self.send(UpdateMsg::Delete { key: obj.get_id().clone() }, None),
                             Err(e) => Err(e),
                         }
@@ -177,13 +564,11 @@ impl OperationManager {
         for dep_id in deps.into_iter() {
             let for_each = |obj: &mut DataObject| {
                 self.send(obj.update()?, None)?;
+                self.update_spatial(obj);
                 match obj.query_ref::<dyn ReferTo>() {
                     Some(referrable) => {
-                        for i in 0..referrable.get_num_results() {
-                            geom_ids.push(GeometryId {
-                                id: dep_id.clone(),
-                                index: i,
-                            });
+                        for (index, _) in referrable.get_all_results() {
+                            geom_ids.push(GeometryId { id: dep_id.clone(), index });
                         }
                         Ok(())
                     }
@@ -204,6 +589,7 @@ impl OperationManager {
         }
         if to_remove.len() > 0 {
             for delete in &to_remove {
+                self.spatial.remove(delete);
                 self.send(UpdateMsg::Delete { key: delete.clone() }, None)?;
             }
             self.deps.delete_ids(to_remove);
@@ -233,7 +619,15 @@ impl OperationManager {
             let refs = dep_obj.get_refs();
             for ref_opt in refs {
                 if let Some(refer) = ref_opt {
-                    self.deps.register_sub(&refer.other, refer.owner);
+                    if self.deps.register_sub(&refer.other, refer.owner.clone()) {
+                        let msg = UpdateMsg::DepCycle {
+                            publisher: refer.other,
+                            sub: refer.owner,
+                        };
+                        if let Err(e) = self.send(msg, None) {
+                            error!("Error sending dependency cycle: {:?}", e);
+                        }
+                    }
                 }
             }
         }
@@ -250,6 +644,72 @@ impl OperationManager {
         self.deps.delete_sub(publisher, sub);
     }
 
+    fn update_spatial(&self, obj: &DataObject) {
+        if let Some(refer) = obj.query_ref::<dyn ReferTo>() {
+            let results: Vec<RefGeometry> = refer.get_all_results().into_iter().map(|(_, geom)| geom).collect();
+            self.spatial.insert(obj.get_id(), &results);
+        }
+    }
+
+    ///Returns the objects that might have results within tolerance of the point.
+    pub fn get_objs_near(&self, pt: &Point3f, tolerance: WorldCoord) -> HashSet<RefID> {
+        self.spatial.query(pt, tolerance)
+    }
+
+    pub fn get_file_header(&self) -> FileHeader {
+        self.data.get_header()
+    }
+
+    ///The format version and app versions are filled in when the file is saved, so only these can be changed.
+    pub fn set_file_info(&self, units: Units, project: ProjectInfo) -> Result<(), DBError> {
+        let mut header = self.data.get_header();
+        header.units = units;
+        header.project = project;
+        self.data.set_header(header);
+        self.check_modified()
+    }
+
+    ///How many of the latest undo events are saved with the file so they can be undone after it's reopened.  Zero saves none.
+    pub fn set_history_depth(&self, depth: usize) -> Result<(), DBError> {
+        let mut header = self.data.get_header();
+        header.history_depth = depth;
+        self.data.set_header(header);
+        self.check_modified()
+    }
+
+    ///Groups of results that depend on each other, like walls joined in a ring.
+    pub fn get_dep_cycles(&self) -> Vec<Vec<GeometryId>> {
+        self.deps.get_cycles()
+    }
+
+    ///Dumps the dependency graph, labelled with the type of each object.
+    pub fn export_deps(&self, format: GraphFormat) -> Result<serde_json::Value, DBError> {
+        let edges = self.deps.get_edges();
+        let mut types = ObjectTypes::new();
+        for (publisher, sub) in &edges {
+            for id in &[&publisher.id, &sub.id] {
+                if !types.contains_key(*id) {
+                    if let Ok(type_str) = self.get_type_name(id) {
+                        types.insert(**id, type_str);
+                    }
+                }
+            }
+        }
+        match format {
+            GraphFormat::Dot => Ok(json!(to_dot(&edges, &types))),
+            GraphFormat::Json => Ok(to_json(&edges, &types)),
+        }
+    }
+
+    fn get_type_name(&self, id: &RefID) -> Result<String, DBError> {
+        let mut type_str = String::new();
+        self.data.get_obj(id, |obj| {
+            type_str = obj.get_type_name().to_string();
+            Ok(())
+        })?;
+        Ok(type_str)
+    }
+
     pub fn copy_obj(&self, event: &UndoEventID, id: &RefID) -> Result<RefID, DBError> {
         let mut copy = self.data.duplicate_obj(id)?;
         if let Some(updatable) = copy.query_mut::<dyn UpdateFromRefs>() {
@@ -265,21 +725,25 @@ impl OperationManager {
         output.push_str(&"\n");
         self.deps.debug_state(output);
         output.push_str(&"\n");
+        self.spatial.debug_state(output);
+        output.push_str(&"\n");
     }
 
     pub fn add_object(&self, event: &UndoEventID, obj: DataObject) -> Result<(), DBError> {
+        self.lock(event, obj.get_id())?;
         self.register_deps(&obj);
         self.data.add_obj(event, obj)
     }
 
     pub fn delete_obj(&self, event: &UndoEventID, id: &RefID) -> Result<DataObject, DBError> {
+        self.lock(event, id)?;
         let obj = self.data.delete_obj(event, id)?;
+        self.spatial.remove(id);
         self.send(UpdateMsg::Delete { key: *id }, None)?;
         self.update_deps(id)?;
         if let Some(refer_obj) = obj.query_ref::<dyn ReferTo>() {
-            let num_pts = refer_obj.get_num_results();
-            for i in 0..num_pts {
-                self.deps.delete_id(&GeometryId { id: id.clone(), index: i });
+            for (index, _) in refer_obj.get_all_results() {
+                self.deps.delete_id(&GeometryId { id: id.clone(), index });
             }
         }
         Ok(obj)
@@ -291,12 +755,33 @@ impl OperationManager {
         id: &RefID,
         mut callback: impl FnMut(&mut DataObject) -> Result<(), DBError>,
     ) -> Result<(), DBError> {
+        self.lock(event, id)?;
         self.data.get_mut_obj(event, id, |mut obj| {
             callback(&mut obj)?;
             Ok(())
         })
     }
 
+    ///Sets some of an object's properties.  Only those properties are locked, since changes to different properties can be
+    ///merged.  Properties the object can't read back are undone with a snapshot of the whole object, so that locks all of it.
+    pub fn set_obj_props(&self, event: &UndoEventID, id: &RefID, data: serde_json::Value) -> Result<(), DBError> {
+        let names: Vec<String> = match data.as_object() {
+            Some(map) => map.keys().cloned().collect(),
+            None => return Err(DBError::PropertyNotFound),
+        };
+        let readable = names
+            .iter()
+            .all(|name| self.data.get_obj(id, |obj| obj.get_data(name).map(|_| ())).is_ok());
+        if readable {
+            for name in &names {
+                self.lock_prop(event, id, Some(name))?;
+            }
+        } else {
+            self.lock(event, id)?;
+        }
+        self.data.set_props(event, id, data)
+    }
+
     pub fn get_obj(&self, id: &RefID, callback: impl FnMut(&DataObject) -> Result<(), DBError>) -> Result<(), DBError> {
         self.data.get_obj(id, callback)
     }
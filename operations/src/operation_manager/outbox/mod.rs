@@ -0,0 +1,305 @@
+use crate::prelude::*;
+use crossbeam_channel::{Sender, TrySendError};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+///Once this many updates are held back under `Coalesce`, the user is switched over to resyncing instead.
+pub const MAX_HELD: usize = 4096;
+
+///What to do with a user's updates once their channel is full.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub enum OverflowPolicy {
+    ///Hold updates back until there's room, keeping only the latest for each object.  If too many pile up,
+    ///falls back to `Resync`.
+    Coalesce,
+    ///Drop updates, and tell the client to reconnect once there's room.
+    Resync,
+    ///Stop sending to the user.
+    Disconnect,
+}
+
+impl Default for OverflowPolicy {
+    fn default() -> Self {
+        OverflowPolicy::Coalesce
+    }
+}
+
+///How far behind one user's updates are.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct QueueStats {
+    pub user: UserID,
+    pub policy: OverflowPolicy,
+    ///Waiting in the channel.
+    pub queued: usize,
+    ///None if the channel is unbounded.
+    pub capacity: Option<usize>,
+    ///Waiting for room in the channel.
+    pub held: usize,
+    ///The most that have been waiting in the channel at once.
+    pub peak: usize,
+    ///How many times an update didn't fit.
+    pub overflows: usize,
+}
+
+struct State {
+    policy: OverflowPolicy,
+    ///Each held update is numbered, so one that's been replaced can be told apart from the latest for its object.
+    held: VecDeque<(u64, UpdateMsg)>,
+    ///The number of the latest held update for each object.  Older ones are skipped rather than searched for and removed.
+    held_keys: HashMap<RefID, u64>,
+    next_held: u64,
+    ///How many held updates have been replaced, but are still in `held`.
+    replaced: usize,
+    ///Updates were dropped, so the client has to reconnect.
+    stale: bool,
+    resync_sent: bool,
+    peak: usize,
+    overflows: usize,
+}
+
+///Sends one user's updates, applying their overflow policy when the channel is full.
+pub struct Outbox {
+    sender: Sender<UpdateMsg>,
+    max_held: usize,
+    state: Mutex<State>,
+}
+
+impl State {
+    fn is_latest(&self, num: u64, msg: &UpdateMsg) -> bool {
+        match msg.get_key() {
+            Some(key) => self.held_keys.get(&key) == Some(&num),
+            None => true,
+        }
+    }
+
+    fn held_len(&self) -> usize {
+        self.held.len() - self.replaced
+    }
+
+    fn clear_held(&mut self) {
+        self.held.clear();
+        self.held_keys.clear();
+        self.replaced = 0;
+    }
+}
+
+impl Outbox {
+    pub fn new(sender: Sender<UpdateMsg>) -> Outbox {
+        Outbox::with_max_held(sender, MAX_HELD)
+    }
+
+    pub fn with_max_held(sender: Sender<UpdateMsg>, max_held: usize) -> Outbox {
+        Outbox {
+            sender,
+            max_held,
+            state: Mutex::new(State {
+                policy: OverflowPolicy::default(),
+                held: VecDeque::new(),
+                held_keys: HashMap::new(),
+                next_held: 0,
+                replaced: 0,
+                stale: false,
+                resync_sent: false,
+                peak: 0,
+                overflows: 0,
+            }),
+        }
+    }
+
+    pub fn set_policy(&self, policy: OverflowPolicy) {
+        self.state.lock().expect("Poisoned mutex").policy = policy;
+    }
+
+    ///Only fails if the user is gone, or is over their limit and should be disconnected.
+    pub fn send(&self, msg: UpdateMsg) -> Result<(), DBError> {
+        let mut state = self.state.lock().expect("Poisoned mutex");
+        if state.stale {
+            return Ok(());
+        }
+        if state.held.len() > 0 {
+            //Keep them in order
+            self.hold(&mut state, msg);
+            return self.flush_held(&mut state);
+        }
+        match self.sender.try_send(msg) {
+            Ok(()) => {
+                state.peak = state.peak.max(self.sender.len());
+                Ok(())
+            }
+            Err(TrySendError::Full(msg)) => {
+                state.overflows += 1;
+                warn!("Update queue is full, applying {:?}", state.policy);
+                match state.policy {
+                    OverflowPolicy::Coalesce => self.hold(&mut state, msg),
+                    OverflowPolicy::Resync => state.stale = true,
+                    OverflowPolicy::Disconnect => return Err(DBError::QueueFull),
+                }
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => Err(DBError::UserNotFound),
+        }
+    }
+
+    ///Sends whatever was held back, as far as there's room.
+    pub fn flush(&self) -> Result<(), DBError> {
+        let mut state = self.state.lock().expect("Poisoned mutex");
+        if state.stale && !state.resync_sent {
+            match self.sender.try_send(UpdateMsg::ResyncRequired) {
+                Ok(()) => state.resync_sent = true,
+                Err(TrySendError::Full(_)) => (),
+                Err(TrySendError::Disconnected(_)) => return Err(DBError::UserNotFound),
+            }
+        }
+        self.flush_held(&mut state)
+    }
+
+    fn flush_held(&self, state: &mut State) -> Result<(), DBError> {
+        while let Some((num, msg)) = state.held.pop_front() {
+            if !state.is_latest(num, &msg) {
+                state.replaced -= 1;
+                continue;
+            }
+            let key = msg.get_key();
+            match self.sender.try_send(msg) {
+                Ok(()) => {
+                    state.peak = state.peak.max(self.sender.len());
+                    if let Some(key) = key {
+                        state.held_keys.remove(&key);
+                    }
+                }
+                Err(TrySendError::Full(msg)) => {
+                    state.held.push_front((num, msg));
+                    break;
+                }
+                Err(TrySendError::Disconnected(_)) => return Err(DBError::UserNotFound),
+            }
+        }
+        Ok(())
+    }
+
+    ///An update replaces any held update for the same object.  Once too many are held, they're dropped and the client
+    ///has to resync.
+    fn hold(&self, state: &mut State, msg: UpdateMsg) {
+        let num = state.next_held;
+        state.next_held += 1;
+        if let Some(key) = msg.get_key() {
+            if state.held_keys.insert(key, num).is_some() {
+                state.replaced += 1;
+            }
+        }
+        state.held.push_back((num, msg));
+        if state.held_len() > self.max_held {
+            warn!("Too many updates held back, switching to {:?}", OverflowPolicy::Resync);
+            state.clear_held();
+            state.stale = true;
+            return;
+        }
+        //Clear out replaced updates once they're most of the queue, so it doesn't grow without bound
+        if state.replaced > state.held.len() / 2 {
+            let held = std::mem::replace(&mut state.held, VecDeque::new());
+            state.held = held.into_iter().filter(|(num, msg)| state.is_latest(*num, msg)).collect();
+            state.replaced = 0;
+        }
+    }
+
+    pub fn stats(&self, user: &UserID) -> QueueStats {
+        let state = self.state.lock().expect("Poisoned mutex");
+        QueueStats {
+            user: *user,
+            policy: state.policy,
+            queued: self.sender.len(),
+            capacity: self.sender.capacity(),
+            held: state.held_len(),
+            peak: state.peak,
+            overflows: state.overflows,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delete(key: &RefID) -> UpdateMsg {
+        UpdateMsg::Delete { key: key.clone() }
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let (s, r) = crossbeam_channel::bounded(2);
+        let outbox = Outbox::new(s);
+        let (id_1, id_2) = (RefID::new_v4(), RefID::new_v4());
+        outbox.send(delete(&id_1)).unwrap();
+        outbox.send(UpdateMsg::Modified { modified: true }).unwrap();
+        outbox.send(delete(&id_2)).unwrap();
+        outbox.send(UpdateMsg::Modified { modified: false }).unwrap();
+        outbox.send(delete(&id_2)).unwrap();
+        let stats = outbox.stats(&UserID::new_v4());
+        assert_eq!((stats.queued, stats.capacity, stats.held), (2, Some(2), 2));
+        assert_eq!((stats.peak, stats.overflows), (2, 1));
+
+        assert_eq!(
+            r.try_iter().collect::<Vec<_>>(),
+            vec![delete(&id_1), UpdateMsg::Modified { modified: true }]
+        );
+        outbox.flush().unwrap();
+        assert_eq!(
+            r.try_iter().collect::<Vec<_>>(),
+            vec![UpdateMsg::Modified { modified: false }, delete(&id_2)]
+        );
+        assert_eq!(outbox.stats(&UserID::new_v4()).held, 0);
+    }
+
+    #[test]
+    fn test_resync() {
+        let (s, r) = crossbeam_channel::bounded(1);
+        let outbox = Outbox::new(s);
+        outbox.set_policy(OverflowPolicy::Resync);
+        let id = RefID::new_v4();
+        outbox.send(delete(&id)).unwrap();
+        outbox.send(delete(&id)).unwrap();
+        outbox.flush().unwrap();
+        assert_eq!(r.try_iter().collect::<Vec<_>>(), vec![delete(&id)]);
+
+        //Once there's room the client is told to reconnect, and nothing else is sent
+        outbox.flush().unwrap();
+        outbox.send(delete(&id)).unwrap();
+        outbox.flush().unwrap();
+        assert_eq!(r.try_iter().collect::<Vec<_>>(), vec![UpdateMsg::ResyncRequired]);
+    }
+
+    #[test]
+    fn test_max_held() {
+        let (s, r) = crossbeam_channel::bounded(1);
+        let outbox = Outbox::with_max_held(s, 2);
+        let id = RefID::new_v4();
+        outbox.send(UpdateMsg::Modified { modified: true }).unwrap();
+        //Replacing an update for the same object doesn't add to what's held
+        for _ in 0..10 {
+            outbox.send(delete(&id)).unwrap();
+        }
+        outbox.send(UpdateMsg::Modified { modified: false }).unwrap();
+        let stats = outbox.stats(&UserID::new_v4());
+        assert_eq!((stats.held, stats.policy), (2, OverflowPolicy::Coalesce));
+
+        //One too many, so it falls back to resyncing
+        outbox.send(UpdateMsg::Modified { modified: true }).unwrap();
+        assert_eq!(outbox.stats(&UserID::new_v4()).held, 0);
+        assert_eq!(r.try_iter().collect::<Vec<_>>(), vec![UpdateMsg::Modified { modified: true }]);
+        outbox.send(delete(&id)).unwrap();
+        outbox.flush().unwrap();
+        assert_eq!(r.try_iter().collect::<Vec<_>>(), vec![UpdateMsg::ResyncRequired]);
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let (s, r) = crossbeam_channel::bounded(1);
+        let outbox = Outbox::new(s);
+        outbox.set_policy(OverflowPolicy::Disconnect);
+        let id = RefID::new_v4();
+        outbox.send(delete(&id)).unwrap();
+        assert_eq!(outbox.send(delete(&id)), Err(DBError::QueueFull));
+        drop(r);
+        assert_eq!(outbox.send(delete(&id)), Err(DBError::UserNotFound));
+    }
+}
@@ -0,0 +1,196 @@
+use crate::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+///Cursor and selection changes are relayed at most this often for each user.
+pub const PRESENCE_INTERVAL: Duration = Duration::from_millis(100);
+
+const COLORS: [&str; 8] = ["#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#9a6324"];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserPresence {
+    pub user: UserID,
+    pub name: String,
+    pub color: String,
+    pub cursor: Option<Point3f>,
+    pub selection: Vec<RefID>,
+}
+
+///What to do after a cursor or selection change.
+#[derive(Debug, PartialEq)]
+pub enum Live {
+    ///Enough time has passed, so the change can go out right away.
+    Now,
+    ///Too soon after the last one.  It's held until `take_held` finds the interval has passed.
+    Held,
+}
+
+struct State {
+    presence: UserPresence,
+    last_sent: Option<Instant>,
+    cursor_changed: bool,
+    selection_changed: bool,
+    held: bool,
+}
+
+///Who is looking at a file, where their cursor is and what they have selected.
+pub struct PresenceManager {
+    users: Mutex<HashMap<UserID, State>>,
+}
+
+impl PresenceManager {
+    pub fn new() -> PresenceManager {
+        PresenceManager {
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    ///New users get the first color nobody else is using.  Once they've all been taken, the least used one is shared.
+    pub fn join(&self, user: &UserID) -> UserPresence {
+        let mut users = self.users.lock().expect("Poisoned mutex");
+        let uses = |color: &&str| users.values().filter(|state| state.presence.color == *color).count();
+        let color = COLORS.iter().cloned().min_by_key(uses).expect("No colors");
+        let presence = UserPresence {
+            user: user.clone(),
+            name: String::new(),
+            color: String::from(color),
+            cursor: None,
+            selection: Vec::new(),
+        };
+        users.insert(
+            user.clone(),
+            State {
+                presence: presence.clone(),
+                last_sent: None,
+                cursor_changed: false,
+                selection_changed: false,
+                held: false,
+            },
+        );
+        presence
+    }
+
+    ///Returns false if the user wasn't here.
+    pub fn leave(&self, user: &UserID) -> bool {
+        self.users.lock().expect("Poisoned mutex").remove(user).is_some()
+    }
+
+    pub fn set_info(&self, user: &UserID, name: String, color: String) -> Result<UserPresence, DBError> {
+        let mut users = self.users.lock().expect("Poisoned mutex");
+        let state = users.get_mut(user).ok_or(DBError::UserNotFound)?;
+        state.presence.name = name;
+        state.presence.color = color;
+        Ok(state.presence.clone())
+    }
+
+    pub fn set_cursor(&self, user: &UserID, pos: Point3f, now: Instant) -> Result<Live, DBError> {
+        let mut users = self.users.lock().expect("Poisoned mutex");
+        let state = users.get_mut(user).ok_or(DBError::UserNotFound)?;
+        state.presence.cursor = Some(pos);
+        state.cursor_changed = true;
+        Ok(PresenceManager::throttle(state, now))
+    }
+
+    pub fn set_selection(&self, user: &UserID, ids: Vec<RefID>, now: Instant) -> Result<Live, DBError> {
+        let mut users = self.users.lock().expect("Poisoned mutex");
+        let state = users.get_mut(user).ok_or(DBError::UserNotFound)?;
+        state.presence.selection = ids;
+        state.selection_changed = true;
+        Ok(PresenceManager::throttle(state, now))
+    }
+
+    fn throttle(state: &mut State, now: Instant) -> Live {
+        match state.last_sent {
+            Some(last) if state.held || now.duration_since(last) < PRESENCE_INTERVAL => {
+                state.held = true;
+                Live::Held
+            }
+            _ => Live::Now,
+        }
+    }
+
+    ///The changes that were held back, once it's been long enough since the last ones were sent.
+    pub fn take_held(&self, user: &UserID, now: Instant) -> Vec<UpdateMsg> {
+        let due = match self.users.lock().expect("Poisoned mutex").get(user) {
+            Some(state) => state.held && state.last_sent.map_or(true, |last| now.duration_since(last) >= PRESENCE_INTERVAL),
+            None => false,
+        };
+        if due {
+            self.take_changes(user, now)
+        } else {
+            Vec::new()
+        }
+    }
+
+    ///The messages for whatever changed since they were last sent.
+    pub fn take_changes(&self, user: &UserID, now: Instant) -> Vec<UpdateMsg> {
+        let mut users = self.users.lock().expect("Poisoned mutex");
+        let mut msgs = Vec::new();
+        if let Some(state) = users.get_mut(user) {
+            if state.cursor_changed {
+                if let Some(pos) = state.presence.cursor {
+                    msgs.push(UpdateMsg::Cursor { user: user.clone(), pos });
+                }
+            }
+            if state.selection_changed {
+                msgs.push(UpdateMsg::Selection {
+                    user: user.clone(),
+                    ids: state.presence.selection.clone(),
+                });
+            }
+            state.cursor_changed = false;
+            state.selection_changed = false;
+            state.held = false;
+            state.last_sent = Some(now);
+        }
+        msgs
+    }
+
+    pub fn get_users(&self) -> Vec<UserPresence> {
+        let users = self.users.lock().expect("Poisoned mutex");
+        users.values().map(|state| state.presence.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let presence = PresenceManager::new();
+        let user = UserID::new_v4();
+        presence.join(&user);
+        let start = Instant::now();
+        let pos = Point3f::new(1.0, 2.0, 0.0);
+        assert_eq!(presence.set_cursor(&user, pos, start), Ok(Live::Now));
+        assert_eq!(presence.take_changes(&user, start), vec![UpdateMsg::Cursor { user, pos }]);
+
+        let soon = start + PRESENCE_INTERVAL / 4;
+        assert_eq!(presence.set_cursor(&user, pos, soon), Ok(Live::Held));
+        assert_eq!(presence.set_selection(&user, vec![user], soon), Ok(Live::Held));
+        assert_eq!(presence.take_held(&user, soon), vec![]);
+        let later = start + PRESENCE_INTERVAL;
+        //Still held once the interval is up, until they're taken
+        assert_eq!(presence.set_cursor(&user, pos, later), Ok(Live::Held));
+        assert_eq!(presence.take_held(&user, later).len(), 2);
+        assert_eq!(presence.take_held(&user, later).len(), 0);
+        assert_eq!(presence.set_selection(&user, Vec::new(), later + PRESENCE_INTERVAL), Ok(Live::Now));
+
+        assert!(presence.leave(&user));
+        assert_eq!(presence.set_cursor(&user, pos, later), Err(DBError::UserNotFound));
+    }
+
+    #[test]
+    fn test_colors() {
+        let presence = PresenceManager::new();
+        let users: Vec<UserID> = (0..3).map(|_| UserID::new_v4()).collect();
+        let colors: Vec<String> = users.iter().map(|user| presence.join(user).color).collect();
+        assert_eq!(colors, vec![COLORS[0], COLORS[1], COLORS[2]]);
+        //A color that's given up goes to the next user, rather than doubling up on one that's still in use
+        assert!(presence.leave(&users[0]));
+        assert_eq!(presence.join(&UserID::new_v4()).color, COLORS[0]);
+        assert_eq!(presence.join(&UserID::new_v4()).color, COLORS[3]);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::prelude::*;
+use std::sync::Mutex;
+
+///How many broadcasts are kept for clients that reconnect.
+pub const REPLAY_LENGTH: usize = 4096;
+///Roughly how much memory the kept broadcasts can use.  Meshes can be large, so this usually runs out first.
+pub const REPLAY_BYTES: u64 = 64 * 1024 * 1024;
+
+struct Buffer {
+    last_seq: u64,
+    ///Each broadcast along with its size.
+    msgs: VecDeque<(UpdateMsg, u64)>,
+    bytes: u64,
+}
+
+///Numbers every broadcast for a file and keeps the most recent ones, so a client that lost its connection
+///can be sent just what it missed.  Sequence numbers start again whenever the file is opened, which is a new session.
+pub struct ReplayBuffer {
+    session: SessionID,
+    length: usize,
+    max_bytes: u64,
+    buffer: Mutex<Buffer>,
+}
+
+impl ReplayBuffer {
+    pub fn new() -> ReplayBuffer {
+        ReplayBuffer::with_limits(REPLAY_LENGTH, REPLAY_BYTES)
+    }
+
+    ///Keeps at most `length` broadcasts, and drops the oldest sooner if they add up to more than `max_bytes`.
+    pub fn with_limits(length: usize, max_bytes: u64) -> ReplayBuffer {
+        ReplayBuffer {
+            session: SessionID::new_v4(),
+            length,
+            max_bytes,
+            buffer: Mutex::new(Buffer {
+                last_seq: 0,
+                msgs: VecDeque::new(),
+                bytes: 0,
+            }),
+        }
+    }
+
+    pub fn get_session(&self) -> SessionID {
+        self.session
+    }
+
+    ///Stamps the message with the next sequence number and passes it to `send`.
+    ///The buffer stays locked while sending, so every client gets broadcasts in order.
+    pub fn broadcast<T>(&self, msg: UpdateMsg, send: impl FnOnce(UpdateMsg) -> T) -> T {
+        let mut buffer = self.buffer.lock().expect("Poisoned mutex");
+        buffer.last_seq += 1;
+        let msg = UpdateMsg::Sequenced {
+            seq: buffer.last_seq,
+            msg: Box::new(msg),
+        };
+        let size = bincode::serialized_size(&msg).unwrap_or(0);
+        buffer.msgs.push_back((msg.clone(), size));
+        buffer.bytes += size;
+        while buffer.msgs.len() > self.length || buffer.bytes > self.max_bytes {
+            match buffer.msgs.pop_front() {
+                Some((_, size)) => buffer.bytes -= size,
+                None => break,
+            }
+        }
+        send(msg)
+    }
+
+    ///Calls `connect` with the latest sequence number, and the broadcasts after `since` if it's from this session and
+    ///they're all still buffered.  Nothing can be broadcast until `connect` returns.
+    pub fn connect<T>(&self, since: Option<(SessionID, u64)>, connect: impl FnOnce(u64, Option<Vec<UpdateMsg>>) -> T) -> T {
+        let buffer = self.buffer.lock().expect("Poisoned mutex");
+        let oldest = buffer.last_seq - buffer.msgs.len() as u64;
+        let missed = match since {
+            Some((session, seq)) if session == self.session && seq >= oldest && seq <= buffer.last_seq => {
+                Some(buffer.msgs.iter().skip((seq - oldest) as usize).map(|(msg, _)| msg.clone()).collect())
+            }
+            _ => None,
+        };
+        connect(buffer.last_seq, missed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delete(key: &RefID) -> UpdateMsg {
+        UpdateMsg::Delete { key: key.clone() }
+    }
+
+    #[test]
+    fn test_replay() {
+        let replay = ReplayBuffer::with_limits(3, REPLAY_BYTES);
+        let session = replay.get_session();
+        let ids: Vec<RefID> = (0..5).map(|_| RefID::new_v4()).collect();
+        for id in &ids[0..2] {
+            replay.broadcast(delete(id), |_| ());
+        }
+        assert_eq!(
+            replay.connect(Some((session, 0)), |seq, missed| (seq, missed.map(|m| m.len()))),
+            (2, Some(2))
+        );
+        let missed = replay.connect(Some((session, 1)), |_, missed| missed).unwrap();
+        assert_eq!(
+            missed,
+            vec![UpdateMsg::Sequenced {
+                seq: 2,
+                msg: Box::new(delete(&ids[1]))
+            }]
+        );
+        assert_eq!(replay.connect(Some((session, 2)), |_, missed| missed), Some(Vec::new()));
+
+        for id in &ids[2..5] {
+            replay.broadcast(delete(id), |_| ());
+        }
+        //Only the last 3 are kept, so a client that saw up to 1 has missed too much
+        assert_eq!(replay.connect(Some((session, 1)), |_, missed| missed), None);
+        let missed = replay.connect(Some((session, 2)), |_, missed| missed).unwrap();
+        let missed: Vec<UpdateMsg> = missed.into_iter().map(|msg| msg.unsequenced()).collect();
+        assert_eq!(missed, vec![delete(&ids[2]), delete(&ids[3]), delete(&ids[4])]);
+        //Anything from another session, or from the future, needs a full resync
+        assert_eq!(replay.connect(Some((SessionID::new_v4(), 4)), |_, missed| missed), None);
+        assert_eq!(replay.connect(Some((session, 6)), |_, missed| missed), None);
+        assert_eq!(replay.connect(None, |seq, missed| (seq, missed)), (5, None));
+    }
+
+    #[test]
+    fn test_replay_bytes() {
+        let size = bincode::serialized_size(&UpdateMsg::Sequenced {
+            seq: 1,
+            msg: Box::new(delete(&RefID::new_v4())),
+        })
+        .unwrap();
+        let replay = ReplayBuffer::with_limits(REPLAY_LENGTH, size * 2);
+        let session = replay.get_session();
+        for _ in 0..3 {
+            replay.broadcast(delete(&RefID::new_v4()), |_| ());
+        }
+        //There's room for plenty more messages, but not more bytes
+        assert_eq!(replay.connect(Some((session, 0)), |_, missed| missed), None);
+        assert_eq!(replay.connect(Some((session, 1)), |_, missed| missed.map(|m| m.len())), Some(2));
+    }
+}
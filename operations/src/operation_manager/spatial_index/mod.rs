@@ -0,0 +1,160 @@
+use crate::prelude::*;
+use ccl::dhashmap::DHashMap;
+
+///Size of a grid cell in world units.  Queries look at every cell within the tolerance, so this should be a bit bigger than a typical snap tolerance.
+const CELL_SIZE: WorldCoord = 10.0;
+
+type Cell = (i64, i64);
+
+///A uniform grid over the XY plane, so that snapping only has to look at objects near the cursor.
+///Objects are stored in every cell their results' bounding box touches.
+pub struct SpatialIndex {
+    cells: DHashMap<Cell, HashSet<RefID>>,
+    bounds: DHashMap<RefID, (Cell, Cell)>,
+}
+
+impl SpatialIndex {
+    pub fn new() -> SpatialIndex {
+        SpatialIndex {
+            cells: DHashMap::default(),
+            bounds: DHashMap::default(),
+        }
+    }
+
+    fn get_cell(pt: &Point3f) -> Cell {
+        ((pt.x / CELL_SIZE).floor() as i64, (pt.y / CELL_SIZE).floor() as i64)
+    }
+
+    fn get_bounds(results: &Vec<RefGeometry>) -> Option<(Cell, Cell)> {
+        let mut bounds: Option<(Cell, Cell)> = None;
+        for result in results {
+            let pts = match result {
+                RefGeometry::Point { pt } => vec![*pt],
+                RefGeometry::Line { pt_1, pt_2 } => vec![*pt_1, *pt_2],
+                RefGeometry::Rect { pt_1, pt_2, pt_3 } => vec![*pt_1, *pt_2, *pt_3],
+            };
+            for pt in pts {
+                let cell = SpatialIndex::get_cell(&pt);
+                bounds = match bounds {
+                    Some((min, max)) => Some(((min.0.min(cell.0), min.1.min(cell.1)), (max.0.max(cell.0), max.1.max(cell.1)))),
+                    None => Some((cell, cell)),
+                };
+            }
+        }
+        bounds
+    }
+
+    fn add_to_cells(&self, id: &RefID, (min, max): (Cell, Cell)) {
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                match self.cells.get_mut(&(x, y)) {
+                    Some(mut set) => {
+                        set.insert(id.clone());
+                    }
+                    None => {
+                        let mut set = HashSet::new();
+                        set.insert(id.clone());
+                        self.cells.insert((x, y), set);
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove_from_cells(&self, id: &RefID, (min, max): (Cell, Cell)) {
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                if let Some(mut set) = self.cells.get_mut(&(x, y)) {
+                    set.remove(id);
+                }
+            }
+        }
+    }
+
+    ///Replaces whatever was stored for this object with the area covered by its results.
+    pub fn insert(&self, id: &RefID, results: &Vec<RefGeometry>) {
+        self.remove(id);
+        if let Some(bounds) = SpatialIndex::get_bounds(results) {
+            self.add_to_cells(id, bounds);
+            self.bounds.insert(id.clone(), bounds);
+        }
+    }
+
+    pub fn remove(&self, id: &RefID) {
+        if let Some((_, bounds)) = self.bounds.remove(id) {
+            self.remove_from_cells(id, bounds);
+        }
+    }
+
+    ///Returns every object that might have a result within tolerance of the point.
+    pub fn query(&self, pt: &Point3f, tolerance: WorldCoord) -> HashSet<RefID> {
+        let min = SpatialIndex::get_cell(&Point3f::new(pt.x - tolerance, pt.y - tolerance, pt.z));
+        let max = SpatialIndex::get_cell(&Point3f::new(pt.x + tolerance, pt.y + tolerance, pt.z));
+        let mut results = HashSet::new();
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                if let Some(set) = self.cells.get(&(x, y)) {
+                    results.extend(set.iter().cloned());
+                }
+            }
+        }
+        results
+    }
+
+    pub fn debug_state(&self, output: &mut String) {
+        output.push_str(&format!("{:?} Indexed Objects:\n", self.bounds.len()));
+        for chunk in self.bounds.chunks() {
+            for (id, bounds) in chunk.iter() {
+                output.push_str(&format!("{:?} -> {:?}\n", id, bounds));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query() {
+        let index = SpatialIndex::new();
+        let a = RefID::new_v4();
+        let b = RefID::new_v4();
+        index.insert(
+            &a,
+            &vec![RefGeometry::Line {
+                pt_1: Point3f::new(0.0, 0.0, 0.0),
+                pt_2: Point3f::new(100.0, 0.0, 0.0),
+            }],
+        );
+        index.insert(
+            &b,
+            &vec![RefGeometry::Point {
+                pt: Point3f::new(50.0, 50.0, 0.0),
+            }],
+        );
+
+        let results = index.query(&Point3f::new(75.0, 1.0, 0.0), 1.0);
+        assert!(results.contains(&a));
+        assert!(!results.contains(&b));
+
+        let results = index.query(&Point3f::new(49.0, 49.0, 0.0), 2.0);
+        assert!(!results.contains(&a));
+        assert!(results.contains(&b));
+
+        index.insert(
+            &b,
+            &vec![RefGeometry::Point {
+                pt: Point3f::new(-50.0, -50.0, 0.0),
+            }],
+        );
+        let results = index.query(&Point3f::new(49.0, 49.0, 0.0), 2.0);
+        assert!(!results.contains(&b));
+        let results = index.query(&Point3f::new(-50.0, -50.0, 0.0), 2.0);
+        assert!(results.contains(&b));
+
+        index.remove(&a);
+        let results = index.query(&Point3f::new(75.0, 1.0, 0.0), 1.0);
+        assert!(!results.contains(&a));
+    }
+}
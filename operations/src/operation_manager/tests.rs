@@ -22,10 +22,10 @@ fn test_dep_update() {
         let mut obj_2 = TestObj::new("other stuff");
         let id_1 = obj_1.get_id().clone();
         let id_2 = obj_2.get_id().clone();
-        let ref_1 = GeometryId { id: id_1.clone(), index: 0 };
-        let ref_2 = GeometryId { id: id_2.clone(), index: 0 };
+        let ref_1 = GeometryId::new(id_1.clone(), TestObj::FIRST);
+        let ref_2 = GeometryId::new(id_2.clone(), TestObj::FIRST);
         obj_1.set_ref(
-            0,
+            &TestObj::FIRST,
             &RefGeometry::Point {
                 pt: Point3f::new(0.0, 1.0, 2.0),
             },
@@ -33,7 +33,7 @@ fn test_dep_update() {
             &None,
         );
         obj_2.set_ref(
-            0,
+            &TestObj::FIRST,
             &RefGeometry::Point {
                 pt: Point3f::new(2.0, 1.0, 0.0),
             },
@@ -52,7 +52,7 @@ fn test_dep_update() {
         ops.get_obj(&id_1, |read_1| {
             let point_ref = read_1.query_ref::<dyn ReferTo>().unwrap();
             assert_eq!(
-                point_ref.get_result(0),
+                point_ref.get_result(&TestObj::FIRST),
                 Some(RefGeometry::Point {
                     pt: Point3f::new(3.0, 3.0, 3.0)
                 })
@@ -63,7 +63,7 @@ fn test_dep_update() {
         ops.get_obj(&id_2, |read_2| {
             let point_ref = read_2.query_ref::<dyn ReferTo>().unwrap();
             assert_eq!(
-                point_ref.get_result(0),
+                point_ref.get_result(&TestObj::FIRST),
                 Some(RefGeometry::Point {
                     pt: Point3f::new(2.0, 1.0, 0.0)
                 })
@@ -75,7 +75,7 @@ fn test_dep_update() {
         ops.get_obj(&id_2, |read_2| {
             let point_ref = read_2.query_ref::<dyn ReferTo>().unwrap();
             assert_eq!(
-                point_ref.get_result(0),
+                point_ref.get_result(&TestObj::FIRST),
                 Some(RefGeometry::Point {
                     pt: Point3f::new(3.0, 3.0, 3.0)
                 })
@@ -95,10 +95,10 @@ fn test_dep_undo() {
         let mut obj_2 = TestObj::new("other stuff");
         let id_1 = obj_1.get_id().clone();
         let id_2 = obj_2.get_id().clone();
-        let ref_1 = GeometryId { id: id_1.clone(), index: 0 };
-        let ref_2 = GeometryId { id: id_2.clone(), index: 0 };
+        let ref_1 = GeometryId::new(id_1.clone(), TestObj::FIRST);
+        let ref_2 = GeometryId::new(id_2.clone(), TestObj::FIRST);
         obj_1.set_ref(
-            0,
+            &TestObj::FIRST,
             &RefGeometry::Point {
                 pt: Point3f::new(0.0, 1.0, 2.0),
             },
@@ -106,7 +106,7 @@ fn test_dep_undo() {
             &None,
         );
         obj_2.set_ref(
-            0,
+            &TestObj::FIRST,
             &RefGeometry::Point {
                 pt: Point3f::new(2.0, 1.0, 0.0),
             },
@@ -130,7 +130,7 @@ fn test_dep_undo() {
         ops.get_obj(&id_1, |read_1| {
             let point_ref = read_1.query_ref::<dyn ReferTo>().unwrap();
             assert_eq!(
-                point_ref.get_result(0),
+                point_ref.get_result(&TestObj::FIRST),
                 Some(RefGeometry::Point {
                     pt: Point3f::new(0.0, 1.0, 2.0)
                 })
@@ -141,7 +141,7 @@ fn test_dep_undo() {
         ops.get_obj(&id_2, |read_2| {
             let point_ref = read_2.query_ref::<dyn ReferTo>().unwrap();
             assert_eq!(
-                point_ref.get_result(0),
+                point_ref.get_result(&TestObj::FIRST),
                 Some(RefGeometry::Point {
                     pt: Point3f::new(0.0, 1.0, 2.0)
                 })
@@ -161,10 +161,10 @@ fn test_dep_redo() {
         let mut obj_2 = TestObj::new("other stuff");
         let id_1 = obj_1.get_id().clone();
         let id_2 = obj_2.get_id().clone();
-        let ref_1 = GeometryId { id: id_1.clone(), index: 0 };
-        let ref_2 = GeometryId { id: id_2.clone(), index: 0 };
+        let ref_1 = GeometryId::new(id_1.clone(), TestObj::FIRST);
+        let ref_2 = GeometryId::new(id_2.clone(), TestObj::FIRST);
         obj_1.set_ref(
-            0,
+            &TestObj::FIRST,
             &RefGeometry::Point {
                 pt: Point3f::new(0.0, 1.0, 2.0),
             },
@@ -172,7 +172,7 @@ fn test_dep_redo() {
             &None,
         );
         obj_2.set_ref(
-            0,
+            &TestObj::FIRST,
             &RefGeometry::Point {
                 pt: Point3f::new(2.0, 1.0, 0.0),
             },
@@ -190,7 +190,7 @@ fn test_dep_redo() {
         ops.get_obj(&id_1, |read_1| {
             let point_ref = read_1.query_ref::<dyn ReferTo>().unwrap();
             assert_eq!(
-                point_ref.get_result(0),
+                point_ref.get_result(&TestObj::FIRST),
                 Some(RefGeometry::Point {
                     pt: Point3f::new(0.0, 1.0, 2.0)
                 })
@@ -201,7 +201,7 @@ fn test_dep_redo() {
         ops.get_obj(&id_2, |read_1| {
             let point_ref = read_1.query_ref::<dyn ReferTo>().unwrap();
             assert_eq!(
-                point_ref.get_result(0),
+                point_ref.get_result(&TestObj::FIRST),
                 Some(RefGeometry::Point {
                     pt: Point3f::new(0.0, 1.0, 2.0)
                 })
@@ -227,7 +227,7 @@ fn test_copy() {
         ops.get_obj(&copy_id, |copy| {
             let point_ref = copy.query_ref::<dyn ReferTo>().unwrap();
             assert_eq!(
-                point_ref.get_result(0),
+                point_ref.get_result(&TestObj::FIRST),
                 Some(RefGeometry::Point {
                     pt: Point3f::new(1.0, 2.0, 3.0)
                 })
@@ -237,3 +237,350 @@ fn test_copy() {
         .unwrap();
     });
 }
+
+#[test]
+fn test_export_deps() {
+    test_setup(|ops, _| {
+        let event = UndoEventID::new_v4();
+        ops.begin_undo_event(&USER, event.clone(), String::from("export_deps")).unwrap();
+        let obj_1 = TestObj::new("some stuff");
+        let mut obj_2 = TestObj::new("other stuff");
+        let id_1 = obj_1.get_id().clone();
+        let id_2 = obj_2.get_id().clone();
+        obj_2.set_ref(
+            &TestObj::FIRST,
+            &RefGeometry::Point {
+                pt: Point3f::new(0.0, 1.0, 2.0),
+            },
+            GeometryId::new(id_1.clone(), TestObj::FIRST),
+            &None,
+        );
+        ops.add_object(&event, Box::new(obj_1)).unwrap();
+        ops.add_object(&event, Box::new(obj_2)).unwrap();
+        ops.end_undo_event(event).unwrap();
+
+        let graph = ops.export_deps(GraphFormat::Json).unwrap();
+        assert_eq!(graph["objects"].as_array().unwrap().len(), 2);
+        for object in graph["objects"].as_array().unwrap() {
+            assert_eq!(object["type"], json!("TestObj"));
+        }
+        assert_eq!(
+            graph["edges"],
+            json!([{
+                "publisher": GeometryId::new(id_1.clone(), TestObj::FIRST),
+                "subscriber": GeometryId::new(id_2.clone(), TestObj::FIRST),
+            }])
+        );
+
+        let dot = ops.export_deps(GraphFormat::Dot).unwrap();
+        let dot = dot.as_str().unwrap();
+        assert!(dot.starts_with("digraph deps {"));
+        assert!(dot.contains(&format!("label=\"TestObj {}\";", id_1)));
+        assert!(dot.contains(&format!("\"{}:First\" -> \"{}:First\";", id_1, id_2)));
+    });
+}
+
+#[test]
+fn test_modified() {
+    test_setup(|ops, rcv| {
+        let path = PathBuf::from("./test_modified.flx");
+        let get_notifications = || {
+            rcv.try_iter()
+                .filter_map(|msg| match msg.unsequenced() {
+                    UpdateMsg::Modified { modified } => Some(modified),
+                    _ => None,
+                })
+                .collect::<Vec<bool>>()
+        };
+        assert!(!ops.is_modified());
+        let event = UndoEventID::new_v4();
+        ops.begin_undo_event(&USER, event.clone(), String::from("modified")).unwrap();
+        ops.add_object(&event, Box::new(TestObj::new("some stuff"))).unwrap();
+        ops.end_undo_event(event).unwrap();
+        assert!(ops.is_modified());
+        assert_eq!(get_notifications(), vec![true]);
+
+        ops.save(&path).unwrap();
+        assert!(!ops.is_modified());
+        assert_eq!(get_notifications(), vec![false]);
+
+        ops.undo_latest(&USER).unwrap();
+        assert!(ops.is_modified());
+        ops.redo_latest(&USER).unwrap();
+        assert!(!ops.is_modified());
+        assert_eq!(get_notifications(), vec![true, false]);
+
+        //The file info isn't undoable, but still has to be saved
+        let mut project = ProjectInfo::default();
+        project.name = String::from("House");
+        ops.set_file_info(Units::Feet, project.clone()).unwrap();
+        assert!(ops.is_modified());
+        ops.set_file_info(Units::Feet, project).unwrap();
+        assert_eq!(get_notifications(), vec![true]);
+        ops.save(&path).unwrap();
+        assert!(!ops.is_modified());
+        assert_eq!(get_notifications(), vec![false]);
+        std::fs::remove_file(path).unwrap();
+    });
+}
+
+#[test]
+fn test_history() {
+    test_setup(|ops, _| {
+        let obj_1 = TestObj::new("first");
+        let obj_2 = TestObj::new("second");
+        let id_1 = obj_1.get_id().clone();
+        let id_2 = obj_2.get_id().clone();
+        let event_1 = UndoEventID::new_v4();
+        ops.begin_undo_event(&USER, event_1.clone(), String::from("add first")).unwrap();
+        ops.add_object(&event_1, Box::new(obj_1)).unwrap();
+        ops.end_undo_event(event_1.clone()).unwrap();
+        let event_2 = UndoEventID::new_v4();
+        ops.begin_undo_event(&USER, event_2.clone(), String::from("add second")).unwrap();
+        ops.add_object(&event_2, Box::new(obj_2)).unwrap();
+        ops.end_undo_event(event_2.clone()).unwrap();
+        let event_3 = UndoEventID::new_v4();
+        ops.begin_undo_event(&USER, event_3.clone(), String::from("modify first")).unwrap();
+        ops.modify_obj(&event_3, &id_1, &mut |write: &mut DataObject| {
+            write.query_mut::<dyn Store>().unwrap().set_store_data(String::from("modified"));
+            Ok(())
+        })
+        .unwrap();
+        ops.end_undo_event(event_3.clone()).unwrap();
+
+        let history = ops.get_history();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[1].event_id, event_2);
+        assert_eq!(history[1].user_id, *USER);
+        assert_eq!(history[1].desc, String::from("add second"));
+        assert_eq!(history[1].objects, vec![id_2.clone()]);
+
+        assert_eq!(ops.revert_event(&USER, &event_1), Err(DBError::ModifiedSince(vec![id_1.clone()])));
+        let event_4 = UndoEventID::new_v4();
+        ops.begin_undo_event(&USER, event_4.clone(), String::from("modify second")).unwrap();
+        ops.modify_obj(&event_4, &id_2, &mut |write: &mut DataObject| {
+            write.query_mut::<dyn Store>().unwrap().set_store_data(String::from("pending"));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(ops.revert_event(&USER, &event_2), Err(DBError::ModifiedSince(vec![id_2.clone()])));
+        ops.cancel_event(&event_4).unwrap();
+        ops.revert_event(&USER, &event_2).unwrap();
+        assert_eq!(ops.get_obj(&id_2, |_| Ok(())), Err(DBError::ObjNotFound));
+        assert_eq!(ops.get_history().len(), 2);
+
+        ops.undo_to(&USER, &event_1).unwrap();
+        ops.get_obj(&id_1, |read| {
+            assert_eq!(read.query_ref::<dyn Store>().unwrap().get_store_data(), String::from("first"));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(ops.get_history().len(), 1);
+        assert_eq!(ops.undo_to(&USER, &event_3), Err(DBError::NoUndoEvent));
+    });
+}
+
+#[test]
+fn test_edit_locks() {
+    test_setup(|ops, rcv| {
+        let other_user = UserID::new_v4();
+        let first_event = UndoEventID::new_v4();
+        ops.begin_undo_event(&USER, first_event.clone(), String::from("add first")).unwrap();
+        ops.add_object(&first_event, Box::new(TestObj::new("unlocked"))).unwrap();
+        ops.end_undo_event(first_event.clone()).unwrap();
+        let obj = TestObj::new("locked");
+        let id = obj.get_id().clone();
+        let event = UndoEventID::new_v4();
+        ops.begin_undo_event(&USER, event.clone(), String::from("add obj")).unwrap();
+        ops.add_object(&event, Box::new(obj)).unwrap();
+        let other_event = UndoEventID::new_v4();
+        ops.begin_undo_event(&other_user, other_event.clone(), String::from("modify obj"))
+            .unwrap();
+        match ops.modify_obj(&other_event, &id, &mut |_: &mut DataObject| Ok(())) {
+            Err(DBError::Locked(_)) => (),
+            result => panic!("Expected the object to be locked, got {:?}", result),
+        }
+        assert!(ops.delete_obj(&other_event, &id).is_err());
+        assert_eq!(
+            ops.get_locks(),
+            vec![LockInfo {
+                key: id,
+                prop: None,
+                owner: *USER
+            }]
+        );
+
+        ops.end_undo_event(event.clone()).unwrap();
+        assert_eq!(ops.get_locks().len(), 0);
+        ops.modify_obj(&other_event, &id, &mut |_: &mut DataObject| Ok(())).unwrap();
+        assert_eq!(
+            ops.get_locks(),
+            vec![LockInfo {
+                key: id,
+                prop: None,
+                owner: other_user
+            }]
+        );
+        //Undoing would change the object out from under the other user's edit
+        for result in vec![ops.undo_latest(&USER), ops.undo_to(&USER, &first_event), ops.revert_event(&USER, &event)] {
+            match result {
+                Err(DBError::Locked(_)) => (),
+                result => panic!("Expected the object to be locked, got {:?}", result),
+            }
+        }
+        ops.cancel_event(&other_event).unwrap();
+        assert_eq!(ops.get_locks().len(), 0);
+        ops.undo_latest(&USER).unwrap();
+        assert_eq!(ops.get_obj(&id, |_| Ok(())), Err(DBError::ObjNotFound));
+
+        let lock_msgs: Vec<Option<UserID>> = rcv
+            .try_iter()
+            .filter_map(|msg| match msg.unsequenced() {
+                UpdateMsg::Lock { key, owner, .. } if key == id => Some(owner),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(lock_msgs, vec![Some(*USER), None, Some(other_user), None]);
+
+        //Leaving part way through an edit drops it
+        let left_event = UndoEventID::new_v4();
+        ops.begin_undo_event(&other_user, left_event.clone(), String::from("modify obj")).unwrap();
+        let left_obj = TestObj::new("left behind");
+        let left_id = left_obj.get_id().clone();
+        ops.add_object(&left_event, Box::new(left_obj)).unwrap();
+        ops.cancel_user_events(&other_user).unwrap();
+        assert_eq!(ops.get_obj(&left_id, |_| Ok(())), Err(DBError::ObjNotFound));
+        assert_eq!(ops.get_locks().len(), 0);
+        assert_eq!(ops.end_undo_event(left_event), Err(DBError::NoUndoEvent));
+    });
+}
+
+#[test]
+fn test_presence() {
+    test_setup(|ops, rcv| {
+        let other_user = UserID::new_v4();
+        let (s, other_rcv) = crossbeam_channel::unbounded();
+        ops.set_user_info(&USER, String::from("First"), String::from("#ff0000")).unwrap();
+        ops.add_user(other_user.clone(), s).unwrap();
+        let session = match other_rcv.try_recv() {
+            Ok(UpdateMsg::Synced { session, seq: 0, full: true }) => session,
+            msg => panic!("Expected the new user to be synced, got {:?}", msg),
+        };
+        assert_eq!(rcv.try_recv(), Ok(UpdateMsg::Synced { session, seq: 0, full: true }));
+        match other_rcv.try_recv() {
+            Ok(UpdateMsg::Presence { user, name, color }) => {
+                assert_eq!((user, name.as_str(), color.as_str()), (*USER, "First", "#ff0000"))
+            }
+            msg => panic!("Expected presence for the first user, got {:?}", msg),
+        }
+        match rcv.try_recv() {
+            Ok(UpdateMsg::Presence { user, .. }) => assert_eq!(user, other_user),
+            msg => panic!("Expected presence for the new user, got {:?}", msg),
+        }
+        assert_eq!(ops.get_users().len(), 2);
+
+        let pos = Point3f::new(1.0, 2.0, 3.0);
+        assert_eq!(ops.set_cursor(&other_user, pos), Ok(()));
+        assert_eq!(rcv.try_recv(), Ok(UpdateMsg::Cursor { user: other_user, pos }));
+        //Too soon after the last one, so it's held back until it's flushed after the interval
+        ops.set_selection(&other_user, vec![other_user]).unwrap();
+        ops.flush_updates(&other_user).unwrap();
+        assert!(rcv.try_recv().is_err());
+        std::thread::sleep(PRESENCE_INTERVAL);
+        ops.flush_updates(&other_user).unwrap();
+        assert_eq!(
+            rcv.try_recv(),
+            Ok(UpdateMsg::Selection {
+                user: other_user,
+                ids: vec![other_user]
+            })
+        );
+        assert!(other_rcv.try_recv().is_err());
+
+        ops.remove_user(&other_user).unwrap();
+        assert_eq!(rcv.try_recv(), Ok(UpdateMsg::UserLeft { user: other_user }));
+        assert_eq!(ops.get_users().len(), 1);
+    });
+}
+
+#[test]
+fn test_presence_overflow() {
+    test_setup(|ops, _| {
+        let other_user = UserID::new_v4();
+        let (s, other_rcv) = crossbeam_channel::bounded(1);
+        ops.add_user(other_user.clone(), s).unwrap();
+        other_rcv.try_iter().for_each(drop);
+        ops.flush_updates(&other_user).unwrap();
+        ops.set_overflow_policy(&other_user, OverflowPolicy::Disconnect).unwrap();
+        //Presence goes out the same way as everything else, so a user who's fallen behind is dropped
+        assert_eq!(ops.set_cursor(&USER, Point3f::new(1.0, 2.0, 3.0)), Ok(()));
+        assert!(ops.get_queue_stats().iter().all(|stats| stats.user != other_user));
+        assert_eq!(ops.flush_updates(&other_user), Err(DBError::UserNotFound));
+    });
+}
+
+#[test]
+fn test_reconnect() {
+    test_setup(|ops, _| {
+        let other_user = UserID::new_v4();
+        let (s, rcv) = crossbeam_channel::unbounded();
+        ops.add_user(other_user.clone(), s).unwrap();
+        let session = match rcv.try_recv() {
+            Ok(UpdateMsg::Synced { session, .. }) => session,
+            msg => panic!("Expected the new user to be synced, got {:?}", msg),
+        };
+        rcv.try_iter().count();
+
+        let event = UndoEventID::new_v4();
+        ops.begin_undo_event(&USER, event.clone(), String::from("reconnect")).unwrap();
+        let obj = TestObj::new("some stuff");
+        let id = obj.get_id().clone();
+        ops.add_object(&event, Box::new(obj)).unwrap();
+        let last_seq = match rcv.try_iter().last() {
+            Some(UpdateMsg::Sequenced { seq, .. }) => seq,
+            msg => panic!("Expected a sequenced update, got {:?}", msg),
+        };
+        //The connection drops, and the user misses the delete
+        drop(rcv);
+        ops.delete_obj(&event, &id).unwrap();
+        ops.end_undo_event(event).unwrap();
+
+        let (s, rcv) = crossbeam_channel::unbounded();
+        ops.reconnect(other_user.clone(), s, session, last_seq).unwrap();
+        let msgs: Vec<UpdateMsg> = rcv.try_iter().collect();
+        assert_eq!(
+            msgs[0],
+            UpdateMsg::Synced {
+                session,
+                seq: last_seq,
+                full: false
+            }
+        );
+        assert!(msgs.contains(&UpdateMsg::Sequenced {
+            seq: last_seq + 1,
+            msg: Box::new(UpdateMsg::Delete { key: id })
+        }));
+        assert!(msgs.iter().all(|msg| match msg {
+            UpdateMsg::Other { .. } => false,
+            _ => true,
+        }));
+
+        //From another session, so everything is sent again
+        let event = UndoEventID::new_v4();
+        ops.begin_undo_event(&USER, event.clone(), String::from("reconnect")).unwrap();
+        ops.add_object(&event, Box::new(TestObj::new("other stuff"))).unwrap();
+        ops.end_undo_event(event).unwrap();
+        let (s, rcv) = crossbeam_channel::unbounded();
+        ops.reconnect(other_user.clone(), s, UserID::new_v4(), last_seq).unwrap();
+        let msgs: Vec<UpdateMsg> = rcv.try_iter().collect();
+        match msgs[0] {
+            UpdateMsg::Synced { seq, full: true, .. } => assert!(seq > last_seq),
+            ref msg => panic!("Expected a full resync, got {:?}", msg),
+        }
+        assert!(msgs.iter().any(|msg| match msg {
+            UpdateMsg::Other { .. } => true,
+            _ => false,
+        }));
+        assert_eq!(ops.get_users().len(), 2);
+    });
+}
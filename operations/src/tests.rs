@@ -25,6 +25,10 @@ interfaces!(
 );
 
 impl TestObj {
+    pub const FIRST: ResultInd = RefIndex::named("First");
+    pub const SECOND: ResultInd = RefIndex::named("Second");
+    pub const LINE: ResultInd = RefIndex::named("Line");
+
     pub fn new(dat: &str) -> TestObj {
         TestObj {
             id: RefID::new_v4(),
@@ -83,44 +87,46 @@ impl Store for TestObj {
 }
 
 impl ReferTo for TestObj {
-    fn get_result(&self, index: ResultInd) -> Option<RefGeometry> {
-        match index {
-            0 => Some(self.point.geom.get_geom()),
-            1 => Some(self.point_2.geom.get_geom()),
-            2 => Some(RefGeometry::Line {
+    fn get_result(&self, index: &ResultInd) -> Option<RefGeometry> {
+        if *index == TestObj::FIRST {
+            Some(self.point.geom.get_geom())
+        } else if *index == TestObj::SECOND {
+            Some(self.point_2.geom.get_geom())
+        } else if *index == TestObj::LINE {
+            Some(RefGeometry::Line {
                 pt_1: self.point.geom.pt,
                 pt_2: self.point_2.geom.pt,
-            }),
-            _ => None,
+            })
+        } else {
+            None
         }
     }
 
-    fn get_all_results(&self) -> Vec<RefGeometry> {
+    fn get_all_results(&self) -> Vec<(ResultInd, RefGeometry)> {
         let mut results = Vec::new();
-        results.push(RefGeometry::Point { pt: self.point.geom.pt });
-        results.push(RefGeometry::Point { pt: self.point_2.geom.pt });
-        results.push(RefGeometry::Line {
-            pt_1: self.point.geom.pt,
-            pt_2: self.point_2.geom.pt,
-        });
+        results.push((TestObj::FIRST, RefGeometry::Point { pt: self.point.geom.pt }));
+        results.push((TestObj::SECOND, RefGeometry::Point { pt: self.point_2.geom.pt }));
+        results.push((
+            TestObj::LINE,
+            RefGeometry::Line {
+                pt_1: self.point.geom.pt,
+                pt_2: self.point_2.geom.pt,
+            },
+        ));
         results
     }
-
-    fn get_num_results(&self) -> usize {
-        3
-    }
 }
 
 impl UpdateFromRefs for TestObj {
     fn get_refs(&self) -> Vec<Option<Reference>> {
         let mut results = Vec::new();
         if let Some(id) = &self.point.refer {
-            results.push(Some(Reference::new(self.id.clone(), 0, id.clone())));
+            results.push(Some(Reference::new(self.id.clone(), TestObj::FIRST, id.clone())));
         } else {
             results.push(None);
         }
         if let Some(id) = &self.point_2.refer {
-            results.push(Some(Reference::new(self.id.clone(), 1, id.clone())));
+            results.push(Some(Reference::new(self.id.clone(), TestObj::SECOND, id.clone())));
         } else {
             results.push(None);
         }
@@ -130,10 +136,10 @@ impl UpdateFromRefs for TestObj {
     fn get_available_refs(&self) -> Vec<ReferInd> {
         let mut results = Vec::new();
         if let None = self.point.refer {
-            results.push(0);
+            results.push(TestObj::FIRST);
         }
         if let None = self.point_2.refer {
-            results.push(1);
+            results.push(TestObj::SECOND);
         }
         results
     }
@@ -151,35 +157,37 @@ impl UpdateFromRefs for TestObj {
         false
     }
 
-    fn set_ref(&mut self, index: ReferInd, result: &RefGeometry, other_ref: GeometryId, snap_pt: &Option<Point3f>) {
-        match index {
-            0 => self.point.set_reference(result, other_ref, snap_pt),
-            1 => self.point_2.set_reference(result, other_ref, snap_pt),
-            _ => (),
+    fn set_ref(&mut self, index: &ReferInd, result: &RefGeometry, other_ref: GeometryId, snap_pt: &Option<Point3f>) {
+        if *index == TestObj::FIRST {
+            self.point.set_reference(result, other_ref, snap_pt);
+        } else if *index == TestObj::SECOND {
+            self.point_2.set_reference(result, other_ref, snap_pt);
         }
     }
 
-    fn delete_ref(&mut self, index: ReferInd) {
-        match index {
-            0 => self.point.refer = None,
-            1 => self.point_2.refer = None,
-            _ => (),
+    fn delete_ref(&mut self, index: &ReferInd) {
+        if *index == TestObj::FIRST {
+            self.point.refer = None;
+        } else if *index == TestObj::SECOND {
+            self.point_2.refer = None;
         }
     }
 
-    fn get_associated_geom(&self, index: ReferInd) -> Option<RefGeometry> {
-        match index {
-            0 => Some(self.point.geom.get_geom()),
-            1 => Some(self.point_2.geom.get_geom()),
-            _ => None,
+    fn get_associated_geom(&self, index: &ReferInd) -> Option<RefGeometry> {
+        if *index == TestObj::FIRST {
+            Some(self.point.geom.get_geom())
+        } else if *index == TestObj::SECOND {
+            Some(self.point_2.geom.get_geom())
+        } else {
+            None
         }
     }
 
-    fn set_associated_geom(&mut self, index: ReferInd, geom: &Option<RefGeometry>) {
-        match index {
-            0 => self.point.update(geom),
-            1 => self.point_2.update(geom),
-            _ => (),
+    fn set_associated_geom(&mut self, index: &ReferInd, geom: &Option<RefGeometry>) {
+        if *index == TestObj::FIRST {
+            self.point.update(geom);
+        } else if *index == TestObj::SECOND {
+            self.point_2.update(geom);
         }
     }
 }
@@ -0,0 +1,189 @@
+use crate::prelude::*;
+use crossbeam_channel::{Receiver, TryRecvError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+///The updates waiting to go out to one user for one file.  They're sent in batches, and an object that changed
+///several times since the last batch is only sent once, with its latest state.
+pub struct UpdateQueue {
+    receiver: Receiver<UpdateMsg>,
+    deltas: bool,
+    ///The last mesh sent for each object, so later ones can be sent as deltas.
+    sent: Mutex<HashMap<RefID, MeshData>>,
+    ///The kernel stopped sending, e.g. because the user fell too far behind.
+    closed: AtomicBool,
+}
+
+impl UpdateQueue {
+    ///With `deltas`, a mesh that only had a few vertices move is sent as a `MeshDelta`.
+    pub fn new(receiver: Receiver<UpdateMsg>, deltas: bool) -> UpdateQueue {
+        UpdateQueue {
+            receiver,
+            deltas,
+            sent: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    ///Takes everything waiting to be sent.
+    pub fn drain(&self) -> Vec<UpdateMsg> {
+        let mut msgs = Vec::new();
+        loop {
+            match self.receiver.try_recv() {
+                Ok(msg) => msgs.push(msg),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.closed.store(true, Ordering::SeqCst);
+                    break;
+                }
+            }
+        }
+        self.coalesce(msgs)
+    }
+
+    ///Whether a `drain` found nothing more will be sent.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    ///Like `drain`, but waits for at least one update.
+    pub fn wait(&self) -> Result<Vec<UpdateMsg>, DBError> {
+        let first = self.receiver.recv().map_err(error_other)?;
+        let mut msgs = vec![first];
+        msgs.extend(self.receiver.try_iter());
+        Ok(self.coalesce(msgs))
+    }
+
+    fn coalesce(&self, msgs: Vec<UpdateMsg>) -> Vec<UpdateMsg> {
+        let mut latest = HashMap::new();
+        for (i, msg) in msgs.iter().enumerate() {
+            if let Some(key) = msg.get_key() {
+                latest.insert(key, i);
+            }
+        }
+        msgs.into_iter()
+            .enumerate()
+            .filter(|(i, msg)| match msg.get_key() {
+                Some(key) => latest[&key] == *i,
+                None => true,
+            })
+            .map(|(_, msg)| self.track(msg))
+            .collect()
+    }
+
+    ///Remembers what the client has, and swaps meshes for deltas where that's smaller.
+    fn track(&self, msg: UpdateMsg) -> UpdateMsg {
+        if !self.deltas {
+            return msg;
+        }
+        let mut sent = self.sent.lock().expect("Poisoned mutex");
+        match msg {
+            UpdateMsg::Sequenced { seq, msg } => {
+                drop(sent);
+                UpdateMsg::Sequenced {
+                    seq,
+                    msg: Box::new(self.track(*msg)),
+                }
+            }
+            UpdateMsg::Mesh { data } => {
+                let delta = sent.get(&data.id).and_then(|old| old.diff(&data));
+                sent.insert(data.id, data.clone());
+                match delta {
+                    Some(delta) => UpdateMsg::MeshDelta { data: delta },
+                    None => UpdateMsg::Mesh { data },
+                }
+            }
+            UpdateMsg::Delete { key } => {
+                sent.remove(&key);
+                UpdateMsg::Delete { key }
+            }
+            UpdateMsg::Synced { full: true, .. } => {
+                //The client is starting over
+                sent.clear();
+                msg
+            }
+            msg => msg,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh(id: &RefID, x: f32) -> UpdateMsg {
+        UpdateMsg::Mesh {
+            data: MeshData {
+                id: id.clone(),
+                positions: vec![x, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 1.0],
+                indices: vec![0, 1, 2, 1, 2, 3],
+                metadata: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let (s, r) = crossbeam_channel::unbounded();
+        let queue = UpdateQueue::new(r, false);
+        let (id_1, id_2) = (RefID::new_v4(), RefID::new_v4());
+        s.send(mesh(&id_1, 1.0)).unwrap();
+        s.send(mesh(&id_2, 1.0)).unwrap();
+        s.send(UpdateMsg::Modified { modified: true }).unwrap();
+        s.send(mesh(&id_1, 2.0)).unwrap();
+        s.send(UpdateMsg::Sequenced {
+            seq: 1,
+            msg: Box::new(mesh(&id_1, 3.0)),
+        })
+        .unwrap();
+        s.send(mesh(&id_2, 2.0)).unwrap();
+        s.send(UpdateMsg::Delete { key: id_2 }).unwrap();
+        assert_eq!(
+            queue.drain(),
+            vec![
+                UpdateMsg::Modified { modified: true },
+                UpdateMsg::Sequenced {
+                    seq: 1,
+                    msg: Box::new(mesh(&id_1, 3.0)),
+                },
+                UpdateMsg::Delete { key: id_2 },
+            ]
+        );
+        assert_eq!(queue.drain(), Vec::new());
+        assert!(!queue.is_closed());
+        drop(s);
+        queue.drain();
+        assert!(queue.is_closed());
+    }
+
+    #[test]
+    fn test_deltas() {
+        let (s, r) = crossbeam_channel::unbounded();
+        let queue = UpdateQueue::new(r, true);
+        let id = RefID::new_v4();
+        s.send(mesh(&id, 1.0)).unwrap();
+        assert_eq!(queue.drain(), vec![mesh(&id, 1.0)]);
+        s.send(mesh(&id, 2.0)).unwrap();
+        let delta = MeshDelta {
+            id,
+            vertices: vec![0],
+            positions: vec![2.0, 0.0, 0.0],
+        };
+        assert_eq!(queue.wait(), Ok(vec![UpdateMsg::MeshDelta { data: delta }]));
+
+        //Once it's deleted, or the client starts over, the next one has to be whole
+        s.send(UpdateMsg::Delete { key: id }).unwrap();
+        queue.drain();
+        s.send(mesh(&id, 3.0)).unwrap();
+        assert_eq!(queue.drain(), vec![mesh(&id, 3.0)]);
+        s.send(UpdateMsg::Synced {
+            session: SessionID::new_v4(),
+            seq: 0,
+            full: true,
+        })
+        .unwrap();
+        queue.drain();
+        s.send(mesh(&id, 4.0)).unwrap();
+        assert_eq!(queue.drain(), vec![mesh(&id, 4.0)]);
+    }
+}
@@ -0,0 +1,205 @@
+use data_model::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+///What a user can do with a file.  Viewers only get read-only sessions, and only owners can change roles.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Viewer,
+    Editor,
+    Owner,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub id: UserID,
+    pub name: String,
+    ///Sent as an `Authorization: Bearer` header when connecting, or as `?token=` where headers can't be set.
+    pub token: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Store {
+    users: Vec<Account>,
+    files: HashMap<PathBuf, HashMap<UserID, Role>>,
+}
+
+///The accounts that can connect, and who can do what with each file.  Kept in a JSON file, which is replaced on
+///every change.
+pub struct Auth {
+    path: Option<PathBuf>,
+    store: RwLock<Store>,
+}
+
+impl Auth {
+    ///Starts with no accounts if there's nothing at `path` yet.
+    pub fn load(path: PathBuf) -> Result<Auth, DBError> {
+        let store = if path.exists() {
+            let text = std::fs::read_to_string(&path).map_err(error_other)?;
+            serde_json::from_str(&text).map_err(error_other)?
+        } else {
+            Store::default()
+        };
+        Ok(Auth {
+            path: Some(path),
+            store: RwLock::new(store),
+        })
+    }
+
+    ///Nothing is saved.
+    pub fn in_memory() -> Auth {
+        Auth {
+            path: None,
+            store: RwLock::new(Store::default()),
+        }
+    }
+
+    ///Written to a temporary file first, so a crash can't leave the accounts half written.
+    fn save(&self, store: &Store) -> Result<(), DBError> {
+        match &self.path {
+            Some(path) => operations_kernel::write_atomic(path, 0, |writer| {
+                serde_json::to_writer_pretty(writer, store).map_err(error_other)
+            }),
+            None => Ok(()),
+        }
+    }
+
+    ///Creates an account with a new random token.
+    pub fn add_user(&self, name: String) -> Result<Account, DBError> {
+        let account = Account {
+            id: UserID::new_v4(),
+            name,
+            token: UserID::new_v4().to_simple().to_string(),
+        };
+        let mut store = self.store.write().expect("Poisoned lock");
+        store.users.push(account.clone());
+        self.save(&store)?;
+        Ok(account)
+    }
+
+    pub fn authenticate(&self, token: &str) -> Option<UserID> {
+        let store = self.store.read().expect("Poisoned lock");
+        store
+            .users
+            .iter()
+            .find(|account| account.token == token)
+            .map(|account| account.id)
+    }
+
+    pub fn get_role(&self, file: &PathBuf, user: &UserID) -> Option<Role> {
+        let store = self.store.read().expect("Poisoned lock");
+        store
+            .files
+            .get(file)
+            .and_then(|roles| roles.get(user))
+            .cloned()
+    }
+
+    ///Returns the user's role on a file they're opening.  Whoever opens a file nobody has a role on becomes its owner.
+    pub fn open(&self, file: &PathBuf, user: &UserID) -> Result<Role, DBError> {
+        let mut store = self.store.write().expect("Poisoned lock");
+        let roles = store.files.entry(file.clone()).or_insert_with(HashMap::new);
+        if roles.is_empty() {
+            roles.insert(*user, Role::Owner);
+            self.save(&store)?;
+            return Ok(Role::Owner);
+        }
+        roles
+            .get(user)
+            .cloned()
+            .ok_or_else(|| DBError::Forbidden(format!("No access to {:?}", file)))
+    }
+
+    ///Only owners can give other users access, or take it away with `None`.
+    pub fn set_role(
+        &self,
+        file: &PathBuf,
+        by: &UserID,
+        user: UserID,
+        role: Option<Role>,
+    ) -> Result<(), DBError> {
+        let mut store = self.store.write().expect("Poisoned lock");
+        let roles = store.files.entry(file.clone()).or_insert_with(HashMap::new);
+        if roles.get(by) != Some(&Role::Owner) {
+            return Err(DBError::Forbidden(format!(
+                "Only owners can change roles on {:?}",
+                file
+            )));
+        }
+        match role {
+            Some(role) => roles.insert(user, role),
+            None => roles.remove(&user),
+        };
+        if !roles.values().any(|role| *role == Role::Owner) {
+            //Put it back, so the file isn't left without an owner
+            roles.insert(*by, Role::Owner);
+            return Err(DBError::Forbidden(format!(
+                "{:?} has to keep an owner",
+                file
+            )));
+        }
+        self.save(&store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roles() {
+        let auth = Auth::in_memory();
+        let owner = auth.add_user("owner".to_string()).unwrap();
+        let other = auth.add_user("other".to_string()).unwrap();
+        assert_eq!(auth.authenticate(&owner.token), Some(owner.id));
+        assert_eq!(auth.authenticate(&other.token), Some(other.id));
+        assert_eq!(auth.authenticate("not a token"), None);
+
+        let file = PathBuf::from("test.flx");
+        assert_eq!(auth.open(&file, &owner.id), Ok(Role::Owner));
+        assert_eq!(auth.open(&file, &owner.id), Ok(Role::Owner));
+        assert!(auth.open(&file, &other.id).is_err());
+        assert!(auth
+            .set_role(&file, &other.id, other.id, Some(Role::Editor))
+            .is_err());
+
+        auth.set_role(&file, &owner.id, other.id, Some(Role::Viewer))
+            .unwrap();
+        assert_eq!(auth.open(&file, &other.id), Ok(Role::Viewer));
+        assert_eq!(auth.get_role(&file, &other.id), Some(Role::Viewer));
+        assert!(auth
+            .set_role(&file, &other.id, other.id, Some(Role::Owner))
+            .is_err());
+
+        //The last owner can't leave
+        assert!(auth.set_role(&file, &owner.id, owner.id, None).is_err());
+        assert_eq!(auth.get_role(&file, &owner.id), Some(Role::Owner));
+        auth.set_role(&file, &owner.id, other.id, None).unwrap();
+        assert_eq!(auth.get_role(&file, &other.id), None);
+    }
+
+    #[test]
+    fn test_save() {
+        let path = std::env::temp_dir().join(format!("users_{}.json", RefID::new_v4().to_simple()));
+        let auth = Auth::load(path.clone()).unwrap();
+        let owner = auth.add_user("owner".to_string()).unwrap();
+        let file = PathBuf::from("test.flx");
+        auth.open(&file, &owner.id).unwrap();
+
+        let loaded = Auth::load(path.clone()).unwrap();
+        assert_eq!(loaded.authenticate(&owner.token), Some(owner.id));
+        assert_eq!(loaded.get_role(&file, &owner.id), Some(Role::Owner));
+        //Nothing is left behind from writing it
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        let leftovers = std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|entry| entry.starts_with(&name) && *entry != name)
+            .count();
+        assert_eq!(leftovers, 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,348 @@
+use crate::auth::Role;
+use crate::encoding;
+use data_model::*;
+use operations_kernel::{GraphFormat, OverflowPolicy, ProjectInfo, UndoLimits, Units};
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+///Everything a client can ask for.  Each one arrives as
+///`{"request_id": ..., "func_name": "begin_undo_event", "params": [path, event, desc]}`,
+///with exactly the parameters listed, in order.  Every request gets an `Ack` or an `Error` with its
+///request id, after the updates it caused.  Failures in work that carries on after that, like opening
+///the file for `init_file` or updating dependents outside atomic events, come as errors without one.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "func_name", content = "params", rename_all = "snake_case")]
+pub enum Command {
+    InitFile(#[serde(deserialize_with = "single")] PathBuf),
+    ReconnectFile(PathBuf, SessionID, u64),
+    CloseFile(#[serde(deserialize_with = "single")] PathBuf),
+    SetOverflowPolicy(PathBuf, OverflowPolicy),
+    GetQueueStats(PathBuf, QueryID),
+    ///Owners only.  A null role takes the user's access away.
+    SetFileRole(PathBuf, UserID, Option<Role>),
+    BeginUndoEvent(PathBuf, UndoEventID, String),
+    BeginAtomicEvent(PathBuf, UndoEventID, String),
+    EndUndoEvent(PathBuf, UndoEventID),
+    UndoLatest(#[serde(deserialize_with = "single")] PathBuf),
+    RedoLatest(#[serde(deserialize_with = "single")] PathBuf),
+    SetUndoLimits(PathBuf, UndoLimits),
+    SetUserInfo(PathBuf, String, String),
+    SetCursor(PathBuf, Point3f),
+    SetSelection(PathBuf, Vec<RefID>),
+    GetUsers(PathBuf, QueryID),
+    GetLocks(PathBuf, QueryID),
+    GetHistory(PathBuf, QueryID),
+    UndoTo(PathBuf, UndoEventID),
+    RevertEvent(PathBuf, UndoEventID),
+    SuspendEvent(PathBuf, UndoEventID),
+    ResumeEvent(PathBuf, UndoEventID),
+    CancelEvent(PathBuf, UndoEventID),
+    TakeUndoSnapshot(PathBuf, UndoEventID, RefID),
+    JoinAtPoints(PathBuf, UndoEventID, RefID, RefID, Point3f),
+    SnapToLine(PathBuf, UndoEventID, RefID, RefID, Point3f),
+    SnapToPoint(PathBuf, UndoEventID, RefID, RefID, Point3f),
+    ///Points one of the object's references at a result, like the `refer` of a snap, with the point it was snapped at.
+    SetRef(
+        PathBuf,
+        UndoEventID,
+        RefID,
+        ReferInd,
+        GeometryId,
+        Option<Point3f>,
+    ),
+    GetClosestPoint(PathBuf, RefID, Point3f, QueryID),
+    ///Answers with the best snap, whose `refer` or `other_refer` can be attached to with `set_ref`.
+    SnapPoint(
+        PathBuf,
+        Point3f,
+        WorldCoord,
+        Option<Point3f>,
+        Option<WorldCoord>,
+        QueryID,
+    ),
+    ///Seconds between autosaves, or null to stop autosaving.
+    SetAutosave(PathBuf, Option<u64>),
+    SetBackupCount(PathBuf, usize),
+    SetHistoryDepth(PathBuf, usize),
+    GetModified(PathBuf, QueryID),
+    GetFileHeader(PathBuf, QueryID),
+    SetFileInfo(PathBuf, Units, ProjectInfo),
+    GetDepCycles(PathBuf, QueryID),
+    ExportDeps(PathBuf, GraphFormat, QueryID),
+    ///The type name, then the object itself.
+    AddObject(PathBuf, UndoEventID, String, serde_json::Value),
+    MoveObject(PathBuf, UndoEventID, RefID, Vector3f),
+    DeleteObject(PathBuf, UndoEventID, RefID),
+    GetObjectData(PathBuf, RefID, String, QueryID),
+    SetObjectData(PathBuf, UndoEventID, RefID, serde_json::Value),
+    SetObjectDatas(PathBuf, UndoEventID, Vec<(RefID, serde_json::Value)>),
+    MoveObjects(PathBuf, UndoEventID, HashSet<RefID>, Vector3f),
+    CopyObjects(PathBuf, UndoEventID, HashSet<RefID>, QueryID),
+    Demo(PathBuf, Point3f),
+    #[serde(rename = "demo_100")]
+    Demo100(PathBuf, Point3f),
+}
+
+///Picks the file path out of a command, so `path` and `path_mut` share one list of commands.
+macro_rules! command_path {
+    ($command:expr) => {
+        match $command {
+            Command::InitFile(path)
+            | Command::ReconnectFile(path, ..)
+            | Command::CloseFile(path)
+            | Command::SetOverflowPolicy(path, ..)
+            | Command::GetQueueStats(path, ..)
+            | Command::SetFileRole(path, ..)
+            | Command::BeginUndoEvent(path, ..)
+            | Command::BeginAtomicEvent(path, ..)
+            | Command::EndUndoEvent(path, ..)
+            | Command::UndoLatest(path)
+            | Command::RedoLatest(path)
+            | Command::SetUndoLimits(path, ..)
+            | Command::SetUserInfo(path, ..)
+            | Command::SetCursor(path, ..)
+            | Command::SetSelection(path, ..)
+            | Command::GetUsers(path, ..)
+            | Command::GetLocks(path, ..)
+            | Command::GetHistory(path, ..)
+            | Command::UndoTo(path, ..)
+            | Command::RevertEvent(path, ..)
+            | Command::SuspendEvent(path, ..)
+            | Command::ResumeEvent(path, ..)
+            | Command::CancelEvent(path, ..)
+            | Command::TakeUndoSnapshot(path, ..)
+            | Command::JoinAtPoints(path, ..)
+            | Command::SnapToLine(path, ..)
+            | Command::SnapToPoint(path, ..)
+            | Command::SetRef(path, ..)
+            | Command::GetClosestPoint(path, ..)
+            | Command::SnapPoint(path, ..)
+            | Command::SetAutosave(path, ..)
+            | Command::SetBackupCount(path, ..)
+            | Command::SetHistoryDepth(path, ..)
+            | Command::GetModified(path, ..)
+            | Command::GetFileHeader(path, ..)
+            | Command::SetFileInfo(path, ..)
+            | Command::GetDepCycles(path, ..)
+            | Command::ExportDeps(path, ..)
+            | Command::AddObject(path, ..)
+            | Command::MoveObject(path, ..)
+            | Command::DeleteObject(path, ..)
+            | Command::GetObjectData(path, ..)
+            | Command::SetObjectData(path, ..)
+            | Command::SetObjectDatas(path, ..)
+            | Command::MoveObjects(path, ..)
+            | Command::CopyObjects(path, ..)
+            | Command::Demo(path, ..)
+            | Command::Demo100(path, ..) => path,
+        }
+    };
+}
+
+impl Command {
+    ///Every command is about one file.
+    pub fn path(&self) -> &PathBuf {
+        command_path!(self)
+    }
+
+    pub fn path_mut(&mut self) -> &mut PathBuf {
+        command_path!(self)
+    }
+
+    ///Whether the command changes the file or its settings, so viewers can't send it.
+    ///Cursors, selections and other per-session state don't count.
+    pub fn is_mutating(&self) -> bool {
+        match self {
+            Command::InitFile(..)
+            | Command::ReconnectFile(..)
+            | Command::CloseFile(..)
+            | Command::SetOverflowPolicy(..)
+            | Command::GetQueueStats(..)
+            | Command::SetUserInfo(..)
+            | Command::SetCursor(..)
+            | Command::SetSelection(..)
+            | Command::GetUsers(..)
+            | Command::GetLocks(..)
+            | Command::GetHistory(..)
+            | Command::GetClosestPoint(..)
+            | Command::SnapPoint(..)
+            | Command::GetModified(..)
+            | Command::GetFileHeader(..)
+            | Command::GetDepCycles(..)
+            | Command::ExportDeps(..)
+            | Command::GetObjectData(..) => false,
+            _ => true,
+        }
+    }
+}
+
+///Commands with one parameter still send it in a list.
+fn single<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    let (val,) = <(T,)>::deserialize(deserializer)?;
+    Ok(val)
+}
+
+///Reads a message from a client.  If it can't be read, the request id is still returned when there is one,
+///so the error can be sent back for the right request.
+pub fn parse(text: &str) -> Result<(RequestID, Command), (Option<RequestID>, DBError)> {
+    let value =
+        serde_json::from_str(text).map_err(|e| (None, DBError::BadRequest(e.to_string())))?;
+    parse_value(value)
+}
+
+///Reads a MessagePack message from a client.
+pub fn parse_packed(bytes: &[u8]) -> Result<(RequestID, Command), (Option<RequestID>, DBError)> {
+    let value = encoding::decode(bytes).map_err(|e| (None, e))?;
+    parse_value(value)
+}
+
+fn parse_value(
+    value: serde_json::Value,
+) -> Result<(RequestID, Command), (Option<RequestID>, DBError)> {
+    let bad_request = |e: serde_json::Error| DBError::BadRequest(e.to_string());
+    let request_id = value
+        .get("request_id")
+        .and_then(|id| RequestID::deserialize(id).ok());
+    let msg: CmdMsg = serde_json::from_value(value).map_err(|e| (request_id, bad_request(e)))?;
+    let command = serde_json::from_value(json!({
+        "func_name": msg.func_name,
+        "params": msg.params,
+    }))
+    .map_err(|e| (Some(msg.request_id), bad_request(e)))?;
+    Ok((msg.request_id, command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_err(text: &str) -> (Option<RequestID>, DBError) {
+        match parse(text) {
+            Ok((_, command)) => panic!("Expected an error, got {:?}", command),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let request_id = RequestID::new_v4();
+        let event = UndoEventID::new_v4();
+        let text = json!({
+            "request_id": request_id,
+            "func_name": "begin_undo_event",
+            "params": ["test.flx", event, "desc"],
+        });
+        match parse(&text.to_string()) {
+            Ok((id, Command::BeginUndoEvent(path, ev, desc))) => {
+                assert_eq!(id, request_id);
+                assert_eq!(
+                    (path, ev, desc.as_str()),
+                    (PathBuf::from("test.flx"), event, "desc")
+                );
+            }
+            other => panic!("Expected begin_undo_event, got {:?}", other),
+        }
+        let text =
+            json!({"request_id": request_id, "func_name": "init_file", "params": ["test.flx"]});
+        match parse(&text.to_string()) {
+            Ok((_, Command::InitFile(path))) => assert_eq!(path, PathBuf::from("test.flx")),
+            other => panic!("Expected init_file, got {:?}", other),
+        }
+        let (id, wall) = (RefID::new_v4(), RefID::new_v4());
+        let text = json!({
+            "request_id": request_id,
+            "func_name": "set_ref",
+            "params": ["test.flx", event, id, {"Named": "First"}, {"id": wall, "index": {"Named": "SecondLeft"}}, null],
+        });
+        match parse(&text.to_string()) {
+            Ok((_, Command::SetRef(_, ev, obj, index, refer, snap_pt))) => {
+                assert_eq!((ev, obj, index), (event, id, Dimension::FIRST));
+                assert_eq!(refer, GeometryId::new(wall, Wall::SECOND_LEFT));
+                assert_eq!(snap_pt, None);
+            }
+            other => panic!("Expected set_ref, got {:?}", other),
+        }
+        let text = json!({"request_id": request_id, "func_name": "demo_100", "params": ["test.flx", [0.0, 0.0, 0.0]]});
+        assert!(parse(&text.to_string()).is_ok());
+        let packed = rmp_serde::to_vec_named(&text).unwrap();
+        match parse_packed(&packed) {
+            Ok((id, Command::Demo100(..))) => assert_eq!(id, request_id),
+            other => panic!("Expected demo_100, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_mutating() {
+        let path = PathBuf::from("test.flx");
+        let event = UndoEventID::new_v4();
+        let id = RefID::new_v4();
+        let reads = vec![
+            Command::InitFile(path.clone()),
+            Command::SetCursor(path.clone(), Point3f::new(0.0, 0.0, 0.0)),
+            Command::GetObjectData(path.clone(), id, "Length".to_string(), QueryID::new_v4()),
+        ];
+        let writes = vec![
+            Command::BeginUndoEvent(path.clone(), event, "desc".to_string()),
+            Command::UndoLatest(path.clone()),
+            Command::DeleteObject(path.clone(), event, id),
+            Command::SetAutosave(path.clone(), None),
+            Command::SetFileRole(path.clone(), UserID::new_v4(), Some(Role::Editor)),
+            Command::SetRef(
+                path.clone(),
+                event,
+                id,
+                Dimension::FIRST,
+                GeometryId::new(RefID::new_v4(), Wall::RIGHT_FACE),
+                None,
+            ),
+        ];
+        for command in &reads {
+            assert!(!command.is_mutating(), "{:?}", command);
+            assert_eq!(command.path(), &path);
+        }
+        for command in &writes {
+            assert!(command.is_mutating(), "{:?}", command);
+            assert_eq!(command.path(), &path);
+        }
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        let request_id = RequestID::new_v4();
+        let bad_request = |e: (Option<RequestID>, DBError)| match e {
+            (id, DBError::BadRequest(_)) => id,
+            (_, e) => panic!("Expected a bad request, got {:?}", e),
+        };
+        assert_eq!(bad_request(parse_err("{not json")), None);
+        assert_eq!(
+            bad_request(parse_err(
+                r#"{"func_name": "init_file", "params": ["test.flx"]}"#
+            )),
+            None
+        );
+        //Unknown command, missing and extra parameters, and the wrong type
+        for (func, params) in vec![
+            ("no_such_thing", json!(["test.flx"])),
+            ("undo_to", json!(["test.flx"])),
+            ("init_file", json!(["test.flx", "other.flx"])),
+            ("init_file", json!("test.flx")),
+            ("undo_to", json!(["test.flx", "not an id"])),
+            ("set_backup_count", json!(["test.flx", -1])),
+        ] {
+            let text = json!({"request_id": request_id, "func_name": func, "params": params});
+            assert_eq!(
+                bad_request(parse_err(&text.to_string())),
+                Some(request_id),
+                "{} {:?}",
+                func,
+                params
+            );
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use data_model::*;
+use serde::{Deserialize, Serialize};
+
+///How updates are written to a client, picked with `?encoding=` when connecting.
+///MessagePack is much smaller for meshes, but JSON is the default since it's easier to read while debugging.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Encoding {
+    #[serde(rename = "json")]
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Json
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Encoding {
+    pub fn encode<T: Serialize>(&self, msg: &T) -> Result<Frame, DBError> {
+        match self {
+            Encoding::Json => serde_json::to_string(msg)
+                .map(Frame::Text)
+                .map_err(error_other),
+            Encoding::MessagePack => {
+                let mut buf = Vec::new();
+                //Keep field names, and ids as strings, so clients see the same shapes as with JSON
+                let mut ser = rmp_serde::Serializer::new(&mut buf)
+                    .with_struct_map()
+                    .with_human_readable();
+                msg.serialize(&mut ser).map_err(error_other)?;
+                Ok(Frame::Binary(buf))
+            }
+        }
+    }
+}
+
+///Binary messages from clients are always MessagePack.
+pub fn decode(bytes: &[u8]) -> Result<serde_json::Value, DBError> {
+    rmp_serde::from_slice(bytes).map_err(|e| DBError::BadRequest(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_encodings() {
+        let mut data = MeshData {
+            id: RefID::new_v4(),
+            positions: Vec::new(),
+            indices: vec![0, 1, 2],
+            metadata: Some(json!({"type": "Wall"})),
+        };
+        data.push_pt(Point3f::new(1.0, 2.0, 3.0));
+        data.push_pt(Point3f::new(0.5, 0.0, 0.0));
+        data.push_pt(Point3f::new(0.0, 0.0, 0.25));
+        let msg = UpdateMsg::Sequenced {
+            seq: 1,
+            msg: Box::new(UpdateMsg::Mesh { data }),
+        };
+        let json = match Encoding::Json.encode(&msg) {
+            Ok(Frame::Text(text)) => text,
+            other => panic!("Expected text, got {:?}", other),
+        };
+        let packed = match Encoding::MessagePack.encode(&msg) {
+            Ok(Frame::Binary(bytes)) => bytes,
+            other => panic!("Expected binary, got {:?}", other),
+        };
+        assert!(packed.len() < json.len());
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(decode(&packed), Ok(json));
+        assert!(decode(&[0xc1]).is_err());
+    }
+}
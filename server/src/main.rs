@@ -16,20 +16,32 @@ extern crate structopt;
 use actix::prelude::Future;
 use actix_web::dev::Service;
 use actix_web::{web, App, HttpServer};
+use auth::Auth;
 use log::LevelFilter;
+use project::ProjectRoot;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
+mod auth;
+mod commands;
+mod encoding;
+mod project;
 mod ws_actor;
 
-pub fn start(url: &str, ws_port: u16, http_port: u16) {
+pub fn start(url: &str, ws_port: u16, http_port: u16, auth: Auth, root: ProjectRoot) {
+    let auth = web::Data::new(auth);
+    let root = web::Data::new(root);
     let ws_url = format!("{}:{}", url, ws_port);
     let http_url = format!("{}:{}", url, http_port);
     HttpServer::new(move || {
         App::new()
+            .register_data(auth.clone())
+            .register_data(root.clone())
             .wrap_fn(|req, srv| {
-                println!("Incoming request: {:?}", req);
+                //Only the path, since the query can hold the user's token
+                info!("Incoming request: {} {}", req.method(), req.path());
                 srv.call(req).map(|res| {
-                    println!("Response: {:?}", res);
+                    debug!("Response: {}", res.status());
                     res
                 })
             })
@@ -62,6 +74,18 @@ struct Opt {
     ///Logging level, from 0-5 where 0 is off
     #[structopt(short = "l", long = "log", default_value = "3")]
     log: u8,
+
+    ///Accounts and file roles
+    #[structopt(long = "users", default_value = "users.json", parse(from_os_str))]
+    users: PathBuf,
+
+    ///Where files are opened from.  Clients can't reach anything outside it
+    #[structopt(long = "root", default_value = ".", parse(from_os_str))]
+    root: PathBuf,
+
+    ///Create an account with this name, print its token and exit
+    #[structopt(long = "add-user")]
+    add_user: Option<String>,
 }
 
 fn main() {
@@ -94,5 +118,12 @@ fn main() {
         .apply()
         .unwrap();
 
-    start(&opt.url, opt.ws_port, opt.http_port);
+    let auth = Auth::load(opt.users.clone()).expect("couldn't read users");
+    if let Some(name) = opt.add_user {
+        let account = auth.add_user(name).expect("couldn't add user");
+        println!("Added {} with token {}", account.name, account.token);
+        return;
+    }
+    let root = ProjectRoot::new(&opt.root).expect("couldn't find the project root");
+    start(&opt.url, opt.ws_port, opt.http_port, auth, root);
 }
@@ -0,0 +1,88 @@
+use data_model::*;
+use std::path::{Path, PathBuf};
+
+///The directory files are opened from.  Clients name files relative to it, and every path is resolved to where the
+///file really is, so a file has the same key for roles and open files however it's named.
+pub struct ProjectRoot {
+    root: PathBuf,
+}
+
+impl ProjectRoot {
+    pub fn new(root: &Path) -> Result<ProjectRoot, DBError> {
+        let root = root.canonicalize().map_err(error_other)?;
+        if !root.is_dir() {
+            return Err(DBError::Other(format!("{:?} isn't a directory", root)));
+        }
+        Ok(ProjectRoot { root })
+    }
+
+    ///Fails with `DBError::Forbidden` for anything outside the root, including through symlinks.  The file doesn't have
+    ///to exist yet, but the directory it goes in does.
+    pub fn resolve(&self, path: &Path) -> Result<PathBuf, DBError> {
+        let outside = || DBError::Forbidden(format!("{:?} is outside the project", path));
+        let joined = self.root.join(path);
+        let resolved = match joined.canonicalize() {
+            Ok(resolved) => resolved,
+            //Something's there but can't be followed, like a broken symlink, which could still be written through
+            Err(_) if joined.symlink_metadata().is_ok() => return Err(outside()),
+            Err(_) => {
+                let name = joined.file_name().ok_or_else(outside)?;
+                let parent = joined
+                    .parent()
+                    .ok_or_else(outside)?
+                    .canonicalize()
+                    .map_err(|_| DBError::NotFound(format!("{:?}", path)))?;
+                parent.join(name)
+            }
+        };
+        if resolved.starts_with(&self.root) && resolved != self.root {
+            Ok(resolved)
+        } else {
+            Err(outside())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Auth, Role};
+
+    #[test]
+    fn test_resolve() {
+        let dir = std::env::temp_dir().join(format!("project_{}", RefID::new_v4().to_simple()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let root = ProjectRoot::new(&dir).unwrap();
+        let file = root.resolve(&PathBuf::from("test.flx")).unwrap();
+        assert_eq!(file, dir.canonicalize().unwrap().join("test.flx"));
+        //Every name for the file comes out the same, whether it exists yet or not
+        for _ in 0..2 {
+            for alias in &["./test.flx", "sub/../test.flx"] {
+                assert_eq!(
+                    root.resolve(&PathBuf::from(alias)),
+                    Ok(file.clone()),
+                    "{}",
+                    alias
+                );
+            }
+            assert_eq!(root.resolve(&dir.join("test.flx")), Ok(file.clone()));
+            std::fs::write(&file, "").unwrap();
+        }
+        for outside in &["../test.flx", "/etc/passwd", ".", "sub/../../test.flx"] {
+            match root.resolve(&PathBuf::from(outside)) {
+                Err(DBError::Forbidden(_)) => (),
+                other => panic!("Expected {} to be outside, got {:?}", outside, other),
+            }
+        }
+
+        //Roles follow the file rather than the name it was opened with
+        let auth = Auth::in_memory();
+        let owner = auth.add_user("owner".to_string()).unwrap();
+        let other = auth.add_user("other".to_string()).unwrap();
+        let alias = root.resolve(&PathBuf::from("./test.flx")).unwrap();
+        assert_eq!(auth.open(&file, &owner.id), Ok(Role::Owner));
+        assert_eq!(auth.get_role(&alias, &owner.id), Some(Role::Owner));
+        assert!(auth.open(&alias, &other.id).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,16 @@
 use std::time::{Duration, Instant};
 
+use crate::auth::{Auth, Role};
+use crate::commands::{self, Command};
+use crate::encoding::{Encoding, Frame};
+use crate::project::ProjectRoot;
 use actix::prelude::*;
+use actix_web::http::header;
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
 use ccl::dhashmap::DHashMap;
-use crossbeam_channel::Receiver;
 use data_model::*;
+use operations_kernel::UpdateQueue;
 use serde::Deserialize;
 use std::path::PathBuf;
 
@@ -15,14 +20,19 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
 /// How long before lack of client response causes a timeout
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
-
-fn error<T: std::fmt::Debug>(err: T) -> String {
-    format!("ERROR: {:?}", err)
-}
+///How many updates can wait for each file before the user's overflow policy applies
+const UPDATE_QUEUE_LENGTH: usize = 4096;
 
 #[derive(Deserialize)]
 pub struct User {
-    user_id: UserID,
+    ///For clients that can't set an `Authorization: Bearer` header, like browsers opening a websocket.
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    encoding: Encoding,
+    ///Send meshes that only moved a little as deltas.
+    #[serde(default)]
+    deltas: bool,
 }
 
 /// do websocket handshake and start `MyWebSocket` actor
@@ -30,9 +40,29 @@ pub fn ws_index(
     r: HttpRequest,
     user: web::Query<User>,
     stream: web::Payload,
+    auth: web::Data<Auth>,
+    root: web::Data<ProjectRoot>,
 ) -> Result<HttpResponse, Error> {
-    let res = ws::start(MyWebSocket::new(user.user_id), &r, stream);
-    info!("{:?}", res.as_ref().unwrap());
+    let bearer = r
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| value.starts_with("Bearer "))
+        .map(|value| &value["Bearer ".len()..]);
+    let token = bearer.or(user.token.as_ref().map(String::as_str));
+    let id = match token.and_then(|token| auth.authenticate(token)) {
+        Some(id) => id,
+        None => {
+            warn!("Rejected a connection with an unknown token");
+            return Ok(HttpResponse::Unauthorized().finish());
+        }
+    };
+    let res = ws::start(
+        MyWebSocket::new(id, auth, root, user.encoding, user.deltas),
+        &r,
+        stream,
+    );
+    info!("{:?}", res);
     res
 }
 
@@ -43,7 +73,11 @@ struct MyWebSocket {
     /// otherwise we drop connection.
     hb: Instant,
     id: UserID,
-    updates: DHashMap<PathBuf, Receiver<UpdateMsg>>,
+    auth: web::Data<Auth>,
+    root: web::Data<ProjectRoot>,
+    encoding: Encoding,
+    deltas: bool,
+    updates: DHashMap<PathBuf, UpdateQueue>,
 }
 
 impl Actor for MyWebSocket {
@@ -67,18 +101,8 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for MyWebSocket {
             ws::Message::Pong(_) => {
                 self.hb = Instant::now();
             }
-            ws::Message::Text(msg) => {
-                let cmd: data_model::CmdMsg = serde_json::from_str(&msg).unwrap();
-                info!("{:?}", cmd);
-                if let Err(e) = self.route(cmd) {
-                    error!("{:?}", e);
-                    let msg = data_model::UpdateMsg::Error {
-                        msg: format!("{:?}", e),
-                    };
-                    ctx.text(serde_json::to_string(&msg).unwrap());
-                }
-            }
-            ws::Message::Binary(_) => (),
+            ws::Message::Text(text) => self.run(commands::parse(&text), ctx),
+            ws::Message::Binary(bytes) => self.run(commands::parse_packed(&bytes), ctx),
             ws::Message::Close(_) => {
                 ctx.stop();
             }
@@ -88,82 +112,178 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for MyWebSocket {
 }
 
 impl MyWebSocket {
-    fn new(id: UserID) -> Self {
+    fn new(
+        id: UserID,
+        auth: web::Data<Auth>,
+        root: web::Data<ProjectRoot>,
+        encoding: Encoding,
+        deltas: bool,
+    ) -> Self {
         Self {
             hb: Instant::now(),
             id: id,
+            auth: auth,
+            root: root,
+            encoding: encoding,
+            deltas: deltas,
             updates: DHashMap::default(),
         }
     }
 
-    fn route(&self, mut msg: CmdMsg) -> Result<(), String> {
-        let now = std::time::SystemTime::now();
-        let answer = match msg.func_name.as_ref() {
-            "init_file" => {
-                let (s, r) = crossbeam_channel::unbounded();
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
+    fn send(&self, msg: &UpdateMsg, ctx: &mut <Self as Actor>::Context) {
+        match self.encoding.encode(msg) {
+            Ok(Frame::Text(text)) => ctx.text(text),
+            Ok(Frame::Binary(bytes)) => ctx.binary(bytes),
+            Err(e) => error!("Couldn't encode {:?}: {:?}", msg, e),
+        }
+    }
+
+    ///Runs a command and tells the client whether it worked.  The answer goes out with the file's
+    ///other updates when there are any, so it can't overtake the changes the command made.
+    fn run(
+        &self,
+        parsed: Result<(RequestID, Command), (Option<RequestID>, DBError)>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let (path, msg) = match parsed {
+            Ok((request_id, mut command)) => {
+                info!("{:?}", command);
+                let (path, result) = match self.resolve(&mut command) {
+                    Ok(()) => (Some(command.path().clone()), self.route(command)),
+                    Err(e) => (None, Err(e)),
+                };
+                let msg = match result {
+                    Ok(()) => UpdateMsg::Ack { request_id },
+                    Err(e) => {
+                        error!("{:?}", e);
+                        UpdateMsg::Error {
+                            request_id: Some(request_id),
+                            error: e,
+                        }
+                    }
+                };
+                (path, msg)
+            }
+            Err((request_id, e)) => {
+                error!("Couldn't read command: {:?}", e);
+                let msg = UpdateMsg::Error {
+                    request_id,
+                    error: e,
+                };
+                (None, msg)
+            }
+        };
+        //Files that are closed, or still opening, have nothing queued to wait behind
+        let queued = match &path {
+            Some(path) if self.updates.contains_key(path) => {
+                operations_kernel::send_reply(path, &self.id, msg.clone()).is_ok()
+            }
+            _ => false,
+        };
+        if !queued {
+            self.send(&msg, ctx);
+        }
+    }
+
+    ///Swaps the path the client sent for where the file really is, before anything looks it up.
+    fn resolve(&self, command: &mut Command) -> Result<(), DBError> {
+        let path = command.path_mut();
+        *path = self.root.resolve(path)?;
+        Ok(())
+    }
+
+    ///Checks the user's role on the file.  Opening a file claims it if nobody has a role on it yet.
+    fn authorize(&self, command: &Command) -> Result<(), DBError> {
+        let path = command.path();
+        let role = match command {
+            Command::InitFile(..) | Command::ReconnectFile(..) => self.auth.open(path, &self.id)?,
+            _ => self
+                .auth
+                .get_role(path, &self.id)
+                .ok_or_else(|| DBError::Forbidden(format!("No access to {:?}", path)))?,
+        };
+        if command.is_mutating() && role == Role::Viewer {
+            return Err(DBError::Forbidden(format!(
+                "Read only access to {:?}",
+                path
+            )));
+        }
+        Ok(())
+    }
+
+    fn route(&self, command: Command) -> Result<(), DBError> {
+        self.authorize(&command)?;
+        let now = Instant::now();
+        let answer = match command {
+            Command::InitFile(path) => {
+                let (s, r) = crossbeam_channel::bounded(UPDATE_QUEUE_LENGTH);
                 operations_kernel::init_file(path.clone(), self.id.clone(), s);
-                self.updates.insert(path, r);
+                self.updates.insert(path, UpdateQueue::new(r, self.deltas));
+                Ok(())
+            }
+            Command::ReconnectFile(path, session, last_seq) => {
+                let (s, r) = crossbeam_channel::bounded(UPDATE_QUEUE_LENGTH);
+                operations_kernel::reconnect_file(
+                    path.clone(),
+                    self.id.clone(),
+                    s,
+                    session,
+                    last_seq,
+                );
+                self.updates.insert(path, UpdateQueue::new(r, self.deltas));
+                Ok(())
+            }
+            Command::SetOverflowPolicy(path, policy) => {
+                operations_kernel::set_overflow_policy(&path, &self.id, policy)
+            }
+            Command::GetQueueStats(path, query) => {
+                operations_kernel::get_queue_stats(&path, query, &self.id)
+            }
+            Command::SetFileRole(path, user, role) => {
+                self.auth.set_role(&path, &self.id, user, role)?;
+                if role.is_none() {
+                    self.revoke(path, user);
+                }
                 Ok(())
             }
-            "close_file" => {
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
+            Command::CloseFile(path) => {
+                self.updates.remove(&path);
                 operations_kernel::close_file(path, self.id);
                 Ok(())
             }
-            "begin_undo_event" => {
-                let desc: String = serde_json::from_value(msg.params.remove(2)).map_err(error)?;
-                let id: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::begin_undo_event(&path, &self.id, id, desc).map_err(error)
-            }
-            "end_undo_event" => {
-                let id: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::end_undo_event(&path, id).map_err(error)
-            }
-            "undo_latest" => {
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::undo_latest(&path, &self.id).map_err(error)
-            }
-            "redo_latest" => {
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::redo_latest(&path, &self.id).map_err(error)
-            }
-            "suspend_event" => {
-                let id: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::suspend_event(&path, &id).map_err(error)
-            }
-            "resume_event" => {
-                let id: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::resume_event(&path, &id).map_err(error)
-            }
-            "cancel_event" => {
-                let id: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::cancel_event(&path, &id).map_err(error)
-            }
-            "take_undo_snapshot" => {
-                let obj_id: RefID = serde_json::from_value(msg.params.remove(2)).map_err(error)?;
-                let event_id: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::take_undo_snapshot(&path, &event_id, &obj_id).map_err(error)
-            }
-            "join_at_points" => {
-                let point: Point3f = serde_json::from_value(msg.params.remove(4)).map_err(error)?;
-                let id_2: RefID = serde_json::from_value(msg.params.remove(3)).map_err(error)?;
-                let id_1: RefID = serde_json::from_value(msg.params.remove(2)).map_err(error)?;
-                let event_id: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
+            Command::BeginUndoEvent(path, id, desc) => {
+                operations_kernel::begin_undo_event(&path, &self.id, id, desc)
+            }
+            Command::BeginAtomicEvent(path, id, desc) => {
+                operations_kernel::begin_atomic_event(&path, &self.id, id, desc)
+            }
+            Command::EndUndoEvent(path, id) => operations_kernel::end_undo_event(&path, id),
+            Command::UndoLatest(path) => operations_kernel::undo_latest(&path, &self.id),
+            Command::RedoLatest(path) => operations_kernel::redo_latest(&path, &self.id),
+            Command::SetUndoLimits(path, limits) => {
+                operations_kernel::set_undo_limits(&path, limits)
+            }
+            Command::SetUserInfo(path, name, color) => {
+                operations_kernel::set_user_info(&path, &self.id, name, color)
+            }
+            Command::SetCursor(path, pos) => operations_kernel::set_cursor(&path, &self.id, pos),
+            Command::SetSelection(path, ids) => {
+                operations_kernel::set_selection(&path, &self.id, ids)
+            }
+            Command::GetUsers(path, query) => operations_kernel::get_users(&path, query, &self.id),
+            Command::GetLocks(path, query) => operations_kernel::get_locks(&path, query, &self.id),
+            Command::GetHistory(path, query) => {
+                operations_kernel::get_history(&path, query, &self.id)
+            }
+            Command::UndoTo(path, id) => operations_kernel::undo_to(&path, &self.id, &id),
+            Command::RevertEvent(path, id) => operations_kernel::revert_event(&path, &self.id, &id),
+            Command::SuspendEvent(path, id) => operations_kernel::suspend_event(&path, &id),
+            Command::ResumeEvent(path, id) => operations_kernel::resume_event(&path, &id),
+            Command::CancelEvent(path, id) => operations_kernel::cancel_event(&path, &id),
+            Command::TakeUndoSnapshot(path, event_id, obj_id) => {
+                operations_kernel::take_undo_snapshot(&path, &event_id, &obj_id)
+            }
+            Command::JoinAtPoints(path, event_id, id_1, id_2, point) => {
                 operations_kernel::join_objs(
                     path,
                     &event_id,
@@ -173,33 +293,17 @@ impl MyWebSocket {
                     &RefType::Point,
                     &point,
                 )
-                .map_err(error)
-            }
-            "snap_to_line" => {
-                let point: Point3f = serde_json::from_value(msg.params.remove(4)).map_err(error)?;
-                let id_2: RefID = serde_json::from_value(msg.params.remove(3)).map_err(error)?;
-                let id_1: RefID = serde_json::from_value(msg.params.remove(2)).map_err(error)?;
-                let event_id: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::join_objs(
-                    path,
-                    &event_id,
-                    id_1,
-                    id_2,
-                    &RefType::Rect,
-                    &RefType::Line,
-                    &point,
-                )
-                .map_err(error)
-            }
-            "snap_to_point" => {
-                let point: Point3f = serde_json::from_value(msg.params.remove(4)).map_err(error)?;
-                let id_2: RefID = serde_json::from_value(msg.params.remove(3)).map_err(error)?;
-                let id_1: RefID = serde_json::from_value(msg.params.remove(2)).map_err(error)?;
-                let event_id: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
+            }
+            Command::SnapToLine(path, event_id, id_1, id_2, point) => operations_kernel::join_objs(
+                path,
+                &event_id,
+                id_1,
+                id_2,
+                &RefType::Rect,
+                &RefType::Line,
+                &point,
+            ),
+            Command::SnapToPoint(path, event_id, id_1, id_2, point) => {
                 operations_kernel::snap_obj_to_other(
                     path,
                     &event_id,
@@ -208,13 +312,8 @@ impl MyWebSocket {
                     &RefType::Point,
                     &point,
                 )
-                .map_err(error)
             }
-            "get_closest_point" => {
-                let query: QueryID = serde_json::from_value(msg.params.remove(3)).map_err(error)?;
-                let point: Point3f = serde_json::from_value(msg.params.remove(2)).map_err(error)?;
-                let id: RefID = serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
+            Command::GetClosestPoint(path, id, point, query) => {
                 operations_kernel::get_closest_result(
                     &path,
                     &id,
@@ -223,100 +322,100 @@ impl MyWebSocket {
                     query,
                     &self.id,
                 )
-                .map_err(error)
-            }
-            "add_object" => {
-                let json = msg.params.remove(3);
-                let type_str: String =
-                    serde_json::from_value(msg.params.remove(2)).map_err(error)?;
-                let boxed = data_model::from_json(&type_str, json).map_err(error)?;
-                let event: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::add_obj(&path, &event, boxed).map_err(error)
-            }
-            "move_object" => {
-                let delta: Vector3f =
-                    serde_json::from_value(msg.params.remove(3)).map_err(error)?;
-                let id: RefID = serde_json::from_value(msg.params.remove(2)).map_err(error)?;
-                let event: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::move_obj(path, &event, id, &delta).map_err(error)
-            }
-            "delete_object" => {
-                let id: RefID = serde_json::from_value(msg.params.remove(2)).map_err(error)?;
-                let event: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::delete_obj(&path, &event, &id).map_err(error)
-            }
-            "get_object_data" => {
-                let query: QueryID = serde_json::from_value(msg.params.remove(3)).map_err(error)?;
-                let prop_name: String =
-                    serde_json::from_value(msg.params.remove(2)).map_err(error)?;
-                let id: RefID = serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
+            }
+            Command::SetRef(path, event_id, id, index, refer, snap_pt) => {
+                operations_kernel::set_ref(path, &event_id, id, index, refer, &snap_pt)
+            }
+            Command::SnapPoint(path, point, tolerance, from, grid, query) => {
+                operations_kernel::snap_point(
+                    &path, &point, tolerance, &from, &grid, query, &self.id,
+                )
+            }
+            Command::SetAutosave(path, seconds) => {
+                operations_kernel::set_autosave(&path, seconds.map(Duration::from_secs))
+            }
+            Command::SetBackupCount(path, count) => {
+                operations_kernel::set_backup_count(&path, count)
+            }
+            Command::SetHistoryDepth(path, depth) => {
+                operations_kernel::set_history_depth(&path, depth)
+            }
+            Command::GetModified(path, query) => {
+                operations_kernel::get_modified(&path, query, &self.id)
+            }
+            Command::GetFileHeader(path, query) => {
+                operations_kernel::get_file_header(&path, query, &self.id)
+            }
+            Command::SetFileInfo(path, units, project) => {
+                operations_kernel::set_file_info(&path, units, project)
+            }
+            Command::GetDepCycles(path, query) => {
+                operations_kernel::get_dep_cycles(&path, query, &self.id)
+            }
+            Command::ExportDeps(path, format, query) => {
+                operations_kernel::export_deps(&path, format, query, &self.id)
+            }
+            Command::AddObject(path, event, type_str, json) => {
+                let boxed = data_model::from_json(&type_str, json)?;
+                operations_kernel::add_obj(&path, &event, boxed)
+            }
+            Command::MoveObject(path, event, id, delta) => {
+                operations_kernel::move_obj(path, &event, id, &delta)
+            }
+            Command::DeleteObject(path, event, id) => {
+                operations_kernel::delete_obj(&path, &event, &id)
+            }
+            Command::GetObjectData(path, id, prop_name, query) => {
                 operations_kernel::get_obj_data(&path, &id, &prop_name, query, &self.id)
-                    .map_err(error)
-            }
-            "set_object_data" => {
-                let data = msg.params.remove(3);
-                let id: RefID = serde_json::from_value(msg.params.remove(2)).map_err(error)?;
-                let event: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::set_obj_data(path, &event, id, data).map_err(error)
-            }
-            "set_object_datas" => {
-                let data: Vec<(RefID, serde_json::Value)> =
-                    serde_json::from_value(msg.params.remove(2)).map_err(error)?;
-                let event: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::set_objs_data(path, &event, data).map_err(error)
-            }
-            "move_objects" => {
-                let delta: Vector3f =
-                    serde_json::from_value(msg.params.remove(3)).map_err(error)?;
-                let data: std::collections::HashSet<RefID> =
-                    serde_json::from_value(msg.params.remove(2)).map_err(error)?;
-                let event: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::move_objs(path, &event, data, &delta).map_err(error)
-            }
-            "copy_objects" => {
-                let query: QueryID = serde_json::from_value(msg.params.remove(3)).map_err(error)?;
-                let data: std::collections::HashSet<RefID> =
-                    serde_json::from_value(msg.params.remove(2)).map_err(error)?;
-                let event: UndoEventID =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::copy_objs(path, &event, data, query, &self.id).map_err(error)
-            }
-            "demo" => {
-                let position: Point3f =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
-                operations_kernel::demo(&path, &self.id, &position).map_err(error)
-            }
-            "demo_100" => {
-                let position: Point3f =
-                    serde_json::from_value(msg.params.remove(1)).map_err(error)?;
-                let path: PathBuf = serde_json::from_value(msg.params.remove(0)).map_err(error)?;
+            }
+            Command::SetObjectData(path, event, id, data) => {
+                operations_kernel::set_obj_data(path, &event, id, data)
+            }
+            Command::SetObjectDatas(path, event, data) => {
+                operations_kernel::set_objs_data(path, &event, data)
+            }
+            Command::MoveObjects(path, event, data, delta) => {
+                operations_kernel::move_objs(path, &event, data, &delta)
+            }
+            Command::CopyObjects(path, event, data, query) => {
+                operations_kernel::copy_objs(path, &event, data, query, &self.id)
+            }
+            Command::Demo(path, position) => operations_kernel::demo(&path, &self.id, &position),
+            Command::Demo100(path, position) => {
                 operations_kernel::demo_100(path, self.id.clone(), position);
                 Ok(())
             }
-            _ => Err(error("Not Implemented")),
         };
-        info!(
-            "Processed in {:?} seconds",
-            now.elapsed().unwrap().as_secs_f32()
-        );
+        info!("Processed in {:?} seconds", now.elapsed().as_secs_f32());
         answer
     }
 
+    ///Stops sending the file to a user who just lost access to it.
+    fn revoke(&self, path: PathBuf, user: UserID) {
+        if user == self.id {
+            if self.updates.remove(&path).is_some() {
+                operations_kernel::close_file(path, user);
+            }
+            return;
+        }
+        //Tell them why first.  Their connection sees the queue close, and stops watching just this file.
+        let msg = UpdateMsg::Error {
+            request_id: None,
+            error: DBError::Forbidden(format!("No access to {:?}", path)),
+        };
+        if operations_kernel::send_reply(&path, &user, msg).is_ok() {
+            operations_kernel::close_file(path, user);
+        }
+    }
+
+    fn close_all(&self) {
+        for chunk in self.updates.chunks() {
+            for (file, _) in chunk.iter() {
+                operations_kernel::close_file(file.clone(), self.id);
+            }
+        }
+    }
+
     /// helper method that sends ping to client every HEARTBEAT_INTERVAL.
     ///
     /// also this method checks heartbeats from client
@@ -325,11 +424,7 @@ impl MyWebSocket {
             // check client heartbeats
             if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
                 // heartbeat timed out
-                for chunk in act.updates.chunks() {
-                    for (file, _) in chunk.iter() {
-                        operations_kernel::close_file(file.clone(), act.id);
-                    }
-                }
+                act.close_all();
                 // stop actor
                 ctx.stop();
                 // don't try to send a ping
@@ -341,14 +436,36 @@ impl MyWebSocket {
             if let actix::ActorState::Stopped = ctx.state() {
                 return;
             }
+            let mut closed = Vec::new();
             for chunk in act.updates.chunks() {
-                for (path, r) in chunk.iter() {
-                    for msg in r.try_iter() {
+                for (path, queue) in chunk.iter() {
+                    for msg in queue.drain() {
                         trace!("Sending msg: {:?} for file {:?}", msg, path);
-                        ctx.text(serde_json::to_string(&msg).unwrap());
+                        act.send(&msg, ctx);
                     }
+                    if queue.is_closed() {
+                        closed.push(path.clone());
+                    } else if let Err(e) = operations_kernel::flush_updates(path, &act.id) {
+                        debug!("Couldn't flush updates for {:?}: {:?}", path, e);
+                    }
+                }
+            }
+            let mut overflowed = false;
+            for path in closed {
+                if act.auth.get_role(&path, &act.id).is_some() {
+                    overflowed = true;
+                } else {
+                    //Their access was taken away, and the file was already closed for them.  Their other files carry on.
+                    info!("{:?} lost access to {:?}", act.id, path);
+                    act.updates.remove(&path);
                 }
             }
+            if overflowed {
+                //They fell too far behind, so drop the connection and let them reconnect
+                warn!("Disconnecting {:?}", act.id);
+                act.close_all();
+                ctx.stop();
+            }
         });
     }
 }
@@ -1,6 +1,7 @@
 import * as ops from '../src/operations/operations';
 //@ts-ignore
 import WebsocketAsPromised from "websocket-as-promised";
+import { encode, decode } from "@msgpack/msgpack";
 
 declare global {
   export type JsDimension = import("../data-model-wasm/pkg/index").JsDimension;
@@ -13,11 +14,21 @@ window.addEventListener('DOMContentLoaded', () => {
     var connection = "ws://" + window.location.host + "/ws";
     console.log(connection);
     console.log("hi there!");
-    var user = ops.initialize(mod);
-    connection = connection + "?user_id=" + user;
+    ops.initialize(mod);
+    var params = new URLSearchParams(window.location.search);
+    //Open the page with ?encoding=json to get readable messages while debugging
+    var encoding = params.get("encoding") || "msgpack";
+    //Tokens come from running the server with --add-user
+    var token = params.get("token") || prompt("Token:");
+    connection = connection + "?token=" + encodeURIComponent(token) + "&encoding=" + encoding + "&deltas=true";
     var conn = new WebsocketAsPromised(connection, {
-      packMessage: (data: any) => JSON.stringify(data),
-      unpackMessage: (data: any) => JSON.parse(data),
+      createWebSocket: (url: string) => {
+        var socket = new WebSocket(url);
+        socket.binaryType = "arraybuffer";
+        return socket;
+      },
+      packMessage: (data: any) => encoding == "msgpack" ? encode(data) : JSON.stringify(data),
+      unpackMessage: (data: any) => typeof data == "string" ? JSON.parse(data) : decode(new Uint8Array(data)),
     });
     ops.setConnection(conn).then(() => {
       console.log("prompting: ");
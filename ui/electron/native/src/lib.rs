@@ -18,14 +18,14 @@ mod slab;
 mod wall;
 
 use ccl::dhashmap::DHashMap;
-use crossbeam_channel::Receiver;
 use data_model::*;
 use neon::prelude::*;
+use operations_kernel::UpdateQueue;
 use std::path::PathBuf;
 use std::str::FromStr;
 
 lazy_static! {
-    static ref UPDATES: DHashMap<PathBuf, Receiver<UpdateMsg>> = DHashMap::default();
+    static ref UPDATES: DHashMap<PathBuf, UpdateQueue> = DHashMap::default();
 }
 
 struct GetNextUpdate {
@@ -39,17 +39,7 @@ impl Task for GetNextUpdate {
 
     fn perform(&self) -> Result<Self::Output, Self::Error> {
         match UPDATES.get(&self.file) {
-            Some(r) => {
-                if r.len() > 0 {
-                    let mut results = Vec::new();
-                    for msg in r.try_iter() {
-                        results.push(msg);
-                    }
-                    Ok(results)
-                } else {
-                    Ok(vec![r.recv().unwrap()])
-                }
-            }
+            Some(queue) => queue.wait().map_err(|e| format!("{:?}", e)),
             None => Err(format!("File {:?} not found", self.file)),
         }
     }
@@ -80,7 +70,25 @@ fn init_file(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     let (s, r) = crossbeam_channel::unbounded();
     let pathbuf = PathBuf::from(path);
     operations_kernel::init_file(pathbuf.clone(), UserID::from_str(&user).unwrap(), s.clone());
-    UPDATES.insert(pathbuf, r);
+    UPDATES.insert(pathbuf, UpdateQueue::new(r, false));
+    Ok(cx.undefined())
+}
+
+fn reconnect_file(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let user = cx.argument::<JsString>(1)?.value();
+    let session = cx.argument::<JsString>(2)?.value();
+    let last_seq = cx.argument::<JsNumber>(3)?.value() as u64;
+    let (s, r) = crossbeam_channel::unbounded();
+    let pathbuf = PathBuf::from(path);
+    operations_kernel::reconnect_file(
+        pathbuf.clone(),
+        UserID::from_str(&user).unwrap(),
+        s,
+        SessionID::from_str(&session).unwrap(),
+        last_seq,
+    );
+    UPDATES.insert(pathbuf, UpdateQueue::new(r, false));
     Ok(cx.undefined())
 }
 
@@ -124,6 +132,21 @@ fn begin_undo_event(mut cx: FunctionContext) -> JsResult<JsString> {
     Ok(cx.string(format!("{:?}", event_id)))
 }
 
+fn begin_atomic_event(mut cx: FunctionContext) -> JsResult<JsString> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let user = cx.argument::<JsString>(1)?.value();
+    let event_id = cx.argument::<JsString>(2)?.value();
+    let desc = cx.argument::<JsString>(3)?.value();
+    operations_kernel::begin_atomic_event(
+        &PathBuf::from(path),
+        &UserID::from_str(&user).unwrap(),
+        UndoEventID::from_str(&event_id).unwrap(),
+        desc,
+    )
+    .unwrap();
+    Ok(cx.string(format!("{:?}", event_id)))
+}
+
 fn end_undo_event(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     let path = cx.argument::<JsString>(0)?.value();
     let id = cx.argument::<JsString>(1)?.value();
@@ -147,6 +170,100 @@ fn redo_latest(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     Ok(cx.undefined())
 }
 
+fn set_undo_limits(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let arg_1 = cx.argument::<JsValue>(1)?;
+    let limits = neon_serde::from_value(&mut cx, arg_1)?;
+    operations_kernel::set_undo_limits(&PathBuf::from(&path), limits).unwrap();
+    Ok(cx.undefined())
+}
+
+fn set_user_info(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let user = cx.argument::<JsString>(1)?.value();
+    let name = cx.argument::<JsString>(2)?.value();
+    let color = cx.argument::<JsString>(3)?.value();
+    operations_kernel::set_user_info(&PathBuf::from(&path), &UserID::from_str(&user).unwrap(), name, color)
+        .unwrap();
+    Ok(cx.undefined())
+}
+
+fn set_cursor(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let user = cx.argument::<JsString>(1)?.value();
+    let arg_2 = cx.argument::<JsValue>(2)?;
+    let pos = neon_serde::from_value(&mut cx, arg_2)?;
+    operations_kernel::set_cursor(&PathBuf::from(&path), &UserID::from_str(&user).unwrap(), pos).unwrap();
+    Ok(cx.undefined())
+}
+
+fn set_selection(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let user = cx.argument::<JsString>(1)?.value();
+    let arg_2 = cx.argument::<JsValue>(2)?;
+    let ids = neon_serde::from_value(&mut cx, arg_2)?;
+    operations_kernel::set_selection(&PathBuf::from(&path), &UserID::from_str(&user).unwrap(), ids)
+        .unwrap();
+    Ok(cx.undefined())
+}
+
+fn get_users(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let user = cx.argument::<JsString>(1)?.value();
+    let query_id = QueryID::from_str(&cx.argument::<JsString>(2)?.value()).unwrap();
+    operations_kernel::get_users(&PathBuf::from(&path), query_id, &UserID::from_str(&user).unwrap())
+        .unwrap();
+    Ok(cx.undefined())
+}
+
+fn get_locks(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let user = cx.argument::<JsString>(1)?.value();
+    let query_id = QueryID::from_str(&cx.argument::<JsString>(2)?.value()).unwrap();
+    operations_kernel::get_locks(&PathBuf::from(&path), query_id, &UserID::from_str(&user).unwrap())
+        .unwrap();
+    Ok(cx.undefined())
+}
+
+fn get_history(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let user = cx.argument::<JsString>(1)?.value();
+    let query_id = QueryID::from_str(&cx.argument::<JsString>(2)?.value()).unwrap();
+    operations_kernel::get_history(
+        &PathBuf::from(&path),
+        query_id,
+        &UserID::from_str(&user).unwrap(),
+    )
+    .unwrap();
+    Ok(cx.undefined())
+}
+
+fn undo_to(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let user = cx.argument::<JsString>(1)?.value();
+    let id = cx.argument::<JsString>(2)?.value();
+    operations_kernel::undo_to(
+        &PathBuf::from(path),
+        &UserID::from_str(&user).unwrap(),
+        &UndoEventID::from_str(&id).unwrap(),
+    )
+    .unwrap();
+    Ok(cx.undefined())
+}
+
+fn revert_event(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let user = cx.argument::<JsString>(1)?.value();
+    let id = cx.argument::<JsString>(2)?.value();
+    operations_kernel::revert_event(
+        &PathBuf::from(path),
+        &UserID::from_str(&user).unwrap(),
+        &UndoEventID::from_str(&id).unwrap(),
+    )
+    .unwrap();
+    Ok(cx.undefined())
+}
+
 fn suspend_event(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     let path = cx.argument::<JsString>(0)?.value();
     let id = cx.argument::<JsString>(1)?.value();
@@ -251,6 +368,20 @@ fn snap_to_point(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     Ok(cx.undefined())
 }
 
+fn set_ref(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let event = RefID::from_str(&cx.argument::<JsString>(1)?.value()).unwrap();
+    let id = RefID::from_str(&cx.argument::<JsString>(2)?.value()).unwrap();
+    let arg_3 = cx.argument::<JsValue>(3)?;
+    let index = neon_serde::from_value(&mut cx, arg_3)?;
+    let arg_4 = cx.argument::<JsValue>(4)?;
+    let refer = neon_serde::from_value(&mut cx, arg_4)?;
+    let arg_5 = cx.argument::<JsValue>(5)?;
+    let snap_pt = neon_serde::from_value(&mut cx, arg_5)?;
+    operations_kernel::set_ref(PathBuf::from(&path), &event, id, index, refer, &snap_pt).unwrap();
+    Ok(cx.undefined())
+}
+
 fn get_closest_point(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     let path = cx.argument::<JsString>(0)?.value();
     let id_1 = RefID::from_str(&cx.argument::<JsString>(1)?.value()).unwrap();
@@ -270,6 +401,148 @@ fn get_closest_point(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     Ok(cx.undefined())
 }
 
+fn snap_point(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let arg_1 = cx.argument::<JsValue>(1)?;
+    let point = neon_serde::from_value(&mut cx, arg_1)?;
+    let tolerance = cx.argument::<JsNumber>(2)?.value();
+    let arg_3 = cx.argument::<JsValue>(3)?;
+    let from = neon_serde::from_value(&mut cx, arg_3)?;
+    let arg_4 = cx.argument::<JsValue>(4)?;
+    let grid = neon_serde::from_value(&mut cx, arg_4)?;
+    let user = cx.argument::<JsString>(5)?.value();
+    let query_id = QueryID::from_str(&cx.argument::<JsString>(6)?.value()).unwrap();
+    operations_kernel::snap_point(
+        &PathBuf::from(&path),
+        &point,
+        tolerance,
+        &from,
+        &grid,
+        query_id,
+        &UserID::from_str(&user).unwrap(),
+    )
+    .unwrap();
+    Ok(cx.undefined())
+}
+
+fn set_autosave(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let arg_1 = cx.argument::<JsValue>(1)?;
+    let seconds: Option<u64> = neon_serde::from_value(&mut cx, arg_1)?;
+    operations_kernel::set_autosave(
+        &PathBuf::from(path),
+        seconds.map(std::time::Duration::from_secs),
+    )
+    .unwrap();
+    Ok(cx.undefined())
+}
+
+fn set_backup_count(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let count = cx.argument::<JsNumber>(1)?.value() as usize;
+    operations_kernel::set_backup_count(&PathBuf::from(path), count).unwrap();
+    Ok(cx.undefined())
+}
+
+fn set_history_depth(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let depth = cx.argument::<JsNumber>(1)?.value() as usize;
+    operations_kernel::set_history_depth(&PathBuf::from(path), depth).unwrap();
+    Ok(cx.undefined())
+}
+
+fn set_overflow_policy(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let user = cx.argument::<JsString>(1)?.value();
+    let arg_2 = cx.argument::<JsValue>(2)?;
+    let policy = neon_serde::from_value(&mut cx, arg_2)?;
+    operations_kernel::set_overflow_policy(
+        &PathBuf::from(&path),
+        &UserID::from_str(&user).unwrap(),
+        policy,
+    )
+    .unwrap();
+    Ok(cx.undefined())
+}
+
+fn get_queue_stats(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let user = cx.argument::<JsString>(1)?.value();
+    let query_id = QueryID::from_str(&cx.argument::<JsString>(2)?.value()).unwrap();
+    operations_kernel::get_queue_stats(
+        &PathBuf::from(&path),
+        query_id,
+        &UserID::from_str(&user).unwrap(),
+    )
+    .unwrap();
+    Ok(cx.undefined())
+}
+
+fn get_modified(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let user = cx.argument::<JsString>(1)?.value();
+    let query_id = QueryID::from_str(&cx.argument::<JsString>(2)?.value()).unwrap();
+    operations_kernel::get_modified(
+        &PathBuf::from(&path),
+        query_id,
+        &UserID::from_str(&user).unwrap(),
+    )
+    .unwrap();
+    Ok(cx.undefined())
+}
+
+fn get_file_header(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let user = cx.argument::<JsString>(1)?.value();
+    let query_id = QueryID::from_str(&cx.argument::<JsString>(2)?.value()).unwrap();
+    operations_kernel::get_file_header(
+        &PathBuf::from(&path),
+        query_id,
+        &UserID::from_str(&user).unwrap(),
+    )
+    .unwrap();
+    Ok(cx.undefined())
+}
+
+fn set_file_info(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let arg_1 = cx.argument::<JsValue>(1)?;
+    let units = neon_serde::from_value(&mut cx, arg_1)?;
+    let arg_2 = cx.argument::<JsValue>(2)?;
+    let project = neon_serde::from_value(&mut cx, arg_2)?;
+    operations_kernel::set_file_info(&PathBuf::from(&path), units, project).unwrap();
+    Ok(cx.undefined())
+}
+
+fn get_dep_cycles(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let user = cx.argument::<JsString>(1)?.value();
+    let query_id = QueryID::from_str(&cx.argument::<JsString>(2)?.value()).unwrap();
+    operations_kernel::get_dep_cycles(
+        &PathBuf::from(&path),
+        query_id,
+        &UserID::from_str(&user).unwrap(),
+    )
+    .unwrap();
+    Ok(cx.undefined())
+}
+
+fn export_deps(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let path = cx.argument::<JsString>(0)?.value();
+    let arg_1 = cx.argument::<JsValue>(1)?;
+    let format = neon_serde::from_value(&mut cx, arg_1)?;
+    let user = cx.argument::<JsString>(2)?.value();
+    let query_id = QueryID::from_str(&cx.argument::<JsString>(3)?.value()).unwrap();
+    operations_kernel::export_deps(
+        &PathBuf::from(&path),
+        format,
+        query_id,
+        &UserID::from_str(&user).unwrap(),
+    )
+    .unwrap();
+    Ok(cx.undefined())
+}
+
 fn move_object(mut cx: FunctionContext) -> JsResult<JsUndefined> {
     let path = cx.argument::<JsString>(0)?.value();
     let event = RefID::from_str(&cx.argument::<JsString>(1)?.value()).unwrap();
@@ -413,13 +686,26 @@ fn get_query_id(mut cx: FunctionContext) -> JsResult<JsString> {
 register_module!(mut cx, {
     cx.export_function("get_updates", get_updates)?;
     cx.export_function("init_file", init_file)?;
+    cx.export_function("reconnect_file", reconnect_file)?;
     cx.export_function("close_file", close_file)?;
+    cx.export_function("set_overflow_policy", set_overflow_policy)?;
+    cx.export_function("get_queue_stats", get_queue_stats)?;
     cx.export_function("save_file", save_file)?;
     cx.export_function("save_as_file", save_as_file)?;
     cx.export_function("begin_undo_event", begin_undo_event)?;
+    cx.export_function("begin_atomic_event", begin_atomic_event)?;
     cx.export_function("end_undo_event", end_undo_event)?;
     cx.export_function("undo_latest", undo_latest)?;
     cx.export_function("redo_latest", redo_latest)?;
+    cx.export_function("set_undo_limits", set_undo_limits)?;
+    cx.export_function("set_user_info", set_user_info)?;
+    cx.export_function("set_cursor", set_cursor)?;
+    cx.export_function("set_selection", set_selection)?;
+    cx.export_function("get_users", get_users)?;
+    cx.export_function("get_locks", get_locks)?;
+    cx.export_function("get_history", get_history)?;
+    cx.export_function("undo_to", undo_to)?;
+    cx.export_function("revert_event", revert_event)?;
     cx.export_function("take_undo_snapshot", take_undo_snapshot)?;
     cx.export_function("suspend_event", suspend_event)?;
     cx.export_function("resume_event", resume_event)?;
@@ -428,6 +714,7 @@ register_module!(mut cx, {
     cx.export_function("join_at_points", join_at_points)?;
     cx.export_function("snap_to_line", snap_to_line)?;
     cx.export_function("snap_to_point", snap_to_point)?;
+    cx.export_function("set_ref", set_ref)?;
     cx.export_function("move_object", move_object)?;
     cx.export_function("move_objects", move_objects)?;
     cx.export_function("delete_object", delete_object)?;
@@ -436,6 +723,15 @@ register_module!(mut cx, {
     cx.export_function("set_objects_datas", set_objects_datas)?;
     cx.export_function("copy_objects", copy_objects)?;
     cx.export_function("get_closest_point", get_closest_point)?;
+    cx.export_function("snap_point", snap_point)?;
+    cx.export_function("set_autosave", set_autosave)?;
+    cx.export_function("set_backup_count", set_backup_count)?;
+    cx.export_function("set_history_depth", set_history_depth)?;
+    cx.export_function("get_modified", get_modified)?;
+    cx.export_function("get_file_header", get_file_header)?;
+    cx.export_function("set_file_info", set_file_info)?;
+    cx.export_function("get_dep_cycles", get_dep_cycles)?;
+    cx.export_function("export_deps", export_deps)?;
     cx.export_function("demo", demo)?;
     cx.export_function("demo_100", demo_100)?;
     cx.export_function("projectOnLine", math::project_on_line)?;
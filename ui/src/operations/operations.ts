@@ -26,6 +26,14 @@ var renderer: Renderer = null;
 var filename: string = "";
 var pendingChanges: Map<String, Array<(obj: BABYLON.Mesh) => void>> = new Map();
 var pendingReads: Map<String, (val: any) => void> = new Map();
+var pendingRequests: Map<String, { resolve: () => void, reject: (error: any) => void }> = new Map();
+var modifiedListener: (modified: boolean) => void = null;
+var lockListener: (id: string, owner: string | null, prop: string | null) => void = null;
+var presenceListener: (msg: any) => void = null;
+//Where the broadcasts we've seen are up to, so a reconnect only needs what was missed
+var session: string = null;
+var lastSeq: number = 0;
+var renderedIds: Set<string> = new Set();
 
 
 export interface DataObject {
@@ -40,12 +48,18 @@ function initRenderer(canvas: HTMLCanvasElement) {
     renderer.initialize(canvas)
 }
 
-function send(func: string, params: Array<any>) {
+//Resolves when the server acknowledges the command, or rejects with its error, e.g. {code: "Locked", detail: "..."}
+function send(func: string, params: Array<any>): Promise<void> {
+    const request_id = dataModel.getQueryId();
     var msg = {
+        "request_id": request_id,
         "func_name": func,
         "params": params
     }
-    connection.sendPacked(msg)
+    return new Promise((resolve, reject) => {
+        pendingRequests.set(request_id, { resolve: resolve, reject: reject })
+        connection.sendPacked(msg)
+    })
 }
 
 export function initFile(canvas: HTMLCanvasElement, name: string) {
@@ -61,6 +75,50 @@ export function initFile(canvas: HTMLCanvasElement, name: string) {
     renderNext(filename)  //This will readd itself, so it's an infinite loop in the background
 }
 
+export function reconnect() {
+    if (connection) {
+        if (session) {
+            send("reconnect_file", [filename, session, lastSeq])
+        }
+        else {
+            send("init_file", [filename])
+        }
+    }
+    else if (session) {
+        dataModel.reconnect_file(filename, user, session, lastSeq)
+    }
+}
+
+//What the server does when we can't keep up with updates: "Coalesce", "Resync" or "Disconnect"
+export function setOverflowPolicy(policy: string) {
+    if (connection) {
+        send("set_overflow_policy", [filename, policy])
+    }
+    else {
+        dataModel.set_overflow_policy(filename, user, policy)
+    }
+}
+
+//How many updates are waiting for each user
+export function getQueueStats() {
+    const query = dataModel.getQueryId();
+    if (connection) {
+        send("get_queue_stats", [filename, query])
+    }
+    else {
+        dataModel.get_queue_stats(filename, user, query)
+    }
+    return waitForRead(query)
+}
+
+//Only the file's owners can do this, and only on the server.  role is "owner", "editor", "viewer" or null to remove access
+export function setFileRole(otherUser: string, role: string | null) {
+    if (connection) {
+        return send("set_file_role", [filename, otherUser, role])
+    }
+    return Promise.reject("Roles need a server")
+}
+
 export function closeFile() {
     if (connection) {
         send("close_file", [filename])
@@ -99,6 +157,18 @@ export function beginUndoEvent(desc: string) {
     return event;
 }
 
+//Anything that fails inside an atomic event rolls back the whole event
+export function beginAtomicEvent(desc: string) {
+    var event = dataModel.getUndoEventId();
+    if (connection) {
+        send("begin_atomic_event", [filename, event, desc])
+    }
+    else {
+        dataModel.begin_atomic_event(filename, user, event, desc)
+    }
+    return event;
+}
+
 export function endUndoEvent(event: string) {
     if (connection) {
         send("end_undo_event", [filename, event])
@@ -154,6 +224,46 @@ export function redoLatest() {
     }
 }
 
+export function setUndoLimits(limits: {max_events: number | null, max_bytes: number | null}) {
+    if (connection) {
+        send("set_undo_limits", [filename, limits])
+    }
+    else {
+        dataModel.set_undo_limits(filename, limits)
+    }
+}
+
+export function getHistory() {
+    const query = dataModel.getQueryId();
+    if (connection) {
+        send("get_history", [filename, query])
+    }
+    else {
+        dataModel.get_history(filename, user, query)
+    }
+    return waitForRead(query)
+}
+
+export function undoTo(event: string) {
+    if (connection) {
+        send("undo_to", [filename, event])
+    }
+    else {
+        dataModel.undo_to(filename, user, event)
+    }
+    renderNext(filename)
+}
+
+export function revertEvent(event: string) {
+    if (connection) {
+        send("revert_event", [filename, event])
+    }
+    else {
+        dataModel.revert_event(filename, user, event)
+    }
+    renderNext(filename)
+}
+
 export function takeUndoSnapshot(event: string, id: string) {
     if (connection) {
         send("take_undo_snapshot", [filename, event, id])
@@ -187,11 +297,66 @@ export function deleteObject(event: string, id: string) {
 }
 
 function handleUpdate(msg: any) {
-    if (msg.Error) {
-        console.log(msg.Error.msg)
+    if (msg.Sequenced) {
+        lastSeq = msg.Sequenced.seq
+        msg = msg.Sequenced.msg
+    }
+    if (msg.Synced) {
+        session = msg.Synced.session
+        lastSeq = msg.Synced.seq
+        if (msg.Synced.full) {
+            //Everything is about to be sent again
+            renderedIds.forEach((id) => {
+                renderer.deleteMesh(id)
+            })
+            renderedIds.clear()
+        }
+    }
+    else if (msg === "ResyncRequired") {
+        //We fell too far behind and updates were dropped, so catch up from where we got to
+        reconnect()
+    }
+    else if (msg.Ack) {
+        var request = pendingRequests.get(msg.Ack.request_id)
+        if (request) {
+            pendingRequests.delete(msg.Ack.request_id)
+            request.resolve()
+        }
+    }
+    else if (msg.Error) {
+        var request = pendingRequests.get(msg.Error.request_id)
+        if (request) {
+            pendingRequests.delete(msg.Error.request_id)
+            request.reject(msg.Error.error)
+        }
+        else {
+            console.log(msg.Error.error)
+        }
     }
     else if (msg.Delete) {
         renderer.deleteMesh(msg.Delete.key)
+        renderedIds.delete(msg.Delete.key)
+    }
+    else if (msg.DepCycle) {
+        console.log("Dependency cycle: ", msg.DepCycle)
+    }
+    else if (msg.Saved) {
+        console.log(msg.Saved.autosave ? "Autosaved " : "Saved ", filename)
+    }
+    else if (msg.Modified) {
+        if (modifiedListener) {
+            modifiedListener(msg.Modified.modified)
+        }
+    }
+    else if (msg.Lock) {
+        if (lockListener) {
+            lockListener(msg.Lock.key, msg.Lock.owner, msg.Lock.prop)
+        }
+    }
+    else if (msg.Presence || msg.UserLeft || msg.Cursor || msg.Selection) {
+        if (presenceListener) {
+            presenceListener(msg)
+        }
     }
     else {
         if (msg.Read) {
@@ -207,11 +372,16 @@ function handleUpdate(msg: any) {
                 id = msg.Mesh.data.id;
                 renderer.renderMesh(msg.Mesh.data, id)
             }
+            if (msg.MeshDelta) {
+                id = msg.MeshDelta.data.id;
+                renderer.applyMeshDelta(msg.MeshDelta.data, id)
+            }
             if (msg.Other) {
                 id = msg.Other.data.id;
                 renderer.renderObject(msg.Other.data, id)
             }
             if (id) {
+                renderedIds.add(id)
                 let callbacks = pendingChanges.get(id)
                 if (callbacks) {
                     let mesh = renderer.getMesh(id)
@@ -351,6 +521,164 @@ export function getClosestPoint(id: string, pt: Point3d) {
     })
 }
 
+export function snapPoint(pt: Point3d, tolerance: number, from?: Point3d, grid?: number) {
+    const query = dataModel.getQueryId();
+    const fromPt = from ? from : null;
+    const gridSize = grid ? grid : null;
+    if (connection) {
+        send("snap_point", [filename, pt, tolerance, fromPt, gridSize, query])
+    }
+    else {
+        dataModel.snap_point(filename, pt, tolerance, fromPt, gridSize, user, query)
+    }
+    return waitForRead(query)
+}
+
+export function setAutosave(seconds: number | null) {
+    if (connection) {
+        send("set_autosave", [filename, seconds])
+    }
+    else {
+        dataModel.set_autosave(filename, seconds)
+    }
+}
+
+export function setBackupCount(count: number) {
+    if (connection) {
+        send("set_backup_count", [filename, count])
+    }
+    else {
+        dataModel.set_backup_count(filename, count)
+    }
+}
+
+export function setHistoryDepth(depth: number) {
+    if (connection) {
+        send("set_history_depth", [filename, depth])
+    }
+    else {
+        dataModel.set_history_depth(filename, depth)
+    }
+}
+
+//Called whenever the file goes from saved to unsaved or back, so the UI can show an indicator and warn before closing
+export function onModifiedChanged(listener: (modified: boolean) => void) {
+    modifiedListener = listener;
+}
+
+//Called whenever a user starts or stops editing an object, so the UI can highlight objects other people are working on.
+//prop is set when only that property is locked, like while someone is setting it
+export function onLockChanged(listener: (id: string, owner: string | null, prop: string | null) => void) {
+    lockListener = listener;
+}
+
+//Called with Presence, UserLeft, Cursor and Selection messages about the other users on the file
+export function onPresence(listener: (msg: any) => void) {
+    presenceListener = listener;
+}
+
+export function setUserInfo(name: string, color: string) {
+    if (connection) {
+        send("set_user_info", [filename, name, color])
+    }
+    else {
+        dataModel.set_user_info(filename, user, name, color)
+    }
+}
+
+export function setCursor(pos: Point3d) {
+    if (connection) {
+        send("set_cursor", [filename, pos])
+    }
+    else {
+        dataModel.set_cursor(filename, user, pos)
+    }
+}
+
+export function setSelection(ids: string[]) {
+    if (connection) {
+        send("set_selection", [filename, ids])
+    }
+    else {
+        dataModel.set_selection(filename, user, ids)
+    }
+}
+
+export function getUsers() {
+    const query = dataModel.getQueryId();
+    if (connection) {
+        send("get_users", [filename, query])
+    }
+    else {
+        dataModel.get_users(filename, user, query)
+    }
+    return waitForRead(query)
+}
+
+export function getLocks() {
+    const query = dataModel.getQueryId();
+    if (connection) {
+        send("get_locks", [filename, query])
+    }
+    else {
+        dataModel.get_locks(filename, user, query)
+    }
+    return waitForRead(query)
+}
+
+export function getModified() {
+    const query = dataModel.getQueryId();
+    if (connection) {
+        send("get_modified", [filename, query])
+    }
+    else {
+        dataModel.get_modified(filename, user, query)
+    }
+    return waitForRead(query)
+}
+
+export function getFileHeader() {
+    const query = dataModel.getQueryId();
+    if (connection) {
+        send("get_file_header", [filename, query])
+    }
+    else {
+        dataModel.get_file_header(filename, user, query)
+    }
+    return waitForRead(query)
+}
+
+export function setFileInfo(units: "Millimeters" | "Meters" | "Inches" | "Feet", project: {name: string, author: string, description: string}) {
+    if (connection) {
+        send("set_file_info", [filename, units, project])
+    }
+    else {
+        dataModel.set_file_info(filename, units, project)
+    }
+}
+
+export function getDepCycles() {
+    const query = dataModel.getQueryId();
+    if (connection) {
+        send("get_dep_cycles", [filename, query])
+    }
+    else {
+        dataModel.get_dep_cycles(filename, user, query)
+    }
+    return waitForRead(query)
+}
+
+export function exportDeps(format: "Dot" | "Json") {
+    const query = dataModel.getQueryId();
+    if (connection) {
+        send("export_deps", [filename, format, query])
+    }
+    else {
+        dataModel.export_deps(filename, format, user, query)
+    }
+    return waitForRead(query)
+}
+
 export function snapToPoint(event: string, id: string, snap_to_id: string, pt: Point3d) {
     if (connection) {
         send("snap_to_point", [filename, event, id, snap_to_id, pt])
@@ -371,6 +699,19 @@ export function snapToLine(event: string, id: string, snap_to_id: string, pt: Po
     return waitForChange(id)
 }
 
+//Attaches one of the object's references to a result, like the refer of a snapPoint result.  Unlike snapToPoint,
+//this can attach to wall faces, corners and midpoints.
+export function setRef(event: string, id: string, index: any, refer: any, snapPt?: Point3d) {
+    const pt = snapPt ? snapPt : null;
+    if (connection) {
+        send("set_ref", [filename, event, id, index, refer, pt])
+    }
+    else {
+        dataModel.set_ref(filename, event, id, index, refer, pt)
+    }
+    return waitForChange(id)
+}
+
 export function moveObj(event: string, id: string, delta: Point3d) {
     if (connection) {
         send("move_object", [filename, event, id, delta])
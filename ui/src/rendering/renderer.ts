@@ -143,6 +143,21 @@ export class Renderer {
         vertexData.applyToMesh(mesh);
     }
 
+    //Moves just the vertices listed in the delta, 3 positions for each
+    applyMeshDelta(delta: any, id: string) {
+        var mesh = this._scene.getMeshByName(id) as BABYLON.Mesh
+        if (mesh) {
+            var positions = mesh.getVerticesData(BABYLON.VertexBuffer.PositionKind);
+            delta.vertices.forEach((vertex: number, i: number) => {
+                positions[vertex * 3] = delta.positions[i * 3];
+                positions[vertex * 3 + 1] = delta.positions[i * 3 + 1];
+                positions[vertex * 3 + 2] = delta.positions[i * 3 + 2];
+            })
+            mesh.setVerticesData(BABYLON.VertexBuffer.PositionKind, positions);
+            mesh.refreshBoundingInfo();
+        }
+    }
+
     showNormals(mesh: BABYLON.Mesh) {
         var normals = mesh.getVerticesData(BABYLON.VertexBuffer.NormalKind);
         var positions = mesh.getVerticesData(BABYLON.VertexBuffer.PositionKind);